@@ -2,7 +2,6 @@ package de.uni.tuebingen.tlceval.features.processor.crop
 
 import android.graphics.PointF
 import de.uni.tuebingen.tlceval.custom_views.CropRect
-import de.uni.tuebingen.tlceval.custom_views.sortFromList
 import de.uni.tuebingen.tlceval.data.Capture
 import de.uni.tuebingen.tlceval.data.Point
 import de.uni.tuebingen.tlceval.data.Rect
@@ -92,19 +91,14 @@ class CropModel(val timestamp: Long, val captureDao: CaptureDao, val rectDao: Re
             }!!
         } else {
             return withContext(Dispatchers.Default) {
+                // Top left, top right, bottom right, bottom left as found by the detector,
+                // re-sorting by y would swap the corners of a rotated plate
                 val corners = processor.detectPlate()
                     .toList()
                     .chunked(2)
-                    .map { corner ->
-                        PointF(
-                            corner[0].toFloat(),
-                            corner[1].toFloat()
-                        )
-                    } // TODO: check if corners are implemented the same way in android
+                    .map { corner -> PointF(corner[0], corner[1]) }
                     .take(4) // Just for safety
-                val rect = CropRect()
-                rect.sortFromList(corners)
-                rect
+                CropRect(corners[0], corners[1], corners[2], corners[3])
             }
         }
     }
@@ -118,8 +112,8 @@ class CropModel(val timestamp: Long, val captureDao: CaptureDao, val rectDao: Re
             return withContext(Dispatchers.IO) {
                 Timber.d("Launched!")
                 val corners = listOf(rect.topLeft, rect.topRight, rect.bottomRight, rect.bottomLeft)
-                    .map { listOf(it.x.toInt(), it.y.toInt()) }
-                    .flatten().toIntArray()
+                    .map { listOf(it.x, it.y) }
+                    .flatten().toFloatArray()
 
                 Timber.d("Corners: $corners, ${corners.size}")
 
@@ -128,15 +122,15 @@ class CropModel(val timestamp: Long, val captureDao: CaptureDao, val rectDao: Re
                 Timber.d("Warp plate : $success")
                 while (!success) {
                     Timber.d("Unwarping was not successful. Additional try with altered corner")
-                    corners[0] += 1
-                    corners[1] += 1
+                    corners[0] += 1f
+                    corners[1] += 1f
                     success = processor.warpPlate(corners, orientation.toLong())
                 }
 
                 Timber.d("Success: $success")
 
                 // Save rect
-                val points = corners.asIterable().chunked(2).map { xy -> Point(xy[0], xy[1]) }
+                val points = corners.asIterable().chunked(2).map { xy -> Point(xy[0].toInt(), xy[1].toInt()) }
                 val saveRect = Rect(
                     captureTimestamp = timestamp,
                     top_left = points[0],
@@ -1,3 +1,4 @@
+#[allow(clippy::single_component_path_imports)]
 use image;
 use image::{DynamicImage, GenericImageView, GrayImage};
 use linregress::{FormulaRegressionBuilder, RegressionDataBuilder};
@@ -9,10 +10,13 @@ use tlc_common::{
 
 pub struct BackgroundFitter {
     input: DynamicImage,
+    /// Luma of the input, beyond 255 for fused exposures
+    gray: HDRGrayImage,
     background_fit: Result<HDRGrayImage, String>,
     save_path: String,
 }
 
+#[allow(clippy::needless_return)]
 fn coord_to_poly(x: f64, y: f64) -> Vec<f64> {
     //Test: [2, 3] -> [ 1.,  2.,  3.,  4.,  6.,  9.,  8., 12., 18., 27., 16., 24., 36., 54., 81.]
     return vec![
@@ -37,14 +41,26 @@ fn coord_to_poly(x: f64, y: f64) -> Vec<f64> {
 impl BackgroundFitter {
     pub fn new(image: &DynamicImage, save_path: String) -> Self {
         let input: DynamicImage = image.clone();
+        let gray = input.to_luma8().convert();
+        BackgroundFitter::from_gray(input, gray, save_path)
+    }
+
+    /// Fits the background of a linear image fused from several exposures. Without colors
+    /// the plate is assumed to have dark blobs.
+    pub fn from_hdr(image: &HDRGrayImage, save_path: String) -> Self {
+        let input = DynamicImage::ImageLuma8(image.convert());
+        BackgroundFitter::from_gray(input, image.clone(), save_path)
+    }
 
+    fn from_gray(input: DynamicImage, gray: HDRGrayImage, save_path: String) -> Self {
         let downscale_factor = 4u32 * 4u32;
-        let background_fit = BackgroundFitter::fit_background(&input, downscale_factor);
-        return BackgroundFitter {
-            input: input,
-            background_fit: background_fit,
-            save_path: save_path,
-        };
+        let background_fit = BackgroundFitter::fit_background(&gray, downscale_factor);
+        BackgroundFitter {
+            input,
+            gray,
+            background_fit,
+            save_path,
+        }
     }
 
     pub fn has_potential_dark_blobs(&self) -> bool {
@@ -66,7 +82,7 @@ impl BackgroundFitter {
         debug!("{:?}", self.input.dimensions());
         match &self.background_fit {
             Ok(bf) => {
-                let gray = self.input.to_luma8().convert();
+                let gray = self.gray.clone();
 
                 // Both images are in f64
                 let img = if blobs_dark { gray.invert() } else { gray };
@@ -100,19 +116,40 @@ impl BackgroundFitter {
         }
     }
 
-    fn fit_background(
-        input_image: &DynamicImage,
-        scale_factor: u32,
-    ) -> Result<HDRGrayImage, String> {
-        let (input, target) =
-            BackgroundFitter::build_input_target_from_image(input_image, scale_factor);
+    /// The blobs without the background and without clamping to 8 bit, for fused exposures
+    pub fn remove_background_hdr(&self, blobs_dark: bool) -> Result<HDRGrayImage, String> {
+        match &self.background_fit {
+            Ok(bf) => {
+                let (width, height) = self.gray.dimensions();
+                let subtracted: Vec<f64> = self
+                    .gray
+                    .iter()
+                    .zip(bf.iter())
+                    .map(|(g, b)| {
+                        if blobs_dark {
+                            b.saturating_sub(g)
+                        } else {
+                            g.saturating_sub(b)
+                        }
+                    })
+                    .collect();
+                HDRGrayImage::from_raw(width, height, subtracted)
+                    .ok_or_else(|| "Could not create background removal image".to_string())
+            }
+            Err(err) => Err(err.to_string()),
+        }
+    }
+
+    fn fit_background(gray: &HDRGrayImage, scale_factor: u32) -> Result<HDRGrayImage, String> {
+        let (input, target) = BackgroundFitter::build_input_target(gray, scale_factor);
         let (formula, data) = BackgroundFitter::build_training_formula_data(input, target);
         debug!("To optimize: {}", formula);
-        let (width, height) = input_image.dimensions();
+        let (width, height) = gray.dimensions();
         let (parameters, intercept) = BackgroundFitter::perform_fit(formula, data);
         BackgroundFitter::eval_fit(parameters, intercept, width, height)
     }
 
+    #[allow(clippy::assign_op_pattern, clippy::needless_return)]
     fn build_training_formula_data(
         input: Vec<Vec<f64>>,
         target: Vec<f64>,
@@ -137,13 +174,10 @@ impl BackgroundFitter {
         return (formula.to_string(), ret);
     }
 
-    fn build_input_target_from_image(
-        input_image: &DynamicImage,
-        scale_factor: u32,
-    ) -> (Vec<Vec<f64>>, Vec<f64>) {
-        let (width, height) = input_image.dimensions();
+    #[allow(clippy::needless_return)]
+    fn build_input_target(gray: &HDRGrayImage, scale_factor: u32) -> (Vec<Vec<f64>>, Vec<f64>) {
+        let (width, height) = gray.dimensions();
         debug!("Inputs: {}", width * height);
-        let gray = input_image.to_luma8();
         let mut target: Vec<f64> = Vec::new();
         let mut input: Vec<Vec<f64>> = Vec::new();
 
@@ -151,7 +185,7 @@ impl BackgroundFitter {
             let idx = x + y * width;
             if idx % scale_factor == 0 {
                 input.push(coord_to_poly(x as f64, y as f64));
-                target.push(p[0] as f64);
+                target.push(p[0]);
             }
         }
 
@@ -204,6 +238,7 @@ mod test {
     use crate::BackgroundFitter;
     use assert_approx_eq::assert_approx_eq;
     use image::{DynamicImage, GenericImageView, GrayImage};
+    use tlc_common::{HDRGrayImage, LDRToHDRGray};
 
     #[test]
     fn test_poly_gen() {
@@ -216,6 +251,7 @@ mod test {
         assert!(when.iter().zip(then.iter()).all(|(a, b)| a == b));
     }
 
+    #[allow(clippy::needless_return)]
     fn setup_simple_test_image(width: u32, height: u32) -> DynamicImage {
         let mut raw_vec: Vec<u8> = Vec::with_capacity((width * height) as usize);
         for i in 0..(width * height) {
@@ -225,6 +261,7 @@ mod test {
         return DynamicImage::ImageLuma8(GrayImage::from_vec(width, height, raw_vec).unwrap());
     }
 
+    #[allow(clippy::needless_return, clippy::neg_multiply)]
     fn setup_sine_test_image(width: u32, height: u32) -> DynamicImage {
         let mut raw_vec: Vec<u8> = Vec::with_capacity((width * height) as usize);
         for i in 0..(width * height) {
@@ -240,7 +277,8 @@ mod test {
     }
 
     fn perform_fit(given_image: &DynamicImage) -> Vec<f64> {
-        let (input, target) = BackgroundFitter::build_input_target_from_image(&given_image, 1);
+        let (input, target) =
+            BackgroundFitter::build_input_target(&given_image.to_luma8().convert(), 1);
         let (formula, data) = BackgroundFitter::build_training_formula_data(input, target);
         let (parameters, intercept) = BackgroundFitter::perform_fit(formula, data); // When
         let (width, height) = given_image.dimensions();
@@ -249,10 +287,11 @@ mod test {
     }
 
     #[test]
+    #[allow(clippy::len_zero)]
     fn test_fit_with_python() {
         let given = setup_simple_test_image(100, 100);
 
-        let (input, target) = BackgroundFitter::build_input_target_from_image(&given, 1);
+        let (input, target) = BackgroundFitter::build_input_target(&given.to_luma8().convert(), 1);
         let (formula, data) = BackgroundFitter::build_training_formula_data(input, target);
         let (when_parameters, when_intercept) = BackgroundFitter::perform_fit(formula, data); // When
 
@@ -294,7 +333,7 @@ mod test {
         let when: Vec<f64> = perform_fit(&given_image);
 
         let then: Vec<f64> = given_image
-            .to_luma()
+            .to_luma8()
             .into_vec()
             .iter()
             .map(|x| *x as f64)
@@ -311,7 +350,7 @@ mod test {
         let when: Vec<f64> = perform_fit(&given_image);
 
         let then: Vec<f64> = given_image
-            .to_luma()
+            .to_luma8()
             .into_vec()
             .iter()
             .map(|x| *x as f64)
@@ -321,4 +360,25 @@ mod test {
             assert_approx_eq!(when[i], then[i], 1f64);
         }
     }
+
+    #[test]
+    fn test_hdr_background_keeps_values_above_8_bit() {
+        // A spot twice as bright as 8 bit allows on a linear gradient
+        let given = HDRGrayImage::from_fn(100, 100, |x, y| {
+            let spot = (x as i32 - 50).pow(2) + (y as i32 - 50).pow(2) < 25;
+            image::Luma([200.0 + x as f64 + if spot { 300.0 } else { 0.0 }])
+        });
+
+        let when = BackgroundFitter::from_hdr(&given, String::new())
+            .remove_background_hdr(false)
+            .unwrap();
+
+        // The plain least squares fit is pulled up a little by the spot
+        assert!((when.get_pixel(50, 50)[0] - 300.0).abs() < 30.0);
+        assert!(when.get_pixel(10, 80)[0] < 5.0);
+        let dark = BackgroundFitter::from_hdr(&given, String::new())
+            .remove_background_hdr(true)
+            .unwrap();
+        assert_eq!(dark.get_pixel(50, 50)[0], 0.0);
+    }
 }
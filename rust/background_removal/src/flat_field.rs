@@ -0,0 +1,297 @@
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageBuffer, Rgb, RgbImage};
+use std::convert::TryInto;
+
+type RgbFloatImage = ImageBuffer<Rgb<f32>, Vec<f32>>;
+
+/// Longer side of the stored profile. Illumination varies slowly, so a coarse grid suffices
+/// and averaging down to it removes plate texture and noise.
+const PROFILE_SIZE: u32 = 128;
+
+/// Darker regions of the reference are not amplified beyond this
+const MIN_GAIN: f32 = 0.05;
+
+const MAGIC: &[u8; 6] = b"TLCFF1";
+
+/// Illumination and vignetting profile of a capture setup, measured on a blank plate.
+/// Dividing a plate crop by it evens out the lighting before the background removal.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FlatField {
+    /// Relative brightness per channel with a mean of 1
+    gain: RgbFloatImage,
+    /// Sensor offset per channel, measured with the light off
+    dark: Option<RgbFloatImage>,
+}
+
+impl FlatField {
+    /// Builds the profile from a crop of a blank plate and optionally of a dark frame, both
+    /// warped with the same plate corners as the plates to correct
+    pub fn from_reference(
+        blank: &DynamicImage,
+        dark: Option<&DynamicImage>,
+    ) -> Result<Self, String> {
+        let blank = profile(blank);
+        let dark = dark.map(profile);
+        if let Some(dark) = &dark {
+            if dark.dimensions() != blank.dimensions() {
+                return Err("The dark frame does not match the blank plate".to_string());
+            }
+        }
+
+        let mut gain = match &dark {
+            Some(dark) => subtract(&blank, dark),
+            None => blank,
+        };
+        for c in 0..3 {
+            let pixels = (gain.width() * gain.height()) as f32;
+            let mean = gain.pixels().map(|p| p[c]).sum::<f32>() / pixels;
+            if mean <= 0.0 {
+                return Err("The blank plate is too dark".to_string());
+            }
+            gain.pixels_mut()
+                .for_each(|p| p[c] = (p[c] / mean).max(MIN_GAIN));
+        }
+        Ok(FlatField { gain, dark })
+    }
+
+    /// Corrects a plate crop of any size
+    pub fn apply(&self, image: &DynamicImage) -> DynamicImage {
+        let rgb = image.to_rgb8();
+        let (width, height) = rgb.dimensions();
+        let (gain, dark) = (
+            resized(&self.gain, width, height),
+            self.dark.as_ref().map(|dark| resized(dark, width, height)),
+        );
+
+        let corrected = RgbImage::from_fn(width, height, |x, y| {
+            let p = rgb.get_pixel(x, y);
+            let g = gain.get_pixel(x, y);
+            let offset = dark.as_ref().map_or([0.0; 3], |d| d.get_pixel(x, y).0);
+            Rgb([0, 1, 2].map(|c| {
+                ((p[c] as f32 - offset[c]).max(0.0) / g[c])
+                    .round()
+                    .clamp(0.0, 255.0) as u8
+            }))
+        });
+        DynamicImage::ImageRgb8(corrected)
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        std::fs::write(path, self.to_bytes())
+            .map_err(|e| format!("Saving the flat field failed: {}", e))
+    }
+
+    pub fn load(path: &str) -> Result<Self, String> {
+        let bytes =
+            std::fs::read(path).map_err(|e| format!("Reading the flat field failed: {}", e))?;
+        FlatField::from_bytes(&bytes)
+    }
+
+    /// Magic, width, height, dark frame flag and the little endian gain and dark values
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&self.gain.width().to_le_bytes());
+        bytes.extend_from_slice(&self.gain.height().to_le_bytes());
+        bytes.push(self.dark.is_some() as u8);
+        for image in std::iter::once(&self.gain).chain(self.dark.iter()) {
+            image
+                .as_raw()
+                .iter()
+                .for_each(|v| bytes.extend_from_slice(&v.to_le_bytes()));
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        let invalid = || "Invalid flat field data".to_string();
+        if bytes.len() < 15 || &bytes[..6] != MAGIC {
+            return Err(invalid());
+        }
+        let width = u32::from_le_bytes(bytes[6..10].try_into().unwrap());
+        let height = u32::from_le_bytes(bytes[10..14].try_into().unwrap());
+        let has_dark = bytes[14] == 1;
+        // Upscaling an empty profile in `apply` would underflow
+        if width == 0 || height == 0 {
+            return Err(invalid());
+        }
+
+        let values: Vec<f32> = bytes[15..]
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+            .collect();
+        let images = if has_dark { 2 } else { 1 };
+        let length = (width as usize)
+            .checked_mul(height as usize)
+            .and_then(|pixels| pixels.checked_mul(3))
+            .ok_or_else(invalid)?;
+        if Some(values.len()) != length.checked_mul(images) {
+            return Err(invalid());
+        }
+
+        let gain = RgbFloatImage::from_raw(width, height, values[..length].to_vec())
+            .ok_or_else(invalid)?;
+        let dark = if has_dark {
+            Some(
+                RgbFloatImage::from_raw(width, height, values[length..].to_vec())
+                    .ok_or_else(invalid)?,
+            )
+        } else {
+            None
+        };
+        Ok(FlatField { gain, dark })
+    }
+}
+
+/// Averaged, downscaled copy of a reference crop
+fn profile(image: &DynamicImage) -> RgbFloatImage {
+    let rgb = image.to_rgb32f();
+    let (width, height) = rgb.dimensions();
+    let scale = (PROFILE_SIZE as f32 / width.max(height) as f32).min(1.0);
+    let (w, h) = (
+        ((width as f32 * scale).round() as u32).max(1),
+        ((height as f32 * scale).round() as u32).max(1),
+    );
+    // Still in the 0 to 1 range, which resizing keeps
+    let mut small = image::imageops::resize(&rgb, w, h, FilterType::Triangle);
+    // Back to the 0 to 255 range of the crops
+    small
+        .pixels_mut()
+        .for_each(|p| p.0 = p.0.map(|v| v * 255.0));
+    small
+}
+
+fn subtract(image: &RgbFloatImage, other: &RgbFloatImage) -> RgbFloatImage {
+    RgbFloatImage::from_fn(image.width(), image.height(), |x, y| {
+        let (a, b) = (image.get_pixel(x, y), other.get_pixel(x, y));
+        Rgb([0, 1, 2].map(|c| (a[c] - b[c]).max(0.0)))
+    })
+}
+
+/// Bilinear upscaling. `imageops::resize` would clamp the gains to 1.
+fn resized(image: &RgbFloatImage, width: u32, height: u32) -> RgbFloatImage {
+    let (w, h) = image.dimensions();
+    let source = |x: u32, size: u32, source_size: u32| {
+        let s = ((x as f32 + 0.5) * source_size as f32 / size as f32 - 0.5)
+            .clamp(0.0, (source_size - 1) as f32);
+        let first = s.floor() as u32;
+        (first, (first + 1).min(source_size - 1), s - first as f32)
+    };
+
+    RgbFloatImage::from_fn(width, height, |x, y| {
+        let (x0, x1, fx) = source(x, width, w);
+        let (y0, y1, fy) = source(y, height, h);
+        let value = |c: usize| {
+            let top = image.get_pixel(x0, y0)[c] * (1.0 - fx) + image.get_pixel(x1, y0)[c] * fx;
+            let bottom = image.get_pixel(x0, y1)[c] * (1.0 - fx) + image.get_pixel(x1, y1)[c] * fx;
+            top * (1.0 - fy) + bottom * fy
+        };
+        Rgb([value(0), value(1), value(2)])
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::FlatField;
+    use image::{DynamicImage, Rgb, RgbImage};
+
+    /// Brightness falling off towards the corners like lens vignetting
+    fn vignetting(width: u32, height: u32, x: u32, y: u32) -> f32 {
+        let (dx, dy) = (
+            x as f32 / width as f32 - 0.5,
+            y as f32 / height as f32 - 0.3,
+        );
+        1.0 - 0.8 * (dx * dx + dy * dy)
+    }
+
+    fn plate(width: u32, height: u32, offset: f32, spot: bool) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+            let in_spot = spot && (x as i32 - 60).pow(2) + (y as i32 - 150).pow(2) < 100;
+            let albedo = if in_spot { 80.0 } else { 200.0 };
+            let value = albedo * vignetting(width, height, x, y) + offset;
+            Rgb([
+                value.round() as u8,
+                (value * 0.9).round() as u8,
+                value.round() as u8,
+            ])
+        }))
+    }
+
+    fn background_range(image: &DynamicImage) -> (u8, u8) {
+        image
+            .to_rgb8()
+            .enumerate_pixels()
+            .filter(|(x, y, _)| (*x as i32 - 60).pow(2) + (*y as i32 - 150).pow(2) > 400)
+            .fold((255, 0), |(lo, hi), (_, _, p)| (lo.min(p[0]), hi.max(p[0])))
+    }
+
+    #[test]
+    fn test_removes_vignetting() {
+        let reference = plate(200, 400, 0.0, false);
+        let given = plate(200, 400, 0.0, true);
+
+        let when = FlatField::from_reference(&reference, None)
+            .unwrap()
+            .apply(&given);
+
+        let (before_min, before_max) = background_range(&given);
+        let (after_min, after_max) = background_range(&when);
+        assert!(before_max - before_min > 50);
+        assert!(after_max - after_min <= 8, "{} {}", after_min, after_max);
+        // The spot keeps its contrast to the background
+        let spot = when.to_rgb8().get_pixel(60, 150)[0] as f32;
+        let background = when.to_rgb8().get_pixel(100, 150)[0] as f32;
+        assert!((spot / background - 0.4).abs() < 0.05);
+    }
+
+    #[test]
+    fn test_subtracts_dark_frame() {
+        let dark = DynamicImage::ImageRgb8(RgbImage::from_pixel(200, 400, Rgb([30, 30, 30])));
+        let reference = plate(200, 400, 30.0, false);
+        let given = plate(200, 400, 30.0, true);
+
+        let when = FlatField::from_reference(&reference, Some(&dark))
+            .unwrap()
+            .apply(&given);
+
+        let (after_min, after_max) = background_range(&when);
+        assert!(after_max - after_min <= 8, "{} {}", after_min, after_max);
+    }
+
+    #[test]
+    fn test_applies_to_other_crop_sizes() {
+        let flat_field = FlatField::from_reference(&plate(200, 400, 0.0, false), None).unwrap();
+        let given = plate(100, 200, 0.0, false);
+
+        let when = flat_field.apply(&given);
+
+        let (after_min, after_max) = background_range(&when);
+        assert!(after_max - after_min <= 8, "{} {}", after_min, after_max);
+    }
+
+    #[test]
+    fn test_storage_round_trip() {
+        let dark = DynamicImage::ImageRgb8(RgbImage::from_pixel(50, 100, Rgb([5, 6, 7])));
+        let given = FlatField::from_reference(&plate(50, 100, 10.0, false), Some(&dark)).unwrap();
+
+        let when = FlatField::from_bytes(&given.to_bytes()).unwrap();
+
+        assert_eq!(when, given);
+        assert!(FlatField::from_bytes(b"TLCFF1 broken").is_err());
+    }
+
+    #[test]
+    fn test_rejects_invalid_sizes() {
+        let header = |width: u32, height: u32| {
+            let mut bytes = b"TLCFF1".to_vec();
+            bytes.extend_from_slice(&width.to_le_bytes());
+            bytes.extend_from_slice(&height.to_le_bytes());
+            bytes.push(0);
+            bytes
+        };
+
+        assert!(FlatField::from_bytes(&header(0, 10)).is_err());
+        assert!(FlatField::from_bytes(&header(10, 0)).is_err());
+        // The number of values overflows u32
+        assert!(FlatField::from_bytes(&header(65536, 65536)).is_err());
+    }
+}
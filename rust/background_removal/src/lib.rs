@@ -1,3 +1,5 @@
 pub use background_fitter::BackgroundFitter;
+pub use flat_field::FlatField;
 
 mod background_fitter;
+mod flat_field;
@@ -0,0 +1,220 @@
+use image::GrayImage;
+use std::collections::HashSet;
+use tlc_common::Circle;
+
+/// Shape and intensity measures of a detected spot, to flag streaking or irregular spots
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BlobDescriptors {
+    /// Number of pixels
+    pub area: f32,
+    /// Length of the outline in pixels
+    pub perimeter: f32,
+    /// 1 for a disk, smaller for irregular outlines
+    pub circularity: f32,
+    /// 0 for a circle, towards 1 for a streak
+    pub eccentricity: f32,
+    /// Angle of the major axis to the x axis in radians, from -pi/2 to pi/2
+    pub orientation: f32,
+    pub intensity_mean: f32,
+    pub intensity_max: u8,
+    /// Mean intensity over the mean of a ring around the spot
+    pub signal_to_background: f32,
+    /// Extent below the peak, towards the baseline, over the extent above it. About 1 for a
+    /// symmetric spot and larger for a tailing one.
+    pub tailing_factor: f32,
+}
+
+impl BlobDescriptors {
+    /// Measures the spot made of `pixels`, `circle` is its fitted circle
+    pub fn measure(image: &GrayImage, pixels: &[(u32, u32)], circle: &Circle) -> Self {
+        let area = pixels.len() as f32;
+        let members: HashSet<(u32, u32)> = pixels.iter().copied().collect();
+
+        // Each pixel side facing the outside, corrected for the staircase of slanted edges
+        let outside = |x: i64, y: i64| x < 0 || y < 0 || !members.contains(&(x as u32, y as u32));
+        let sides: usize = pixels
+            .iter()
+            .map(|(x, y)| {
+                let (x, y) = (*x as i64, *y as i64);
+                [(x - 1, y), (x + 1, y), (x, y - 1), (x, y + 1)]
+                    .iter()
+                    .filter(|(nx, ny)| outside(*nx, *ny))
+                    .count()
+            })
+            .sum();
+        let perimeter = sides as f32 * std::f32::consts::FRAC_PI_4;
+        let circularity = if perimeter > 0.0 {
+            4.0 * std::f32::consts::PI * area / (perimeter * perimeter)
+        } else {
+            0.0
+        };
+
+        let count = area.max(1.0);
+        let (mx, my) = pixels.iter().fold((0.0, 0.0), |(sx, sy), (x, y)| {
+            (sx + *x as f32 / count, sy + *y as f32 / count)
+        });
+        let (mu20, mu02, mu11) = pixels.iter().fold((0.0, 0.0, 0.0), |(a, b, c), (x, y)| {
+            let (dx, dy) = (*x as f32 - mx, *y as f32 - my);
+            (
+                a + dx * dx / count,
+                b + dy * dy / count,
+                c + dx * dy / count,
+            )
+        });
+        let root = ((mu20 - mu02).powi(2) + 4.0 * mu11 * mu11).sqrt();
+        let (major, minor) = ((mu20 + mu02 + root) / 2.0, (mu20 + mu02 - root) / 2.0);
+        let eccentricity = if major > 0.0 {
+            (1.0 - minor.max(0.0) / major).sqrt()
+        } else {
+            0.0
+        };
+        let orientation = 0.5 * (2.0 * mu11).atan2(mu20 - mu02);
+
+        let values = pixels.iter().map(|(x, y)| image.get_pixel(*x, *y)[0]);
+        let intensity_mean = values.clone().map(|v| v as f32).sum::<f32>() / count;
+        let intensity_max = values.max().unwrap_or(0);
+
+        let background = ring_mean(image, circle, &members);
+        let signal_to_background = intensity_mean / background.max(1.0);
+
+        let top = pixels.iter().map(|(_, y)| *y).min().unwrap_or(0) as f32;
+        let bottom = pixels.iter().map(|(_, y)| *y).max().unwrap_or(0) as f32;
+        // Measured from the peak like in column chromatography, the centroid follows the tail
+        let peaks: Vec<f32> = pixels
+            .iter()
+            .filter(|(x, y)| image.get_pixel(*x, *y)[0] == intensity_max)
+            .map(|(_, y)| *y as f32)
+            .collect();
+        let peak = if peaks.is_empty() {
+            circle.center.y
+        } else {
+            peaks.iter().sum::<f32>() / peaks.len() as f32
+        };
+        let front = (peak - top + 0.5).max(0.5);
+        let tail = (bottom - peak + 0.5).max(0.5);
+
+        BlobDescriptors {
+            area,
+            perimeter,
+            circularity,
+            eccentricity,
+            orientation,
+            intensity_mean,
+            intensity_max,
+            signal_to_background,
+            tailing_factor: tail / front,
+        }
+    }
+
+    /// Measures the pixels inside `circle`, for blobs without a labeled component
+    pub fn within_circle(image: &GrayImage, circle: &Circle) -> Self {
+        let (width, height) = image.dimensions();
+        let pixels: Vec<(u32, u32)> = circle.pixels(width, height).collect();
+        BlobDescriptors::measure(image, &pixels, circle)
+    }
+
+    /// The descriptors in the order of the fields
+    pub fn to_vec(&self) -> Vec<f32> {
+        vec![
+            self.area,
+            self.perimeter,
+            self.circularity,
+            self.eccentricity,
+            self.orientation,
+            self.intensity_mean,
+            self.intensity_max as f32,
+            self.signal_to_background,
+            self.tailing_factor,
+        ]
+    }
+}
+
+/// Mean of the pixels between 1.5 and 2 radii around the spot, outside of it
+fn ring_mean(image: &GrayImage, circle: &Circle, members: &HashSet<(u32, u32)>) -> f32 {
+    let (width, height) = image.dimensions();
+    let outer = Circle::new(
+        circle.center.x,
+        circle.center.y,
+        2.0 * circle.radius.max(1.0),
+    );
+    let inner = 1.5 * circle.radius.max(1.0);
+    let (sum, count) = outer
+        .pixels(width, height)
+        .filter(|(x, y)| {
+            let (dx, dy) = (*x as f32 - circle.center.x, *y as f32 - circle.center.y);
+            dx * dx + dy * dy >= inner * inner && !members.contains(&(*x, *y))
+        })
+        .fold((0.0, 0usize), |(sum, count), (x, y)| {
+            (sum + image.get_pixel(x, y)[0] as f32, count + 1)
+        });
+    if count > 0 {
+        sum / count as f32
+    } else {
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{BlobDescriptors, BlobDetector, ThresholdDetector};
+    use image::{GrayImage, Luma};
+    use tlc_common::Circle;
+
+    /// An elliptic spot with sigmas along x and y, stretched downwards by `tail`
+    fn spot(sigma_x: f32, sigma_y: f32, tail: f32) -> GrayImage {
+        GrayImage::from_fn(200, 160, |x, y| {
+            let dx = x as f32 - 100.0;
+            let dy = y as f32 - 70.0;
+            let sigma_y = if dy > 0.0 { sigma_y * tail } else { sigma_y };
+            let value = 200.0
+                * (-dx * dx / (2.0 * sigma_x * sigma_x)).exp()
+                * (-dy * dy / (2.0 * sigma_y * sigma_y)).exp();
+            Luma([value as u8])
+        })
+    }
+
+    fn only_blob(image: &GrayImage) -> BlobDescriptors {
+        let blobs = ThresholdDetector::default().detect(image);
+        assert_eq!(blobs.len(), 1, "{:?}", blobs);
+        blobs.values().next().unwrap().descriptors
+    }
+
+    #[test]
+    fn test_round_spot_descriptors() {
+        let when = only_blob(&spot(6.0, 6.0, 1.0));
+
+        assert!((when.circularity - 1.0).abs() < 0.15, "{:?}", when);
+        assert!(when.eccentricity < 0.3, "{:?}", when);
+        assert!((when.tailing_factor - 1.0).abs() < 0.15, "{:?}", when);
+        assert_eq!(when.intensity_max, 200);
+        assert!(when.signal_to_background > 10.0, "{:?}", when);
+    }
+
+    #[test]
+    fn test_streak_descriptors() {
+        let when = only_blob(&spot(4.0, 4.0, 2.5));
+
+        assert!(when.tailing_factor > 2.0, "{:?}", when);
+        assert!(when.eccentricity > 0.6, "{:?}", when);
+        // The major axis is vertical
+        assert!(
+            (when.orientation.abs() - std::f32::consts::FRAC_PI_2).abs() < 0.1,
+            "{:?}",
+            when
+        );
+    }
+
+    #[test]
+    fn test_descriptors_within_circle() {
+        let given = GrayImage::from_fn(60, 60, |x, y| {
+            let inside = (x as f32 - 30.0).powi(2) + (y as f32 - 30.0).powi(2) <= 100.0;
+            Luma([if inside { 100 } else { 10 }])
+        });
+
+        let when = BlobDescriptors::within_circle(&given, &Circle::new(30.0, 30.0, 10.0));
+
+        assert!((when.area - 317.0).abs() < 10.0, "{:?}", when);
+        assert_eq!(when.intensity_mean, 100.0);
+        assert_eq!(when.signal_to_background, 10.0);
+    }
+}
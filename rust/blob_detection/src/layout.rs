@@ -0,0 +1,252 @@
+use image::GrayImage;
+use std::collections::HashMap;
+use tlc_common::{clip_span, pixel_span, Circle};
+
+use crate::{Blob, BlobDescriptors};
+
+/// Expected application grid of equally spaced lanes, centred on the plate. Hand spotted
+/// plates are usually shifted a bit, so the grid follows the detected blobs sideways.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LanePrior {
+    pub lanes: u32,
+    /// Distance between neighbouring lanes as a fraction of the image width
+    pub spacing: f32,
+    /// Height of the application line above the bottom edge as a fraction of the image height
+    pub baseline: f32,
+    /// Blobs further from a lane than this fraction of the spacing are discarded
+    pub tolerance: f32,
+    /// Moves kept blobs sideways onto the centre of their lane
+    pub snap: bool,
+}
+
+impl LanePrior {
+    pub fn new(lanes: u32, spacing: f32, baseline: f32) -> Self {
+        LanePrior {
+            lanes,
+            spacing,
+            baseline,
+            tolerance: 0.35,
+            snap: false,
+        }
+    }
+
+    /// Lane centres in pixels, without the shift towards the detected blobs
+    fn lane_positions(&self, width: u32) -> Vec<f32> {
+        let spacing = self.spacing * width as f32;
+        let first = width as f32 / 2.0 - (self.lanes as f32 - 1.0) / 2.0 * spacing;
+        (0..self.lanes)
+            .map(|i| first + i as f32 * spacing)
+            .collect()
+    }
+
+    /// Drops blobs between the lanes and proposes a blob for each lane without one. Proposed
+    /// blobs have a response of 0 and ids after the largest detected id.
+    pub fn apply(&self, image: &GrayImage, blobs: HashMap<u32, Blob>) -> HashMap<u32, Blob> {
+        if self.lanes == 0 || self.spacing <= 0.0 {
+            return blobs;
+        }
+        let (width, height) = image.dimensions();
+        let spacing = self.spacing * width as f32;
+        let nearest = |lanes: &[f32], x: f32| {
+            lanes
+                .iter()
+                .enumerate()
+                .map(|(i, lane)| (i, x - lane))
+                .min_by(|a, b| a.1.abs().total_cmp(&b.1.abs()))
+                .unwrap()
+        };
+
+        let mut lanes = self.lane_positions(width);
+        let shifts: Vec<f32> = blobs
+            .values()
+            .map(|blob| nearest(&lanes, blob.circle.center.x).1)
+            .filter(|offset| offset.abs() <= spacing / 2.0)
+            .collect();
+        if let Some(shift) = median(shifts) {
+            lanes.iter_mut().for_each(|lane| *lane += shift);
+        }
+
+        let mut occupied = vec![false; lanes.len()];
+        let mut kept: HashMap<u32, Blob> = blobs
+            .into_iter()
+            .filter_map(|(key, mut blob)| {
+                let (lane, offset) = nearest(&lanes, blob.circle.center.x);
+                if offset.abs() > self.tolerance * spacing {
+                    return None;
+                }
+                occupied[lane] = true;
+                if self.snap {
+                    blob.circle.center.x = lanes[lane];
+                }
+                Some((key, blob))
+            })
+            .collect();
+
+        let expected_y = median(kept.values().map(|b| b.circle.center.y).collect())
+            .unwrap_or((1.0 - self.baseline) * height as f32);
+        let radius =
+            median(kept.values().map(|b| b.circle.radius).collect()).unwrap_or(spacing / 4.0);
+        let mut next_key = kept.keys().max().map_or(1, |key| key + 1);
+        for (lane, x) in lanes.iter().enumerate() {
+            if occupied[lane] || *x < 0.0 || *x >= width as f32 {
+                continue;
+            }
+            let circle = Circle::new(*x, refine_row(image, *x, expected_y, radius), radius);
+            kept.insert(
+                next_key,
+                Blob {
+                    circle,
+                    response: 0.0,
+                    descriptors: BlobDescriptors::within_circle(image, &circle),
+                },
+            );
+            next_key += 1;
+        }
+        kept
+    }
+}
+
+/// Moves `y` to the intensity weighted centre of the lane within two radii, so a faint spot
+/// that was too weak for detection is still centred
+fn refine_row(image: &GrayImage, x: f32, y: f32, radius: f32) -> f32 {
+    let (width, height) = image.dimensions();
+    let columns = clip_span(pixel_span(x - radius, x + radius), width);
+    let rows = clip_span(pixel_span(y - 2.0 * radius, y + 2.0 * radius), height);
+
+    let rows: Vec<(u32, f32)> = rows
+        .map(|row| {
+            let sum: u32 = columns
+                .clone()
+                .map(|col| image.get_pixel(col, row)[0] as u32)
+                .sum();
+            (row, sum as f32)
+        })
+        .collect();
+    let floor = rows.iter().map(|(_, v)| *v).fold(f32::MAX, f32::min);
+    let (weighted, total) = rows.iter().fold((0.0, 0.0), |(weighted, total), (row, v)| {
+        (weighted + *row as f32 * (v - floor), total + (v - floor))
+    });
+    if total > 0.0 {
+        weighted / total
+    } else {
+        y
+    }
+}
+
+fn median(mut values: Vec<f32>) -> Option<f32> {
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_by(|a, b| a.total_cmp(b));
+    let mid = values.len() / 2;
+    Some(if values.len() % 2 == 0 {
+        (values[mid - 1] + values[mid]) / 2.0
+    } else {
+        values[mid]
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test_util::gaussian_spots;
+    use crate::{
+        Blob, BlobDescriptors, BlobDetectionParams, BlobDetector, LanePrior, ThresholdDetector,
+        ThresholdMethod,
+    };
+    use image::GrayImage;
+    use std::collections::HashMap;
+    use tlc_common::Circle;
+
+    fn blob(x: f32, y: f32) -> Blob {
+        let circle = Circle::new(x, y, 10.0);
+        Blob {
+            circle,
+            response: 0.5,
+            descriptors: BlobDescriptors::within_circle(&GrayImage::new(400, 200), &circle),
+        }
+    }
+
+    #[test]
+    fn test_discards_blobs_between_lanes() {
+        // Lanes at 125, 200 and 275
+        let prior = LanePrior::new(3, 0.1875, 0.1);
+        let given: HashMap<u32, Blob> = vec![
+            (1, blob(125.0, 80.0)),
+            (2, blob(200.0, 80.0)),
+            (3, blob(275.0, 82.0)),
+            (4, blob(240.0, 120.0)),
+        ]
+        .into_iter()
+        .collect();
+
+        let when = prior.apply(&GrayImage::new(400, 200), given);
+
+        assert_eq!(when.len(), 3, "{:?}", when);
+        assert!(!when.contains_key(&4));
+    }
+
+    #[test]
+    fn test_follows_shifted_lanes_and_snaps() {
+        let prior = LanePrior {
+            snap: true,
+            ..LanePrior::new(3, 0.1875, 0.1)
+        };
+        let given: HashMap<u32, Blob> = vec![
+            (1, blob(137.0, 80.0)),
+            (2, blob(211.0, 80.0)),
+            (3, blob(290.0, 80.0)),
+        ]
+        .into_iter()
+        .collect();
+
+        let when = prior.apply(&GrayImage::new(400, 200), given);
+
+        assert_eq!(when.len(), 3, "{:?}", when);
+        assert_eq!(when[&1].circle.center.x, 137.0);
+        assert_eq!(when[&3].circle.center.x, 287.0);
+    }
+
+    #[test]
+    fn test_proposes_missed_faint_spot() {
+        let given = gaussian_spots(
+            400,
+            200,
+            &[
+                (125.0, 80.0, 6.0, 200.0),
+                (200.0, 80.0, 6.0, 200.0),
+                (275.0, 90.0, 6.0, 30.0),
+            ],
+        );
+        let otsu = BlobDetectionParams {
+            threshold: ThresholdMethod::Otsu,
+            ..BlobDetectionParams::default()
+        };
+        let params = BlobDetectionParams {
+            layout: Some(LanePrior::new(3, 0.1875, 0.1)),
+            ..otsu
+        };
+
+        let without = ThresholdDetector::new(otsu).detect(&given);
+        let when = ThresholdDetector::new(params).detect(&given);
+
+        assert_eq!(without.len(), 2, "{:?}", without);
+        assert_eq!(when.len(), 3, "{:?}", when);
+        let proposed = when.values().find(|blob| blob.response == 0.0).unwrap();
+        assert_eq!(proposed.circle.center.x, 275.0);
+        assert!(
+            (proposed.circle.center.y - 90.0).abs() < 3.0,
+            "{:?}",
+            proposed
+        );
+    }
+
+    #[test]
+    fn test_proposes_spots_on_baseline_without_detections() {
+        let prior = LanePrior::new(2, 0.25, 0.1);
+
+        let when = prior.apply(&GrayImage::new(400, 200), HashMap::new());
+
+        assert_eq!(when.len(), 2);
+        assert!(when.values().all(|blob| blob.circle.center.y == 180.0));
+    }
+}
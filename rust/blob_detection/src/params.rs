@@ -0,0 +1,196 @@
+use imageproc::region_labelling::Connectivity;
+
+use crate::{LanePrior, WatershedSeeds};
+
+/// How the background removed plate is split into spots and background
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ThresholdMethod {
+    /// Brighter than the mean of the whole plate
+    Mean,
+    /// Otsu's level, which separates the histogram into two classes
+    Otsu,
+    /// Brighter than the mean of the surrounding block by `offset`, for uneven plates.
+    /// The block radius is a fraction of the larger image side.
+    Adaptive { block_radius: f32, offset: u8 },
+}
+
+impl ThresholdMethod {
+    /// Parses `mean`, `otsu` or `adaptive`, the latter with a block of 5 % and an offset of 5
+    pub fn from_name(name: &str) -> Result<Self, String> {
+        match name.to_lowercase().as_str() {
+            "mean" => Ok(ThresholdMethod::Mean),
+            "otsu" => Ok(ThresholdMethod::Otsu),
+            "adaptive" => Ok(ThresholdMethod::Adaptive {
+                block_radius: 0.05,
+                offset: 5,
+            }),
+            _ => Err(format!("Unknown threshold method {}", name)),
+        }
+    }
+}
+
+/// Settings of the `ThresholdDetector`. Sizes are fractions of the larger image side, so
+/// the same settings work for crops of any resolution.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BlobDetectionParams {
+    /// Allowed deviation of the bounding box aspect ratio from 1
+    pub aspect_ratio_tolerance: f32,
+    /// Bounding box side limits
+    pub min_size: f32,
+    pub max_size: f32,
+    pub threshold: ThresholdMethod,
+    /// Radius of the opening, which removes specks and thin bridges between spots
+    pub opening_radius: f32,
+    pub connectivity: Connectivity,
+    /// Splits touching spots, which form one component, before the size and shape filters
+    pub watershed: Option<WatershedSeeds>,
+    /// Expected lanes, to drop stray components and fill lanes without a detected spot
+    pub layout: Option<LanePrior>,
+}
+
+impl Default for BlobDetectionParams {
+    fn default() -> Self {
+        BlobDetectionParams {
+            aspect_ratio_tolerance: 0.75,
+            min_size: 0.02,
+            max_size: 0.25,
+            threshold: ThresholdMethod::Mean,
+            opening_radius: 0.0075,
+            connectivity: Connectivity::Four,
+            watershed: None,
+            layout: None,
+        }
+    }
+}
+
+impl BlobDetectionParams {
+    /// Starts from the defaults
+    pub fn builder() -> BlobDetectionParamsBuilder {
+        BlobDetectionParamsBuilder {
+            params: BlobDetectionParams::default(),
+        }
+    }
+}
+
+/// Changes single settings and checks them together in `build`
+#[derive(Debug, Clone, Copy)]
+pub struct BlobDetectionParamsBuilder {
+    params: BlobDetectionParams,
+}
+
+impl BlobDetectionParamsBuilder {
+    pub fn aspect_ratio_tolerance(mut self, tolerance: f32) -> Self {
+        self.params.aspect_ratio_tolerance = tolerance;
+        self
+    }
+
+    pub fn size(mut self, min_size: f32, max_size: f32) -> Self {
+        self.params.min_size = min_size;
+        self.params.max_size = max_size;
+        self
+    }
+
+    pub fn threshold(mut self, threshold: ThresholdMethod) -> Self {
+        self.params.threshold = threshold;
+        self
+    }
+
+    pub fn opening_radius(mut self, radius: f32) -> Self {
+        self.params.opening_radius = radius;
+        self
+    }
+
+    pub fn connectivity(mut self, connectivity: Connectivity) -> Self {
+        self.params.connectivity = connectivity;
+        self
+    }
+
+    pub fn watershed(mut self, seeds: Option<WatershedSeeds>) -> Self {
+        self.params.watershed = seeds;
+        self
+    }
+
+    pub fn layout(mut self, layout: Option<LanePrior>) -> Self {
+        self.params.layout = layout;
+        self
+    }
+
+    pub fn build(self) -> Result<BlobDetectionParams, String> {
+        let params = self.params;
+        if params.aspect_ratio_tolerance < 0.0 {
+            return Err("The aspect ratio tolerance must not be negative".to_string());
+        }
+        if params.min_size < 0.0 || params.min_size > params.max_size || params.max_size > 1.0 {
+            return Err(format!(
+                "Invalid blob size range {} to {}",
+                params.min_size, params.max_size
+            ));
+        }
+        if !(0.0..0.5).contains(&params.opening_radius) {
+            return Err(format!("Invalid opening radius {}", params.opening_radius));
+        }
+        if let ThresholdMethod::Adaptive { block_radius, .. } = params.threshold {
+            if block_radius <= 0.0 || block_radius > 1.0 {
+                return Err(format!("Invalid adaptive block radius {}", block_radius));
+            }
+        }
+        if let Some(layout) = params.layout {
+            if layout.spacing <= 0.0 || !(0.0..=1.0).contains(&layout.baseline) {
+                return Err(format!(
+                    "Invalid lane layout with a spacing of {} and a baseline at {}",
+                    layout.spacing, layout.baseline
+                ));
+            }
+        }
+        Ok(params)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{BlobDetectionParams, ThresholdMethod};
+    use imageproc::region_labelling::Connectivity;
+
+    #[test]
+    fn test_builder_changes_only_given_values() {
+        let when = BlobDetectionParams::builder()
+            .size(0.005, 0.05)
+            .threshold(ThresholdMethod::Otsu)
+            .connectivity(Connectivity::Eight)
+            .build()
+            .unwrap();
+
+        assert_eq!(when.min_size, 0.005);
+        assert_eq!(when.max_size, 0.05);
+        assert_eq!(when.threshold, ThresholdMethod::Otsu);
+        assert_eq!(when.connectivity, Connectivity::Eight);
+        assert_eq!(
+            when.aspect_ratio_tolerance,
+            BlobDetectionParams::default().aspect_ratio_tolerance
+        );
+    }
+
+    #[test]
+    fn test_builder_rejects_invalid_values() {
+        assert!(BlobDetectionParams::builder()
+            .size(0.3, 0.1)
+            .build()
+            .is_err());
+        assert!(BlobDetectionParams::builder()
+            .aspect_ratio_tolerance(-1.0)
+            .build()
+            .is_err());
+        assert!(BlobDetectionParams::builder()
+            .threshold(ThresholdMethod::Adaptive {
+                block_radius: 0.0,
+                offset: 0
+            })
+            .build()
+            .is_err());
+        assert!(ThresholdMethod::from_name("median").is_err());
+        assert_eq!(
+            ThresholdMethod::from_name("Otsu"),
+            Ok(ThresholdMethod::Otsu)
+        );
+    }
+}
@@ -0,0 +1,343 @@
+use image::GrayImage;
+use std::collections::HashMap;
+use tlc_common::Circle;
+
+use crate::{Blob, BlobDescriptors, BlobDetector};
+
+/// Kernel half size in standard deviations
+const KERNEL_EXTENT: f32 = 3.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScaleSpaceMethod {
+    LaplacianOfGaussian,
+    /// Approximates the Laplacian by subtracting neighbouring blur levels, which is cheaper
+    DifferenceOfGaussian,
+}
+
+/// Finds blobs as maxima of the scale normalized Laplacian over position and scale. Unlike
+/// a threshold, touching spots keep separate maxima and faint spots are found as well.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScaleSpaceDetector {
+    pub method: ScaleSpaceMethod,
+    /// Range of the blob standard deviations in pixels, the radius is sqrt(2) sigma
+    pub min_sigma: f32,
+    pub max_sigma: f32,
+    /// Number of sigmas between both, spaced geometrically
+    pub scales: usize,
+    /// Minimal response for an image scaled to 0 to 1
+    pub threshold: f32,
+    /// Blobs covering more than this fraction of their area with a stronger blob are dropped
+    pub max_overlap: f32,
+}
+
+impl Default for ScaleSpaceDetector {
+    fn default() -> Self {
+        ScaleSpaceDetector {
+            method: ScaleSpaceMethod::LaplacianOfGaussian,
+            min_sigma: 2.0,
+            max_sigma: 30.0,
+            scales: 10,
+            threshold: 0.02,
+            max_overlap: 0.5,
+        }
+    }
+}
+
+impl BlobDetector for ScaleSpaceDetector {
+    /// Ids are ordered by decreasing response, starting at 1
+    fn detect(&self, image: &GrayImage) -> HashMap<u32, Blob> {
+        let plane = Plane::from_image(image);
+        let sigmas = self.sigmas();
+        let responses: Vec<Plane> = match self.method {
+            ScaleSpaceMethod::LaplacianOfGaussian => sigmas
+                .iter()
+                .map(|sigma| plane.blurred(*sigma).laplacian(-sigma * sigma))
+                .collect(),
+            ScaleSpaceMethod::DifferenceOfGaussian => {
+                // One more level above the largest sigma for the last difference
+                let ratio = sigmas.get(1).map_or(1.6, |s| s / sigmas[0]);
+                let blurred: Vec<Plane> = sigmas
+                    .iter()
+                    .chain(std::iter::once(&(sigmas[sigmas.len() - 1] * ratio)))
+                    .map(|sigma| plane.blurred(*sigma))
+                    .collect();
+                blurred
+                    .windows(2)
+                    .map(|pair| pair[0].difference(&pair[1], 1.0 / (ratio - 1.0)))
+                    .collect()
+            }
+        };
+
+        let mut candidates = find_maxima(&responses, &sigmas, self.threshold);
+        candidates.sort_by(|a, b| b.1.total_cmp(&a.1));
+        let mut kept: Vec<(Circle, f32)> = vec![];
+        for candidate in candidates {
+            if kept
+                .iter()
+                .all(|(circle, _)| overlap(&candidate.0, circle) <= self.max_overlap)
+            {
+                kept.push(candidate);
+            }
+        }
+        kept.into_iter()
+            .enumerate()
+            .map(|(i, (circle, response))| {
+                let blob = Blob {
+                    circle,
+                    response,
+                    descriptors: BlobDescriptors::within_circle(image, &circle),
+                };
+                (i as u32 + 1, blob)
+            })
+            .collect()
+    }
+}
+
+impl ScaleSpaceDetector {
+    fn sigmas(&self) -> Vec<f32> {
+        let scales = self.scales.max(2);
+        let ratio = (self.max_sigma / self.min_sigma).max(1.0);
+        (0..scales)
+            .map(|i| self.min_sigma * ratio.powf(i as f32 / (scales - 1) as f32))
+            .collect()
+    }
+}
+
+/// Maxima over their 3 x 3 x 3 neighbourhood in position and scale, refined to sub pixels,
+/// with their response
+fn find_maxima(responses: &[Plane], sigmas: &[f32], threshold: f32) -> Vec<(Circle, f32)> {
+    let mut maxima = vec![];
+    for (s, plane) in responses.iter().enumerate() {
+        let neighbours = &responses[s.saturating_sub(1)..(s + 2).min(responses.len())];
+        for y in 1..plane.height.saturating_sub(1) {
+            for x in 1..plane.width.saturating_sub(1) {
+                let value = plane.get(x, y);
+                if value < threshold {
+                    continue;
+                }
+                let is_maximum = neighbours.iter().all(|other| {
+                    (y - 1..=y + 1).all(|ny| (x - 1..=x + 1).all(|nx| other.get(nx, ny) <= value))
+                });
+                if !is_maximum {
+                    continue;
+                }
+
+                let offset = |before: f32, after: f32| {
+                    let curvature = before - 2.0 * value + after;
+                    if curvature < 0.0 {
+                        (0.5 * (before - after) / curvature).clamp(-0.5, 0.5)
+                    } else {
+                        0.0
+                    }
+                };
+                let dx = offset(plane.get(x - 1, y), plane.get(x + 1, y));
+                let dy = offset(plane.get(x, y - 1), plane.get(x, y + 1));
+                let circle = Circle::new(
+                    x as f32 + dx,
+                    y as f32 + dy,
+                    sigmas[s] * std::f32::consts::SQRT_2,
+                );
+                maxima.push((circle, value));
+            }
+        }
+    }
+    maxima
+}
+
+/// Fraction of the smaller circle covered by the other
+fn overlap(a: &Circle, b: &Circle) -> f32 {
+    let d = (a.center - b.center).norm();
+    let (r1, r2) = (a.radius, b.radius);
+    let smaller = r1.min(r2);
+    if d >= r1 + r2 {
+        return 0.0;
+    }
+    if d <= (r1 - r2).abs() {
+        return 1.0;
+    }
+    let lens = |r: f32, other: f32| {
+        let angle = ((d * d + r * r - other * other) / (2.0 * d * r)).clamp(-1.0, 1.0);
+        r * r * angle.acos()
+    };
+    let triangle = 0.5
+        * ((-d + r1 + r2) * (d + r1 - r2) * (d - r1 + r2) * (d + r1 + r2))
+            .max(0.0)
+            .sqrt();
+    (lens(r1, r2) + lens(r2, r1) - triangle) / (std::f32::consts::PI * smaller * smaller)
+}
+
+/// Single channel float image with its border repeated for filtering
+struct Plane {
+    width: usize,
+    height: usize,
+    values: Vec<f32>,
+}
+
+impl Plane {
+    fn from_image(image: &GrayImage) -> Self {
+        Plane {
+            width: image.width() as usize,
+            height: image.height() as usize,
+            values: image.pixels().map(|p| p[0] as f32 / 255.0).collect(),
+        }
+    }
+
+    fn get(&self, x: usize, y: usize) -> f32 {
+        self.values[y * self.width + x]
+    }
+
+    fn clamped(&self, x: isize, y: isize) -> f32 {
+        let x = x.clamp(0, self.width as isize - 1) as usize;
+        let y = y.clamp(0, self.height as isize - 1) as usize;
+        self.get(x, y)
+    }
+
+    fn map<F>(&self, value: F) -> Plane
+    where
+        F: Fn(isize, isize) -> f32,
+    {
+        let values = (0..self.height as isize)
+            .flat_map(|y| (0..self.width as isize).map(move |x| (x, y)))
+            .map(|(x, y)| value(x, y))
+            .collect();
+        Plane {
+            width: self.width,
+            height: self.height,
+            values,
+        }
+    }
+
+    /// Separable Gaussian blur
+    fn blurred(&self, sigma: f32) -> Plane {
+        let radius = (KERNEL_EXTENT * sigma).ceil() as isize;
+        let weights: Vec<f32> = (-radius..=radius)
+            .map(|i| (-(i * i) as f32 / (2.0 * sigma * sigma)).exp())
+            .collect();
+        let total: f32 = weights.iter().sum();
+        let kernel: Vec<(isize, f32)> = (-radius..=radius)
+            .zip(weights.iter().map(|w| w / total))
+            .collect();
+
+        let horizontal =
+            self.map(|x, y| kernel.iter().map(|(i, w)| w * self.clamped(x + i, y)).sum());
+        horizontal.map(|x, y| {
+            kernel
+                .iter()
+                .map(|(i, w)| w * horizontal.clamped(x, y + i))
+                .sum()
+        })
+    }
+
+    fn laplacian(&self, factor: f32) -> Plane {
+        self.map(|x, y| {
+            let neighbours = self.clamped(x - 1, y)
+                + self.clamped(x + 1, y)
+                + self.clamped(x, y - 1)
+                + self.clamped(x, y + 1);
+            factor * (neighbours - 4.0 * self.clamped(x, y))
+        })
+    }
+
+    fn difference(&self, other: &Plane, factor: f32) -> Plane {
+        self.map(|x, y| factor * (self.clamped(x, y) - other.clamped(x, y)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::scale_space_detector::overlap;
+    use crate::test_util::gaussian_spots;
+    use crate::{
+        detect_blobs, BlobDetectionParams, BlobDetector, ScaleSpaceDetector, ScaleSpaceMethod,
+        ThresholdDetector,
+    };
+    use image::GrayImage;
+    use nalgebra::{distance, Point2};
+    use tlc_common::Circle;
+
+    /// Spots as (x, y, sigma, amplitude)
+    const SPOTS: [(f32, f32, f32, f32); 3] = [
+        (40.0, 50.0, 6.0, 200.0),
+        (62.0, 50.0, 6.0, 200.0),
+        (130.0, 45.0, 5.0, 50.0),
+    ];
+
+    /// Two touching spots and a faint one on a background removed plate
+    fn plate() -> GrayImage {
+        gaussian_spots(170, 100, &SPOTS)
+    }
+
+    fn assert_finds_spots(detector: ScaleSpaceDetector) {
+        let when = detector.detect(&plate());
+
+        assert_eq!(when.len(), SPOTS.len(), "{:?}", when);
+        for (x, y, sigma, _) in SPOTS.iter() {
+            let spot = Point2::new(*x, *y);
+            let blob = when
+                .values()
+                .min_by(|a, b| {
+                    distance(&a.circle.center, &spot).total_cmp(&distance(&b.circle.center, &spot))
+                })
+                .unwrap();
+            assert!(distance(&blob.circle.center, &spot) < 1.0, "{:?}", blob);
+            let radius = sigma * std::f32::consts::SQRT_2;
+            assert!(
+                (blob.circle.radius - radius).abs() < radius * 0.25,
+                "{:?}",
+                blob
+            );
+        }
+        // The faint spot answers weakest and gets the last id
+        assert!(when[&3].circle.center.x > 100.0);
+    }
+
+    #[test]
+    fn test_laplacian_of_gaussian_separates_touching_spots() {
+        assert_finds_spots(ScaleSpaceDetector {
+            min_sigma: 3.0,
+            max_sigma: 12.0,
+            ..ScaleSpaceDetector::default()
+        });
+    }
+
+    #[test]
+    fn test_difference_of_gaussian_separates_touching_spots() {
+        assert_finds_spots(ScaleSpaceDetector {
+            method: ScaleSpaceMethod::DifferenceOfGaussian,
+            min_sigma: 3.0,
+            max_sigma: 12.0,
+            scales: 12,
+            ..ScaleSpaceDetector::default()
+        });
+    }
+
+    #[test]
+    fn test_threshold_detector_matches_detect_blobs() {
+        let given = plate();
+
+        let when = ThresholdDetector::default().detect(&given);
+
+        let circles = detect_blobs(&given, &BlobDetectionParams::default());
+        assert_eq!(when.len(), circles.len());
+        assert!(when.iter().all(|(key, blob)| circles[key] == blob.circle));
+    }
+
+    #[test]
+    fn test_empty_plate_has_no_blobs() {
+        let given = GrayImage::new(60, 40);
+
+        let when = ScaleSpaceDetector::default().detect(&given);
+
+        assert!(when.is_empty());
+    }
+
+    #[test]
+    fn test_circle_overlap() {
+        let a = Circle::new(0.0, 0.0, 2.0);
+
+        assert_eq!(overlap(&a, &Circle::new(5.0, 0.0, 2.0)), 0.0);
+        assert_eq!(overlap(&a, &Circle::new(0.5, 0.0, 1.0)), 1.0);
+        let half = overlap(&a, &Circle::new(2.0, 0.0, 2.0));
+        assert!((half - 0.391).abs() < 1e-3, "{}", half);
+    }
+}
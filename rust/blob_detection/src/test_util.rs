@@ -0,0 +1,16 @@
+use image::{GrayImage, Luma};
+
+/// A background removed plate with the sum of Gaussian spots given as
+/// (x, y, sigma, amplitude), clamped to 8 bit
+pub(crate) fn gaussian_spots(width: u32, height: u32, spots: &[(f32, f32, f32, f32)]) -> GrayImage {
+    GrayImage::from_fn(width, height, |x, y| {
+        let value: f32 = spots
+            .iter()
+            .map(|(cx, cy, sigma, amplitude)| {
+                let d2 = (x as f32 - cx).powi(2) + (y as f32 - cy).powi(2);
+                amplitude * (-d2 / (2.0 * sigma * sigma)).exp()
+            })
+            .sum();
+        Luma([value.min(255.0) as u8])
+    })
+}
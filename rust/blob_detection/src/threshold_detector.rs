@@ -0,0 +1,294 @@
+use image::{GrayImage, ImageBuffer, Luma};
+use imageproc::region_labelling::connected_components;
+use itertools::Itertools;
+use nalgebra::Point2;
+use std::collections::HashMap;
+use tlc_common::{Circle, Quad, StatsImage};
+
+use crate::watershed::split_regions;
+use crate::{Blob, BlobDescriptors, BlobDetectionParams, BlobDetector, ThresholdMethod};
+
+/// Thresholds the plate and labels the connected components
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ThresholdDetector {
+    pub params: BlobDetectionParams,
+}
+
+impl ThresholdDetector {
+    pub fn new(params: BlobDetectionParams) -> Self {
+        ThresholdDetector { params }
+    }
+}
+
+impl BlobDetector for ThresholdDetector {
+    /// The response is the mean intensity of the component from 0 to 1, or 0 for blobs
+    /// proposed by the layout prior
+    fn detect(&self, image: &GrayImage) -> HashMap<u32, Blob> {
+        let blobs = detect_components(image, &self.params);
+        match &self.params.layout {
+            Some(layout) => layout.apply(image, blobs),
+            None => blobs,
+        }
+    }
+}
+
+fn detect_components(image: &GrayImage, params: &BlobDetectionParams) -> HashMap<u32, Blob> {
+    let (width, height) = image.dimensions();
+
+    let mut regions = get_labeled_regions(image, params);
+    if let Some(seeds) = params.watershed {
+        // Maxima closer than half the smallest spot belong to one spot
+        let min_distance = (width.max(height) as f32 * params.min_size / 2.0) as u32;
+        regions = split_regions(&regions, image, seeds, min_distance);
+    }
+    let grouped = regions
+        .enumerate_pixels()
+        .filter(|(_, _, p)| p[0] != 0)
+        .map(|(x, y, p)| (p[0], (x, y)))
+        .into_group_map();
+
+    let added_intensity: HashMap<u32, Vec<(u32, u32, Luma<u8>)>> = grouped
+        .iter()
+        .map(|(key, coords)| {
+            let coords_val: Vec<(u32, u32, Luma<u8>)> = coords
+                .iter()
+                .map(|(x, y)| {
+                    let p = image.get_pixel(*x, *y);
+                    (*x, *y, *p)
+                })
+                .collect();
+
+            (*key, coords_val)
+        })
+        .collect();
+
+    let center: HashMap<u32, (f64, f64)> = added_intensity
+        .iter()
+        .map(|(key, cv)| {
+            let center = cv
+                .iter()
+                .map(|(x, y, p)| (x * p[0] as u32, y * p[0] as u32))
+                .fold((0u64, 0u64), |sum, x| {
+                    (sum.0 + x.0 as u64, sum.1 + x.1 as u64)
+                });
+            let sum = cv
+                .iter()
+                .map(|(_x, _y, p)| p[0])
+                .fold(0u64, |sum, x| sum + x as u64);
+
+            (
+                *key,
+                (center.0 as f64 / sum as f64, center.1 as f64 / sum as f64),
+            )
+        })
+        .collect();
+
+    let aspect_ratio_tolerance = params.aspect_ratio_tolerance;
+    let size_min = width.max(height) as f32 * params.min_size;
+    let size_max = width.max(height) as f32 * params.max_size;
+    let bounding_box: HashMap<u32, Quad> = added_intensity
+        .iter()
+        .map(|(key, cv)| {
+            let points: Vec<Point2<f32>> = cv
+                .iter()
+                .map(|(x, y, _p)| Point2::new(*x as f32, *y as f32))
+                .collect();
+
+            let left =
+                points
+                    .iter()
+                    .map(|p| p.x)
+                    .fold(width as f32, |min, x| if x <= min { x } else { min });
+
+            let right = points
+                .iter()
+                .map(|p| p.x)
+                .fold(0f32, |max, x| if x >= max { x } else { max });
+
+            let top =
+                points
+                    .iter()
+                    .map(|p| p.y)
+                    .fold(height as f32, |min, y| if y <= min { y } else { min });
+
+            let bottom = points
+                .iter()
+                .map(|p| p.y)
+                .fold(0f32, |max, y| if y >= max { y } else { max });
+            let top_left = Point2::new(left, top);
+            let top_right = Point2::new(right, top);
+            let bottom_right = Point2::new(right, bottom);
+            let bottom_left = Point2::new(left, bottom);
+
+            (
+                *key,
+                Quad {
+                    top_left,
+                    top_right,
+                    bottom_right,
+                    bottom_left,
+                },
+            )
+        })
+        .filter(|(_key, bbox)| {
+            let bbac = bbox.aspect_ratio();
+
+            bbac >= 1.0 - aspect_ratio_tolerance && bbac <= 1.0 + aspect_ratio_tolerance
+        })
+        .filter(|(_key, bbox)| {
+            let (bbw, bbh) = bbox.dimensions();
+            (bbw >= size_min && bbw <= size_max) && (bbh >= size_min && bbh <= size_max)
+        })
+        .collect();
+
+    let center_radius: HashMap<u32, Circle> = bounding_box
+        .iter()
+        .map(|(key, bbox)| (key, (bbox, center[key])))
+        .map(|(key, (bbox, center))| {
+            let cx = Point2::new(center.0 as f32, center.1 as f32);
+            let tl_dist = (bbox.top_left - cx).norm().abs() as f64;
+            let tr_dist = (bbox.top_right - cx).norm().abs() as f64;
+            let br_dist = (bbox.bottom_right - cx).norm().abs() as f64;
+            let bl_dist = (bbox.bottom_left - cx).norm().abs() as f64;
+
+            (
+                *key,
+                Circle::new(
+                    center.0 as f32,
+                    center.1 as f32,
+                    tl_dist.min(tr_dist).min(br_dist).min(bl_dist) as f32,
+                ),
+            )
+        })
+        .collect();
+
+    center_radius
+        .into_iter()
+        .map(|(key, circle)| {
+            let pixels = &grouped[&key];
+            let descriptors = BlobDescriptors::measure(image, pixels, &circle);
+            let response = descriptors.intensity_mean / 255.0;
+            (
+                key,
+                Blob {
+                    circle,
+                    response,
+                    descriptors,
+                },
+            )
+        })
+        .collect()
+}
+
+fn get_labeled_regions(
+    image: &GrayImage,
+    params: &BlobDetectionParams,
+) -> ImageBuffer<Luma<u32>, Vec<u32>> {
+    let (width, height) = image.dimensions();
+    let max_dim = width.max(height);
+
+    let thresholded = match params.threshold {
+        ThresholdMethod::Mean => imageproc::contrast::threshold(image, image.mean()[0]),
+        ThresholdMethod::Otsu => {
+            imageproc::contrast::threshold(image, imageproc::contrast::otsu_level(image))
+        }
+        ThresholdMethod::Adaptive {
+            block_radius,
+            offset,
+        } => {
+            let radius = ((max_dim as f32 * block_radius) as u32).max(1);
+            let local_mean = imageproc::filter::box_filter(image, radius, radius);
+            GrayImage::from_fn(width, height, |x, y| {
+                let level = local_mean.get_pixel(x, y)[0].saturating_add(offset);
+                Luma([if image.get_pixel(x, y)[0] > level {
+                    255
+                } else {
+                    0
+                }])
+            })
+        }
+    };
+    let opened = imageproc::morphology::open(
+        &thresholded,
+        imageproc::distance_transform::Norm::LInf,
+        (max_dim as f32 * params.opening_radius) as u8,
+    );
+    //opened.save("thresholded.jpg").unwrap();
+
+    let background_color = image.min();
+
+    connected_components(&opened, params.connectivity, background_color)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{BlobDetectionParams, BlobDetector, ThresholdDetector, ThresholdMethod};
+    use image::{GrayImage, Luma};
+
+    /// Four micro spots on a plate of 800 x 400 pixels with a background gradient
+    fn micro_spots(gradient: f32) -> GrayImage {
+        GrayImage::from_fn(800, 400, |x, y| {
+            let value: f32 = (0..4)
+                .map(|i| {
+                    let cx = 160.0 + i as f32 * 160.0;
+                    let d2 = (x as f32 - cx).powi(2) + (y as f32 - 200.0).powi(2);
+                    150.0 * (-d2 / 4.5).exp()
+                })
+                .sum();
+            Luma([(value + gradient * x as f32).min(255.0) as u8])
+        })
+    }
+
+    #[test]
+    fn test_micro_spots_need_smaller_sizes() {
+        let given = micro_spots(0.0);
+        let micro = BlobDetectionParams::builder()
+            .size(0.005, 0.05)
+            .opening_radius(0.0025)
+            .build()
+            .unwrap();
+
+        assert!(ThresholdDetector::default().detect(&given).is_empty());
+        for threshold in [
+            ThresholdMethod::Mean,
+            ThresholdMethod::from_name("adaptive").unwrap(),
+        ] {
+            let when =
+                ThresholdDetector::new(BlobDetectionParams { threshold, ..micro }).detect(&given);
+
+            assert_eq!(when.len(), 4, "{:?} {:?}", threshold, when);
+        }
+    }
+
+    #[test]
+    fn test_adaptive_threshold_handles_uneven_background() {
+        let given = micro_spots(0.1);
+        let params = BlobDetectionParams::builder()
+            .size(0.005, 0.05)
+            .opening_radius(0.0025)
+            .threshold(ThresholdMethod::from_name("adaptive").unwrap())
+            .build()
+            .unwrap();
+
+        let when = ThresholdDetector::new(params).detect(&given);
+
+        assert_eq!(when.len(), 4, "{:?}", when);
+    }
+
+    #[test]
+    fn test_otsu_threshold_finds_spots() {
+        let given = GrayImage::from_fn(300, 150, |x, y| {
+            let d2 = |cx: f32| (x as f32 - cx).powi(2) + (y as f32 - 75.0).powi(2);
+            let value = 200.0 * (-d2(80.0) / 128.0).exp() + 120.0 * (-d2(220.0) / 128.0).exp();
+            Luma([value as u8])
+        });
+        let params = BlobDetectionParams::builder()
+            .threshold(ThresholdMethod::Otsu)
+            .build()
+            .unwrap();
+
+        let when = ThresholdDetector::new(params).detect(&given);
+
+        assert_eq!(when.len(), 2, "{:?}", when);
+    }
+}
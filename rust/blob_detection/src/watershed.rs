@@ -0,0 +1,199 @@
+use image::{GrayImage, ImageBuffer, Luma};
+use imageproc::distance_transform::euclidean_squared_distance_transform;
+use imageproc::region_labelling::{connected_components, Connectivity};
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+
+type LabelImage = ImageBuffer<Luma<u32>, Vec<u32>>;
+
+/// Where the watershed floods merged components from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatershedSeeds {
+    /// Centres of the component shape, for spots of even intensity
+    DistanceTransform,
+    /// Brightest points of the component, for spots with a dense core
+    IntensityMaxima,
+}
+
+/// Splits each labeled region along the valleys between its seeds. Seeds are maxima of the
+/// elevation within `min_distance` pixels, so closer maxima count as one spot.
+pub(crate) fn split_regions(
+    regions: &LabelImage,
+    image: &GrayImage,
+    seeds: WatershedSeeds,
+    min_distance: u32,
+) -> LabelImage {
+    let (width, height) = regions.dimensions();
+    let elevation: Vec<f64> = match seeds {
+        WatershedSeeds::DistanceTransform => {
+            let background = GrayImage::from_fn(width, height, |x, y| {
+                Luma([(regions.get_pixel(x, y)[0] == 0) as u8])
+            });
+            euclidean_squared_distance_transform(&background)
+                .pixels()
+                .map(|p| p[0].sqrt())
+                .collect()
+        }
+        WatershedSeeds::IntensityMaxima => {
+            let smoothed = imageproc::filter::gaussian_blur_f32(image, 1.5);
+            smoothed.pixels().map(|p| p[0] as f64).collect()
+        }
+    };
+
+    let index = |x: u32, y: u32| (y * width + x) as usize;
+    let radius = min_distance.max(1) as i64;
+    let seed_mask = GrayImage::from_fn(width, height, |x, y| {
+        let label = regions.get_pixel(x, y)[0];
+        if label == 0 {
+            return Luma([0]);
+        }
+        let value = elevation[index(x, y)];
+        let is_maximum = (-radius..=radius).all(|dy| {
+            (-radius..=radius).all(|dx| {
+                let (nx, ny) = (x as i64 + dx, y as i64 + dy);
+                if nx < 0 || ny < 0 || nx >= width as i64 || ny >= height as i64 {
+                    return true;
+                }
+                let (nx, ny) = (nx as u32, ny as u32);
+                regions.get_pixel(nx, ny)[0] != label || elevation[index(nx, ny)] <= value
+            })
+        });
+        Luma([is_maximum as u8])
+    });
+    // Plateaus of equal maxima form a single seed
+    let seed_labels = connected_components(&seed_mask, Connectivity::Eight, Luma([0]));
+
+    let mut split = LabelImage::new(width, height);
+    let mut queue = BinaryHeap::new();
+    let mut new_labels: HashMap<u32, u32> = HashMap::new();
+    for (x, y, seed) in seed_labels.enumerate_pixels() {
+        if seed[0] == 0 {
+            continue;
+        }
+        let next = new_labels.len() as u32 + 1;
+        let label = *new_labels.entry(seed[0]).or_insert(next);
+        split.put_pixel(x, y, Luma([label]));
+        queue.push(Flood {
+            elevation: elevation[index(x, y)],
+            x,
+            y,
+        });
+    }
+
+    // Flood from the highest elevation downwards, each pixel joins its first neighbour
+    while let Some(Flood { x, y, .. }) = queue.pop() {
+        let label = split.get_pixel(x, y)[0];
+        let region = regions.get_pixel(x, y)[0];
+        for (dx, dy) in [(-1i64, 0i64), (1, 0), (0, -1), (0, 1)] {
+            let (nx, ny) = (x as i64 + dx, y as i64 + dy);
+            if nx < 0 || ny < 0 || nx >= width as i64 || ny >= height as i64 {
+                continue;
+            }
+            let (nx, ny) = (nx as u32, ny as u32);
+            if regions.get_pixel(nx, ny)[0] != region || split.get_pixel(nx, ny)[0] != 0 {
+                continue;
+            }
+            split.put_pixel(nx, ny, Luma([label]));
+            queue.push(Flood {
+                elevation: elevation[index(nx, ny)],
+                x: nx,
+                y: ny,
+            });
+        }
+    }
+    split
+}
+
+struct Flood {
+    elevation: f64,
+    x: u32,
+    y: u32,
+}
+
+impl PartialEq for Flood {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Flood {}
+
+impl PartialOrd for Flood {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Flood {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.elevation
+            .total_cmp(&other.elevation)
+            .then_with(|| (other.y, other.x).cmp(&(self.y, self.x)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test_util::gaussian_spots;
+    use crate::{BlobDetectionParams, BlobDetector, ThresholdDetector, WatershedSeeds};
+    use nalgebra::{distance, Point2};
+
+    /// Overlapping Gaussian spots as (x, y, sigma, amplitude) on a plate of 200 x 120 pixels
+    const TOUCHING: [(f32, f32, f32, f32); 2] =
+        [(80.0, 60.0, 9.0, 180.0), (112.0, 60.0, 9.0, 180.0)];
+
+    fn assert_split(seeds: WatershedSeeds) {
+        let detector = ThresholdDetector::new(BlobDetectionParams {
+            watershed: Some(seeds),
+            ..BlobDetectionParams::default()
+        });
+
+        let when = detector.detect(&gaussian_spots(200, 120, &TOUCHING));
+
+        assert_eq!(when.len(), 2, "{:?}", when);
+        for (x, y, _, _) in TOUCHING.iter() {
+            let spot = Point2::new(*x, *y);
+            assert!(
+                when.values()
+                    .any(|blob| distance(&blob.circle.center, &spot) < 2.0),
+                "{:?}",
+                when
+            );
+        }
+    }
+
+    #[test]
+    fn test_merged_spots_are_rejected_without_watershed() {
+        let when = ThresholdDetector::default().detect(&gaussian_spots(200, 120, &TOUCHING));
+
+        assert!(when.is_empty(), "{:?}", when);
+    }
+
+    #[test]
+    fn test_splits_merged_spots_at_distance_maxima() {
+        assert_split(WatershedSeeds::DistanceTransform);
+    }
+
+    #[test]
+    fn test_splits_merged_spots_at_intensity_maxima() {
+        assert_split(WatershedSeeds::IntensityMaxima);
+    }
+
+    #[test]
+    fn test_single_spot_stays_whole() {
+        let given = gaussian_spots(200, 120, &[(100.0, 60.0, 8.0, 180.0)]);
+
+        for seeds in [
+            WatershedSeeds::DistanceTransform,
+            WatershedSeeds::IntensityMaxima,
+        ] {
+            let when = ThresholdDetector::new(BlobDetectionParams {
+                watershed: Some(seeds),
+                ..BlobDetectionParams::default()
+            })
+            .detect(&given);
+
+            assert_eq!(when.len(), 1, "{:?}", when);
+        }
+    }
+}
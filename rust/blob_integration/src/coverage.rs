@@ -0,0 +1,102 @@
+use image::{ImageBuffer, Luma, Primitive};
+use std::collections::HashMap;
+use tlc_common::{Circle, Ellipse};
+
+/// Integrates spots with each pixel weighted by its area inside the spot, so the integral
+/// hardly changes when a spot moves by a fraction of a pixel. Unlike `integrate_spots` the
+/// values are not rescaled or cut off, which suits background removed and linear images.
+pub fn integrate_spots_subpixel<T>(
+    image: &ImageBuffer<Luma<T>, Vec<T>>,
+    spots: &HashMap<u32, Ellipse>,
+) -> HashMap<u32, f64>
+where
+    T: Primitive + Into<f64>,
+{
+    let (width, height) = image.dimensions();
+    spots
+        .iter()
+        .map(|(key, spot)| {
+            // All pixels touching the spot have their centre within a pixel of its outline
+            let reach = spot.radius_x.max(spot.radius_y) + 1.0;
+            let integral: f64 = Circle::new(spot.center.x, spot.center.y, reach)
+                .pixels(width, height)
+                .map(|(x, y)| {
+                    let value: f64 = image.get_pixel(x, y)[0].into();
+                    value * spot.coverage(x, y) as f64
+                })
+                .sum();
+            (*key, integral)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{integrate_spots, integrate_spots_subpixel};
+    use image::{GrayImage, Luma};
+    use std::collections::HashMap;
+    use tlc_common::{Circle, Ellipse, HDRGrayImage};
+
+    fn spread(values: &[f64]) -> f64 {
+        let max = values.iter().cloned().fold(f64::MIN, f64::max);
+        let min = values.iter().cloned().fold(f64::MAX, f64::min);
+        (max - min) / max
+    }
+
+    #[test]
+    fn test_area_of_flat_image_is_translation_invariant() {
+        let given = HDRGrayImage::from_pixel(60, 60, Luma([1.0]));
+        let shifts: Vec<f32> = (0..10).map(|i| i as f32 * 0.1).collect();
+
+        for (radius_x, radius_y, angle) in [(2.5, 2.5, 0.0), (6.0, 3.0, 0.6)] {
+            let areas: Vec<f64> = shifts
+                .iter()
+                .map(|shift| {
+                    let spot =
+                        Ellipse::new(30.0 + shift, 29.0 + shift / 2.0, radius_x, radius_y, angle);
+                    integrate_spots_subpixel(&given, &vec![(1, spot)].into_iter().collect())[&1]
+                })
+                .collect();
+
+            let expected = std::f64::consts::PI * radius_x as f64 * radius_y as f64;
+            for area in areas {
+                assert!(
+                    (area - expected).abs() / expected < 0.01,
+                    "{} {}",
+                    area,
+                    expected
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_small_spot_integral_is_translation_invariant() {
+        let shifts: Vec<f32> = (0..10).map(|i| i as f32 * 0.1).collect();
+        let (subpixel, whole_pixels): (Vec<f64>, Vec<f64>) = shifts
+            .iter()
+            .map(|shift| {
+                let (cx, cy) = (40.0 + shift, 40.0 + shift);
+                let image = GrayImage::from_fn(80, 80, |x, y| {
+                    let d2 = (x as f32 - cx).powi(2) + (y as f32 - cy).powi(2);
+                    Luma([(200.0 * (-d2 / 8.0).exp()).round() as u8])
+                });
+                let circle = Circle::new(cx, cy, 4.0);
+                let blobs: HashMap<u32, Circle> = vec![(1, circle)].into_iter().collect();
+                let spots: HashMap<u32, Ellipse> =
+                    vec![(1, Ellipse::from(circle))].into_iter().collect();
+                (
+                    integrate_spots_subpixel(&image, &spots)[&1],
+                    integrate_spots(&image, &blobs, 1.0)[&1] as f64,
+                )
+            })
+            .unzip();
+
+        assert!(spread(&subpixel) < 0.01, "{:?}", subpixel);
+        assert!(
+            spread(&whole_pixels) > spread(&subpixel),
+            "{:?}",
+            whole_pixels
+        );
+    }
+}
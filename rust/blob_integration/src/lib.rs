@@ -1,66 +1,162 @@
-use image::{GenericImageView, GrayImage};
+pub use coverage::integrate_spots_subpixel;
+pub use purity::{analyse_purity, LanePurity, LaneSpot};
+
+mod coverage;
+mod purity;
+
+use image::{GrayImage, ImageBuffer, Luma, Primitive};
 use nalgebra::Point2;
 use std::collections::HashMap;
-use tlc_common::{Circle, Quad};
+use tlc_common::{clip_span, Circle, HDRGrayImage, Quad};
 
 pub fn integrate_spots(
     image: &GrayImage,
     blobs: &HashMap<u32, Circle>,
     cut_off_percentage: f32,
 ) -> HashMap<u32, u64> {
+    integrate_spots_clipped(image, blobs, cut_off_percentage)
+        .into_iter()
+        .map(|(key, integral)| (key, integral.value))
+        .collect()
+}
+
+/// Integral of a spot together with how much of it was cut off by the image border
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpotIntegral {
+    pub value: u64,
+    /// Fraction of the spot circle outside of the image, from 0 to 1
+    pub outside: f32,
+}
+
+/// Like `integrate_spots`, but reports the part of each spot beyond the image border, where
+/// the integral misses intensity
+pub fn integrate_spots_clipped(
+    image: &GrayImage,
+    blobs: &HashMap<u32, Circle>,
+    cut_off_percentage: f32,
+) -> HashMap<u32, SpotIntegral> {
     let (min_val, max_val) = find_scaling(image, blobs);
+    let range = (max_val as f32 - min_val as f32).max(1.0);
 
     blobs
         .iter()
         // scale the image first
         .map(|(key, circle)| {
-            let quad = circle.to_quad();
-            let (qw, qh) = quad.dimensions();
-            let (iw, ih) = image.dimensions();
-            let mut scaled_img: Vec<u32> = image
-                .view(
-                    (quad.top_left.x as u32).clamp(0, qw as u32 - iw),
-                    (quad.top_right.y as u32).clamp(0, qh as u32 - ih),
-                    qw as u32,
-                    qh as u32,
-                )
-                .pixels()
-                .map(|(_, _, p)| p[0])
+            let mut scaled_img: Vec<u32> = pixels_in(image, &circle.to_quad())
                 // Scale the image
-                .map(|x| (x as f32 - min_val as f32) / (max_val as f32 - min_val as f32) * 255f32)
+                .map(|x| (x as f32 - min_val as f32) / range * 255f32)
                 // Make sure that it is a int so it is sortable
                 .map(|x| x as u32)
                 .collect();
             // Sort in descending order
             scaled_img.sort_by(|a, b| b.cmp(a));
-            (*key, scaled_img)
+            (*key, circle, scaled_img)
         })
         // Then integrated the top x percent values
-        .map(|(key, sorted_values)| {
+        .map(|(key, circle, sorted_values)| {
             let num_values = sorted_values.len();
             let cutoff_idx = (num_values as f32 * cut_off_percentage) as usize;
 
-            let integrated = sorted_values[..cutoff_idx]
+            let integrated = sorted_values[..cutoff_idx.min(num_values)]
                 .iter()
                 .fold(0f64, |sum, &x| sum + x as f64);
 
-            (key, integrated as u64)
+            let integral = SpotIntegral {
+                value: integrated as u64,
+                outside: fraction_outside(image.dimensions(), circle),
+            };
+            (key, integral)
+        })
+        .collect()
+}
+
+/// Share of the pixel centres inside `circle` that lie beyond the image
+pub fn fraction_outside((width, height): (u32, u32), circle: &Circle) -> f32 {
+    // Moved by whole pixels to lie within the first quadrant, the circle keeps its pixels
+    let (columns, rows) = circle.to_quad().pixel_spans();
+    let whole = Circle::new(
+        circle.center.x - columns.0 as f32,
+        circle.center.y - rows.0 as f32,
+        circle.radius,
+    );
+    let total = whole.pixels(u32::MAX, u32::MAX).count();
+    if total == 0 {
+        return 0.0;
+    }
+    1.0 - circle.pixels(width, height).count() as f32 / total as f32
+}
+
+/// Integrates spots of a linear image fused from several exposures. The values are scaled
+/// like in `integrate_spots`, but without rounding to 8 bit.
+pub fn integrate_spots_hdr(
+    image: &HDRGrayImage,
+    blobs: &HashMap<u32, Circle>,
+    cut_off_percentage: f32,
+) -> HashMap<u32, u64> {
+    let (width, height) = image.dimensions();
+    let bounding_box = find_bounding_box_from_blobs(width, height, blobs);
+    let (min_val, max_val) = pixels_in(image, &bounding_box)
+        .fold((f64::MAX, f64::MIN), |(min, max), value| {
+            (min.min(value), max.max(value))
+        });
+    let range = (max_val - min_val).max(f64::EPSILON);
+
+    blobs
+        .iter()
+        .map(|(key, circle)| {
+            let mut values: Vec<f64> = pixels_in(image, &circle.to_quad())
+                .map(|x| (x - min_val) / range * 255f64)
+                .collect();
+            values.sort_by(|a, b| b.total_cmp(a));
+
+            let cutoff_idx = (values.len() as f32 * cut_off_percentage) as usize;
+            let integrated: f64 = values[..cutoff_idx.min(values.len())].iter().sum();
+            (*key, integrated as u64)
         })
         .collect()
 }
 
+/// Values inside the axis aligned `quad`, clipped to the image
+fn pixels_in<'a, T>(
+    image: &'a ImageBuffer<Luma<T>, Vec<T>>,
+    quad: &Quad,
+) -> impl Iterator<Item = T> + 'a
+where
+    T: Primitive + 'a,
+{
+    let (width, height) = image.dimensions();
+    let (columns, rows) = quad.pixel_spans();
+    let columns = clip_span(columns, width);
+    clip_span(rows, height)
+        .flat_map(move |y| columns.clone().map(move |x| image.get_pixel(x, y)[0]))
+}
+
+/// The strip containing all blobs, which may reach beyond the image. Without blobs it is the
+/// whole image.
 pub fn find_bounding_box_from_blobs(width: u32, height: u32, blobs: &HashMap<u32, Circle>) -> Quad {
+    let (right, bottom) = (
+        width.saturating_sub(1) as f32,
+        height.saturating_sub(1) as f32,
+    );
+    if blobs.is_empty() {
+        return Quad {
+            top_left: Point2::new(0f32, 0f32),
+            top_right: Point2::new(right, 0f32),
+            bottom_right: Point2::new(right, bottom),
+            bottom_left: Point2::new(0f32, bottom),
+        };
+    }
     // Start with the respective maximum and minimum values
     let initial_quad = Quad {
-        top_left: Point2::new((width - 1) as f32, (height - 1) as f32),
-        top_right: Point2::new(0f32, (height - 1) as f32),
+        top_left: Point2::new(right, bottom),
+        top_right: Point2::new(0f32, bottom),
         bottom_right: Point2::new(0f32, 0f32),
-        bottom_left: Point2::new((width - 1) as f32, 0f32),
+        bottom_left: Point2::new(right, 0f32),
     };
     // Find the strip containing all blobs
     blobs
-        .iter()
-        .map(|(_, circle)| circle.to_quad())
+        .values()
+        .map(|circle| circle.to_quad())
         .fold(initial_quad, |cur_best, candidate| {
             let top_left = Point2::new(
                 // X should get smaller
@@ -132,24 +228,134 @@ pub fn find_scaling(image: &GrayImage, blobs: &HashMap<u32, Circle>) -> (u8, u8)
     let (width, height) = image.dimensions();
 
     let bounding_box = find_bounding_box_from_blobs(width, height, blobs);
-    let (bw, bh) = bounding_box.dimensions();
 
-    let img_strip = image.view(
-        bounding_box.top_left.x as u32,
-        bounding_box.top_right.y as u32,
-        bw as u32,
-        bh as u32,
-    );
+    pixels_in(image, &bounding_box).fold((255u8, 0u8), |min_max, candidate| {
+        let (min, max) = min_max;
+        // If the min is larger than the candidate replace it
+        let n_min = if min > candidate { candidate } else { min };
+        // If the max is smaller than the candidate replace it
+        let n_max = if max < candidate { candidate } else { max };
+        (n_min, n_max)
+    })
+}
 
-    img_strip
-        .pixels()
-        .map(|(_, _, p)| p[0])
-        .fold((255u8, 0u8), |min_max, candidate| {
-            let (min, max) = min_max;
-            // If the min is larger than the candidate replace it
-            let n_min = if min > candidate { candidate } else { min };
-            // If the max is smaller than the candidate replace it
-            let n_max = if max < candidate { candidate } else { max };
-            (n_min, n_max)
-        })
+#[cfg(test)]
+mod test {
+    use crate::{
+        find_bounding_box_from_blobs, integrate_spots, integrate_spots_clipped, integrate_spots_hdr,
+    };
+    use image::{GrayImage, Luma};
+    use std::collections::HashMap;
+    use tlc_common::{Circle, HDRGrayImage};
+
+    #[test]
+    fn test_hdr_integrals_keep_ratio_beyond_8_bit() {
+        // Two flat spots of 200 and 800, which a single 8 bit photo could not tell apart
+        let given = HDRGrayImage::from_fn(100, 40, |x, y| {
+            let inside = |cx: i32| (x as i32 - cx).abs() < 8 && (y as i32 - 20).abs() < 8;
+            Luma([if inside(25) {
+                200.0
+            } else if inside(75) {
+                800.0
+            } else {
+                0.0
+            }])
+        });
+        let blobs: HashMap<u32, Circle> = vec![
+            (0, Circle::new(25.0, 20.0, 10.0)),
+            (1, Circle::new(75.0, 20.0, 10.0)),
+        ]
+        .into_iter()
+        .collect();
+
+        let when = integrate_spots_hdr(&given, &blobs, 1.0);
+
+        let ratio = when[&1] as f64 / when[&0] as f64;
+        assert!((ratio - 4.0).abs() < 0.01, "{}", ratio);
+        assert_eq!(integrate_spots_hdr(&given, &blobs, 1.5), when);
+    }
+
+    /// A flat disk of radius 10 at `center` on a plate of 100 x 100 pixels
+    fn disk(center: (f32, f32)) -> (GrayImage, HashMap<u32, Circle>) {
+        let circle = Circle::new(center.0, center.1, 10.0);
+        let image = GrayImage::from_fn(100, 100, |x, y| {
+            let (dx, dy) = (x as f32 - circle.center.x, y as f32 - circle.center.y);
+            Luma([if dx * dx + dy * dy <= 100.0 { 200 } else { 0 }])
+        });
+        (image, vec![(1, circle)].into_iter().collect())
+    }
+
+    #[test]
+    fn test_spots_on_each_border_are_clipped() {
+        let (image, blobs) = disk((50.0, 50.0));
+        let full = integrate_spots_clipped(&image, &blobs, 1.0)[&1];
+        assert_eq!(full.outside, 0.0);
+
+        // Centres on the border and half a radius inside, which lies half a pixel beyond
+        let borders = [
+            ((0.0, 50.0), 0.5),
+            ((99.0, 50.0), 0.5),
+            ((50.0, 0.0), 0.5),
+            ((50.0, 99.0), 0.5),
+            ((4.5, 50.0), 5.0),
+            ((94.5, 50.0), 5.0),
+            ((50.0, 4.5), 5.0),
+            ((50.0, 94.5), 5.0),
+        ];
+        for (center, distance) in borders {
+            let (image, blobs) = disk(center);
+
+            let when = integrate_spots_clipped(&image, &blobs, 1.0)[&1];
+
+            // Area of the circular segment beyond the border
+            let (r, d) = (10f32, distance);
+            let segment = r * r * (d / r).acos() - d * (r * r - d * d).sqrt();
+            let expected = segment / (std::f32::consts::PI * r * r);
+            assert!(
+                (when.outside - expected).abs() < 0.01,
+                "{:?} {:?} {}",
+                center,
+                when,
+                expected
+            );
+            let kept = when.value as f32 / full.value as f32;
+            assert!(
+                (kept - (1.0 - when.outside)).abs() < 0.05,
+                "{:?} {:?}",
+                center,
+                when
+            );
+        }
+    }
+
+    #[test]
+    fn test_spot_touching_border_from_inside_is_whole() {
+        let (image, blobs) = disk((50.0, 50.0));
+        let full = integrate_spots(&image, &blobs, 1.0)[&1];
+
+        for center in [(10.0, 50.0), (89.0, 50.0), (50.0, 10.0), (50.0, 89.0)] {
+            let (image, blobs) = disk(center);
+
+            let when = integrate_spots_clipped(&image, &blobs, 1.0)[&1];
+
+            assert_eq!(when.outside, 0.0, "{:?}", center);
+            assert_eq!(when.value, full, "{:?}", center);
+        }
+    }
+
+    #[test]
+    fn test_empty_inputs() {
+        let given = GrayImage::new(100, 100);
+
+        assert!(integrate_spots(&given, &HashMap::new(), 0.5).is_empty());
+        let bounding_box = find_bounding_box_from_blobs(100, 100, &HashMap::new());
+        assert_eq!(bounding_box.dimensions(), (99.0, 99.0));
+
+        let outside: HashMap<u32, Circle> = vec![(1, Circle::new(-30.0, 50.0, 10.0))]
+            .into_iter()
+            .collect();
+        let when = integrate_spots_clipped(&given, &outside, 0.5);
+        assert_eq!(when[&1].value, 0);
+        assert_eq!(when[&1].outside, 1.0);
+    }
 }
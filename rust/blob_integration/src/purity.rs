@@ -0,0 +1,194 @@
+use image::GrayImage;
+use std::collections::HashMap;
+use tlc_common::Circle;
+
+/// A spot of a lane with its intensity relative to the main spot of the lane
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LaneSpot {
+    pub id: u32,
+    pub circle: Circle,
+    /// Integrated intensity in percent of the main spot
+    pub relative_intensity: f32,
+    /// A secondary spot above the impurity threshold
+    pub impurity: bool,
+}
+
+/// The spots of one lane, the strongest is taken to be the compound itself
+#[derive(Debug, Clone, PartialEq)]
+pub struct LanePurity {
+    pub main: LaneSpot,
+    /// Further spots, from the top of the plate down
+    pub secondary: Vec<LaneSpot>,
+}
+
+impl LanePurity {
+    /// Share of the main spot in the integrated intensity of the whole lane in percent
+    pub fn purity(&self) -> f32 {
+        let total: f32 = 100.0
+            + self
+                .secondary
+                .iter()
+                .map(|spot| spot.relative_intensity)
+                .sum::<f32>();
+        100.0 * 100.0 / total
+    }
+
+    pub fn impurities(&self) -> impl Iterator<Item = &LaneSpot> {
+        self.secondary.iter().filter(|spot| spot.impurity)
+    }
+}
+
+/// Groups the blobs into lanes and compares every spot of a lane to its strongest one. Spots
+/// of more than `impurity_threshold` percent of the main spot are flagged as impurities.
+/// Lanes are returned from left to right.
+pub fn analyse_purity(
+    image: &GrayImage,
+    blobs: &HashMap<u32, Circle>,
+    impurity_threshold: f32,
+) -> Vec<LanePurity> {
+    group_lanes(blobs)
+        .into_iter()
+        .map(|lane| {
+            let intensities: Vec<(u32, Circle, f64)> = lane
+                .into_iter()
+                .map(|(id, circle)| (id, circle, intensity_in(image, &circle)))
+                .collect();
+            let (main_id, main_circle, main_intensity) = *intensities
+                .iter()
+                .max_by(|a, b| a.2.total_cmp(&b.2).then(b.0.cmp(&a.0)))
+                .unwrap();
+            let relative = |intensity: f64| {
+                if main_intensity > 0.0 {
+                    (intensity / main_intensity * 100.0) as f32
+                } else {
+                    0.0
+                }
+            };
+
+            let mut secondary: Vec<LaneSpot> = intensities
+                .iter()
+                .filter(|(id, _, _)| *id != main_id)
+                .map(|(id, circle, intensity)| {
+                    let relative_intensity = relative(*intensity);
+                    LaneSpot {
+                        id: *id,
+                        circle: *circle,
+                        relative_intensity,
+                        impurity: relative_intensity > impurity_threshold,
+                    }
+                })
+                .collect();
+            secondary.sort_by(|a, b| a.circle.center.y.total_cmp(&b.circle.center.y));
+
+            LanePurity {
+                main: LaneSpot {
+                    id: main_id,
+                    circle: main_circle,
+                    relative_intensity: 100.0,
+                    impurity: false,
+                },
+                secondary,
+            }
+        })
+        .collect()
+}
+
+/// Blobs sorted by x, a blob starts a new lane if it does not overlap the lane horizontally
+fn group_lanes(blobs: &HashMap<u32, Circle>) -> Vec<Vec<(u32, Circle)>> {
+    let mut sorted: Vec<(u32, Circle)> = blobs.iter().map(|(id, c)| (*id, *c)).collect();
+    sorted.sort_by(|a, b| a.1.center.x.total_cmp(&b.1.center.x).then(a.0.cmp(&b.0)));
+
+    let mut lanes: Vec<Vec<(u32, Circle)>> = vec![];
+    for (id, circle) in sorted {
+        let joins = lanes.last().is_some_and(|lane| {
+            let x = lane.iter().map(|(_, c)| c.center.x).sum::<f32>() / lane.len() as f32;
+            let width = lane
+                .iter()
+                .map(|(_, c)| c.radius)
+                .fold(circle.radius, f32::max);
+            circle.center.x - x <= width
+        });
+        match lanes.last_mut() {
+            Some(lane) if joins => lane.push((id, circle)),
+            _ => lanes.push(vec![(id, circle)]),
+        }
+    }
+    lanes
+}
+
+/// Sum of the background removed pixels inside the circle
+fn intensity_in(image: &GrayImage, circle: &Circle) -> f64 {
+    let (width, height) = image.dimensions();
+    circle
+        .pixels(width, height)
+        .map(|(x, y)| image.get_pixel(x, y)[0] as f64)
+        .sum()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::analyse_purity;
+    use image::{GrayImage, Luma};
+    use std::collections::HashMap;
+    use tlc_common::Circle;
+
+    /// Flat disks as (x, y, radius, value) on a background removed plate
+    fn plate(spots: &[(f32, f32, f32, u8)]) -> (GrayImage, HashMap<u32, Circle>) {
+        let image = GrayImage::from_fn(300, 200, |x, y| {
+            let inside = spots
+                .iter()
+                .find(|(cx, cy, r, _)| (x as f32 - cx).powi(2) + (y as f32 - cy).powi(2) <= r * r);
+            Luma([inside.map_or(0, |spot| spot.3)])
+        });
+        let blobs = spots
+            .iter()
+            .enumerate()
+            .map(|(i, (x, y, r, _))| (i as u32 + 1, Circle::new(*x, *y, *r)))
+            .collect();
+        (image, blobs)
+    }
+
+    #[test]
+    fn test_flags_secondary_spots_above_threshold() {
+        let (image, blobs) = plate(&[
+            (100.0, 100.0, 12.0, 200),
+            (102.0, 50.0, 6.0, 80),
+            (99.0, 150.0, 4.0, 10),
+            (200.0, 100.0, 12.0, 200),
+        ]);
+
+        let when = analyse_purity(&image, &blobs, 1.0);
+
+        assert_eq!(when.len(), 2);
+        let lane = &when[0];
+        assert_eq!(lane.main.id, 1);
+        assert_eq!(lane.secondary.len(), 2);
+        // About 80 / 200 * (6 / 12)^2
+        let above = &lane.secondary[0];
+        assert_eq!(above.id, 2);
+        assert!((above.relative_intensity - 10.0).abs() < 1.0, "{:?}", above);
+        assert!(above.impurity);
+        let below = &lane.secondary[1];
+        assert_eq!(below.id, 3);
+        assert!(below.relative_intensity < 1.0, "{:?}", below);
+        assert!(!below.impurity);
+        assert_eq!(lane.impurities().count(), 1);
+        assert!((lane.purity() - 90.5).abs() < 1.0, "{}", lane.purity());
+
+        assert_eq!(when[1].main.id, 4);
+        assert!(when[1].secondary.is_empty());
+        assert_eq!(when[1].purity(), 100.0);
+    }
+
+    #[test]
+    fn test_strongest_spot_is_main() {
+        let (image, blobs) = plate(&[(100.0, 60.0, 6.0, 100), (100.0, 120.0, 10.0, 150)]);
+
+        let when = analyse_purity(&image, &blobs, 5.0);
+
+        assert_eq!(when.len(), 1);
+        assert_eq!(when[0].main.id, 2);
+        assert_eq!(when[0].secondary[0].id, 1);
+        assert!(when[0].secondary[0].impurity);
+    }
+}
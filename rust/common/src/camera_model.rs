@@ -0,0 +1,292 @@
+use nalgebra::Point2;
+
+/// Iterations of the fixed point undistortion, enough for phone lens distortions
+const UNDISTORT_ITERATIONS: usize = 20;
+
+/// Brown–Conrady lens distortion with radial (k1, k2, k3) and tangential (p1, p2) coefficients
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Distortion {
+    pub k1: f32,
+    pub k2: f32,
+    pub k3: f32,
+    pub p1: f32,
+    pub p2: f32,
+}
+
+impl Distortion {
+    pub fn radial(k1: f32, k2: f32, k3: f32) -> Self {
+        Distortion {
+            k1,
+            k2,
+            k3,
+            ..Default::default()
+        }
+    }
+
+    /// Distorts normalized image coordinates, i.e. relative to the principal point in focal lengths
+    pub fn apply(&self, x: f32, y: f32) -> (f32, f32) {
+        let r2 = x * x + y * y;
+        let radial = 1.0 + r2 * (self.k1 + r2 * (self.k2 + r2 * self.k3));
+        (
+            x * radial + 2.0 * self.p1 * x * y + self.p2 * (r2 + 2.0 * x * x),
+            y * radial + self.p1 * (r2 + 2.0 * y * y) + 2.0 * self.p2 * x * y,
+        )
+    }
+}
+
+/// Pinhole camera with lens distortion for images of `width` x `height` pixels.
+/// Ideal pixel coordinates are those of the same camera without lens distortion.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CameraModel {
+    pub width: u32,
+    pub height: u32,
+    pub fx: f32,
+    pub fy: f32,
+    pub cx: f32,
+    pub cy: f32,
+    pub distortion: Distortion,
+}
+
+impl CameraModel {
+    pub fn new(
+        (width, height): (u32, u32),
+        (fx, fy): (f32, f32),
+        (cx, cy): (f32, f32),
+        distortion: Distortion,
+    ) -> Self {
+        CameraModel {
+            width,
+            height,
+            fx,
+            fy,
+            cx,
+            cy,
+            distortion,
+        }
+    }
+
+    /// Maps an ideal pixel position to where the lens shows it in the photo
+    pub fn distort(&self, point: &Point2<f32>) -> Point2<f32> {
+        let (x, y) = self
+            .distortion
+            .apply((point.x - self.cx) / self.fx, (point.y - self.cy) / self.fy);
+        Point2::new(x * self.fx + self.cx, y * self.fy + self.cy)
+    }
+
+    /// Maps a pixel position in the photo to its ideal position without lens distortion
+    pub fn undistort(&self, point: &Point2<f32>) -> Point2<f32> {
+        let (xd, yd) = ((point.x - self.cx) / self.fx, (point.y - self.cy) / self.fy);
+        let (mut x, mut y) = (xd, yd);
+        for _ in 0..UNDISTORT_ITERATIONS {
+            let (dx, dy) = self.distortion.apply(x, y);
+            x += xd - dx;
+            y += yd - dy;
+        }
+        Point2::new(x * self.fx + self.cx, y * self.fy + self.cy)
+    }
+
+    /// The same camera for a resized photo with the same aspect ratio
+    pub fn scaled_to(&self, width: u32, height: u32) -> Result<Self, String> {
+        let (sx, sy) = (
+            width as f32 / self.width as f32,
+            height as f32 / self.height as f32,
+        );
+        if (sx - sy).abs() > 0.01 * sx {
+            return Err(format!(
+                "A {}x{} camera model does not fit {}x{} images",
+                self.width, self.height, width, height
+            ));
+        }
+        // Pixel centres are at +0.5, so the principal point scales around the image origin edge
+        Ok(CameraModel {
+            width,
+            height,
+            fx: self.fx * sx,
+            fy: self.fy * sy,
+            cx: (self.cx + 0.5) * sx - 0.5,
+            cy: (self.cy + 0.5) * sy - 0.5,
+            distortion: self.distortion,
+        })
+    }
+
+    /// The camera for the photo rotated clockwise by a multiple of 90 degrees
+    pub fn rotated(&self, degrees: u32) -> Self {
+        (0..(degrees / 90) % 4).fold(*self, |camera, _| camera.rotated90())
+    }
+
+    fn rotated90(&self) -> Self {
+        // A clockwise rotation maps (x, y) to (height - 1 - y, x)
+        let distortion = Distortion {
+            p1: self.distortion.p2,
+            p2: -self.distortion.p1,
+            ..self.distortion
+        };
+        CameraModel {
+            width: self.height,
+            height: self.width,
+            fx: self.fy,
+            fy: self.fx,
+            cx: (self.height - 1) as f32 - self.cy,
+            cy: self.cx,
+            distortion,
+        }
+    }
+}
+
+/// Calibrated camera of a phone model, identified by `CaptureMetadata::device`
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceProfile {
+    pub device: String,
+    pub camera: CameraModel,
+}
+
+impl DeviceProfile {
+    /// Parses `device;width;height;fx;fy;cx;cy;k1;k2;p1;p2;k3`
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let fields: Vec<&str> = line.split(';').map(|field| field.trim()).collect();
+        if fields.len() != 12 || fields[0].is_empty() {
+            return Err(format!("Invalid device profile: {}", line));
+        }
+        let numbers = fields[1..]
+            .iter()
+            .map(|field| field.parse::<f32>())
+            .collect::<Result<Vec<f32>, _>>()
+            .map_err(|e| format!("Invalid device profile {}: {}", line, e))?;
+
+        Ok(DeviceProfile {
+            device: fields[0].to_string(),
+            camera: CameraModel::new(
+                (numbers[0] as u32, numbers[1] as u32),
+                (numbers[2], numbers[3]),
+                (numbers[4], numbers[5]),
+                Distortion {
+                    k1: numbers[6],
+                    k2: numbers[7],
+                    p1: numbers[8],
+                    p2: numbers[9],
+                    k3: numbers[10],
+                },
+            ),
+        })
+    }
+
+    /// The profile as a line for `DeviceProfiles::parse`
+    pub fn to_line(&self) -> String {
+        let c = &self.camera;
+        let d = &c.distortion;
+        format!(
+            "{};{};{};{};{};{};{};{};{};{};{};{}",
+            self.device, c.width, c.height, c.fx, c.fy, c.cx, c.cy, d.k1, d.k2, d.p1, d.p2, d.k3
+        )
+    }
+}
+
+/// A list of device profiles, one per line. Empty lines and lines starting with `#` are skipped.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DeviceProfiles {
+    pub profiles: Vec<DeviceProfile>,
+}
+
+impl DeviceProfiles {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let profiles = text
+            .lines()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(DeviceProfile::parse)
+            .collect::<Result<Vec<DeviceProfile>, String>>()?;
+        Ok(DeviceProfiles { profiles })
+    }
+
+    pub fn load(path: &str) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Reading the device profiles failed: {}", e))?;
+        DeviceProfiles::parse(&text)
+    }
+
+    /// The camera of `device`, ignoring case
+    pub fn find(&self, device: &str) -> Option<&CameraModel> {
+        self.profiles
+            .iter()
+            .find(|profile| profile.device.eq_ignore_ascii_case(device))
+            .map(|profile| &profile.camera)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{CameraModel, DeviceProfile, DeviceProfiles, Distortion};
+    use nalgebra::{distance, Point2};
+
+    fn phone_camera() -> CameraModel {
+        CameraModel::new(
+            (4000, 3000),
+            (3100.0, 3080.0),
+            (2010.0, 1490.0),
+            Distortion {
+                k1: 0.12,
+                k2: -0.25,
+                k3: 0.1,
+                p1: 0.001,
+                p2: -0.0015,
+            },
+        )
+    }
+
+    #[test]
+    fn test_undistort_inverts_distort() {
+        let given = phone_camera();
+
+        for &(x, y) in [
+            (0.0, 0.0),
+            (3999.0, 2999.0),
+            (150.0, 2800.0),
+            (2010.0, 10.0),
+        ]
+        .iter()
+        {
+            let point = Point2::new(x, y);
+            let when = given.undistort(&given.distort(&point));
+
+            assert!(distance(&when, &point) < 0.01, "{} {}", when, point);
+        }
+    }
+
+    #[test]
+    fn test_rotated_camera_distorts_rotated_points() {
+        let given = phone_camera();
+        let rotate = |p: &Point2<f32>| Point2::new(2999.0 - p.y, p.x);
+
+        let when = given.rotated(90);
+
+        let point = Point2::new(300.0, 700.0);
+        let then = rotate(&given.distort(&point));
+        assert!(distance(&when.distort(&rotate(&point)), &then) < 0.01);
+        assert_eq!(given.rotated(360), given);
+    }
+
+    #[test]
+    fn test_scaled_camera() {
+        let given = phone_camera();
+
+        let when = given.scaled_to(2000, 1500).unwrap();
+
+        assert_eq!((when.fx, when.cx), (1550.0, 1004.75));
+        assert!(given.scaled_to(3000, 3000).is_err());
+    }
+
+    #[test]
+    fn test_device_profiles() {
+        let profile = DeviceProfile {
+            device: "Google Pixel 6".to_string(),
+            camera: phone_camera(),
+        };
+        let given = format!("# device profiles\n\n{}\n", profile.to_line());
+
+        let when = DeviceProfiles::parse(&given).unwrap();
+
+        assert_eq!(when.find("google pixel 6"), Some(&profile.camera));
+        assert_eq!(when.find("Pixel 7"), None);
+        assert!(DeviceProfiles::parse("Pixel;1;2;3").is_err());
+    }
+}
@@ -0,0 +1,202 @@
+use image::DynamicImage;
+use rexif::{ExifData, ExifTag, TagValue};
+
+/// The orientation stored in the EXIF orientation tag (values 1 to 8)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Orientation {
+    #[default]
+    Normal,
+    MirrorHorizontal,
+    Rotate180,
+    MirrorVertical,
+    Transpose,
+    Rotate90,
+    Transverse,
+    Rotate270,
+}
+
+impl Orientation {
+    pub fn from_exif(value: u16) -> Self {
+        match value {
+            2 => Orientation::MirrorHorizontal,
+            3 => Orientation::Rotate180,
+            4 => Orientation::MirrorVertical,
+            5 => Orientation::Transpose,
+            6 => Orientation::Rotate90,
+            7 => Orientation::Transverse,
+            8 => Orientation::Rotate270,
+            _ => Orientation::Normal, // Unknown values are treated as upright
+        }
+    }
+
+    pub fn to_exif(self) -> u16 {
+        match self {
+            Orientation::Normal => 1,
+            Orientation::MirrorHorizontal => 2,
+            Orientation::Rotate180 => 3,
+            Orientation::MirrorVertical => 4,
+            Orientation::Transpose => 5,
+            Orientation::Rotate90 => 6,
+            Orientation::Transverse => 7,
+            Orientation::Rotate270 => 8,
+        }
+    }
+
+    /// Transforms an image as stored in the file to its upright representation
+    pub fn apply(self, image: DynamicImage) -> DynamicImage {
+        match self {
+            Orientation::Normal => image,
+            Orientation::MirrorHorizontal => image.fliph(),
+            Orientation::Rotate180 => image.rotate180(),
+            Orientation::MirrorVertical => image.flipv(),
+            // Mirrored along the main diagonal
+            Orientation::Transpose => image.rotate90().fliph(),
+            Orientation::Rotate90 => image.rotate90(),
+            // Mirrored along the anti diagonal
+            Orientation::Transverse => image.rotate270().fliph(),
+            Orientation::Rotate270 => image.rotate270(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WhiteBalance {
+    Auto,
+    Manual,
+}
+
+/// Capture settings parsed from the EXIF data of a photo
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CaptureMetadata {
+    /// Orientation as stored in the file. `read_image` already applied it to the image
+    pub orientation: Orientation,
+    /// Capture time in the EXIF format `YYYY:MM:DD HH:MM:SS`
+    pub timestamp: Option<String>,
+    pub make: Option<String>,
+    pub model: Option<String>,
+    /// Exposure time in seconds
+    pub exposure_time: Option<f64>,
+    pub iso: Option<u32>,
+    pub white_balance: Option<WhiteBalance>,
+    /// Focal length in millimeters
+    pub focal_length: Option<f64>,
+    /// All parsed tags as readable (name, value) pairs
+    pub entries: Vec<(String, String)>,
+}
+
+impl CaptureMetadata {
+    pub fn from_exif(exif: &ExifData) -> Self {
+        let mut metadata = CaptureMetadata::default();
+        let mut date_time = None;
+
+        for entry in &exif.entries {
+            match entry.tag {
+                ExifTag::Orientation => {
+                    if let Some(v) = entry.value.to_i64(0) {
+                        metadata.orientation = Orientation::from_exif(v as u16);
+                    }
+                }
+                ExifTag::DateTimeOriginal => metadata.timestamp = ascii_value(&entry.value),
+                ExifTag::DateTime => date_time = ascii_value(&entry.value),
+                ExifTag::Make => metadata.make = ascii_value(&entry.value),
+                ExifTag::Model => metadata.model = ascii_value(&entry.value),
+                ExifTag::ExposureTime => metadata.exposure_time = entry.value.to_f64(0),
+                ExifTag::ISOSpeedRatings => metadata.iso = entry.value.to_i64(0).map(|v| v as u32),
+                ExifTag::WhiteBalanceMode => {
+                    metadata.white_balance = match entry.value.to_i64(0) {
+                        Some(0) => Some(WhiteBalance::Auto),
+                        Some(1) => Some(WhiteBalance::Manual),
+                        _ => None,
+                    }
+                }
+                ExifTag::FocalLength => metadata.focal_length = entry.value.to_f64(0),
+                _ => {}
+            }
+
+            if entry.tag != ExifTag::UnknownToMe {
+                metadata
+                    .entries
+                    .push((entry.tag.to_string(), entry.value_more_readable.to_string()));
+            }
+        }
+
+        // Not every camera writes the original capture time
+        if metadata.timestamp.is_none() {
+            metadata.timestamp = date_time;
+        }
+
+        metadata
+    }
+
+    /// Manufacturer and model combined, e.g. for display or device profile lookups
+    pub fn device(&self) -> Option<String> {
+        match (&self.make, &self.model) {
+            (Some(make), Some(model)) if model.starts_with(make.as_str()) => Some(model.clone()),
+            (Some(make), Some(model)) => Some(format!("{} {}", make, model)),
+            (Some(make), None) => Some(make.clone()),
+            (None, Some(model)) => Some(model.clone()),
+            (None, None) => None,
+        }
+    }
+}
+
+fn ascii_value(value: &TagValue) -> Option<String> {
+    match value {
+        TagValue::Ascii(s) => {
+            let trimmed = s.trim_matches(char::from(0)).trim();
+            if trimmed.is_empty() {
+                None
+            } else {
+                Some(trimmed.to_string())
+            }
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::Orientation;
+    use image::{DynamicImage, GenericImageView, GrayImage};
+
+    // Upright 3x2 image:
+    // 0 1 2
+    // 3 4 5
+    fn upright() -> Vec<u8> {
+        vec![0, 1, 2, 3, 4, 5]
+    }
+
+    fn stored(width: u32, height: u32, data: Vec<u8>) -> DynamicImage {
+        DynamicImage::ImageLuma8(GrayImage::from_vec(width, height, data).unwrap())
+    }
+
+    #[test]
+    fn test_all_orientations_restore_upright() {
+        // How a camera would store the upright image for each orientation tag
+        let given = vec![
+            (1, stored(3, 2, vec![0, 1, 2, 3, 4, 5])),
+            (2, stored(3, 2, vec![2, 1, 0, 5, 4, 3])),
+            (3, stored(3, 2, vec![5, 4, 3, 2, 1, 0])),
+            (4, stored(3, 2, vec![3, 4, 5, 0, 1, 2])),
+            (5, stored(2, 3, vec![0, 3, 1, 4, 2, 5])),
+            (6, stored(2, 3, vec![2, 5, 1, 4, 0, 3])),
+            (7, stored(2, 3, vec![5, 2, 4, 1, 3, 0])),
+            (8, stored(2, 3, vec![3, 0, 4, 1, 5, 2])),
+        ];
+
+        for (tag, image) in given {
+            let when = Orientation::from_exif(tag).apply(image);
+
+            assert_eq!(when.dimensions(), (3, 2), "Orientation {}", tag);
+            assert_eq!(when.to_luma8().into_vec(), upright(), "Orientation {}", tag);
+        }
+    }
+
+    #[test]
+    fn test_exif_value_roundtrip() {
+        for value in 1..=8 {
+            assert_eq!(Orientation::from_exif(value).to_exif(), value);
+        }
+        assert_eq!(Orientation::from_exif(0), Orientation::Normal);
+    }
+}
@@ -0,0 +1,386 @@
+use crate::Quad;
+use image::{DynamicImage, Rgb, RgbImage};
+use nalgebra::{Matrix3, Point2, Vector3};
+
+/// Patches whose reference channels differ less than this fraction are neutral gray
+const GRAY_TOLERANCE: f32 = 0.05;
+
+/// Entries of the lookup table from linear values back to sRGB
+const ENCODE_STEPS: usize = 4096;
+
+/// A patch of a color card in the photo with its known sRGB color
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ColorPatch {
+    pub center: Point2<f32>,
+    /// Half size of the square which is averaged around the center
+    pub radius: f32,
+    pub reference: [f32; 3],
+}
+
+impl ColorPatch {
+    fn is_gray(&self) -> bool {
+        let r = self.reference;
+        let (min, max) = (r[0].min(r[1]).min(r[2]), r[0].max(r[1]).max(r[2]));
+        max - min <= GRAY_TOLERANCE * max.max(1.0)
+    }
+}
+
+/// The patches of a gray or color card in the photo
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColorTarget {
+    pub patches: Vec<ColorPatch>,
+}
+
+impl ColorTarget {
+    /// A card with `columns` x `rows` equally sized patches inside `card`, listed row by row
+    /// in `references`. Only the inner third of each patch is sampled to avoid its borders.
+    pub fn grid(
+        card: &Quad,
+        columns: usize,
+        rows: usize,
+        references: &[[f32; 3]],
+    ) -> Result<Self, String> {
+        if columns == 0 || rows == 0 || references.len() != columns * rows {
+            return Err(format!(
+                "{} reference colors do not fit a {}x{} card",
+                references.len(),
+                columns,
+                rows
+            ));
+        }
+
+        let (width, height) = card.dimensions();
+        let radius = (width / columns as f32).min(height / rows as f32) / 6.0;
+        let patches = references
+            .iter()
+            .enumerate()
+            .map(|(i, reference)| {
+                let u = ((i % columns) as f32 + 0.5) / columns as f32;
+                let v = ((i / columns) as f32 + 0.5) / rows as f32;
+                let top = card.top_left + (card.top_right - card.top_left) * u;
+                let bottom = card.bottom_left + (card.bottom_right - card.bottom_left) * u;
+                ColorPatch {
+                    center: top + (bottom - top) * v,
+                    radius,
+                    reference: *reference,
+                }
+            })
+            .collect();
+        Ok(ColorTarget { patches })
+    }
+
+    /// Mean color of each patch in the photo
+    pub fn measure(&self, image: &RgbImage) -> Result<Vec<[f32; 3]>, String> {
+        let (width, height) = image.dimensions();
+        self.patches
+            .iter()
+            .map(|patch| {
+                let (x0, x1) = (patch.center.x - patch.radius, patch.center.x + patch.radius);
+                let (y0, y1) = (patch.center.y - patch.radius, patch.center.y + patch.radius);
+                if x0 < 0.0 || y0 < 0.0 || x1 >= width as f32 || y1 >= height as f32 {
+                    return Err(format!(
+                        "The patch at {} is outside the photo",
+                        patch.center
+                    ));
+                }
+
+                let mut sum = [0f32; 3];
+                let mut count = 0;
+                for y in y0.ceil() as u32..=y1.floor() as u32 {
+                    for x in x0.ceil() as u32..=x1.floor() as u32 {
+                        let p = image.get_pixel(x, y);
+                        (0..3).for_each(|c| sum[c] += p[c] as f32);
+                        count += 1;
+                    }
+                }
+                Ok(sum.map(|s| s / count.max(1) as f32))
+            })
+            .collect()
+    }
+}
+
+/// Maps photo colors to the colors of a reference card. The tone curve linearizes the
+/// camera values, the matrix corrects white balance and color cross talk in linear space
+/// and the result is encoded as sRGB again.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColorCalibration {
+    pub matrix: Matrix3<f32>,
+    /// Linear value for each 8 bit camera value
+    pub tone_curve: Vec<f32>,
+    /// RMS deviation of the corrected patches from their references in 8 bit sRGB values
+    pub residual: f32,
+}
+
+impl ColorCalibration {
+    /// Fits the calibration to the patches of a card in the photo
+    pub fn from_target(image: &RgbImage, target: &ColorTarget) -> Result<Self, String> {
+        let measured = target.measure(image)?;
+        ColorCalibration::fit(&target.patches, &measured)
+    }
+
+    /// Fits the tone curve to the gray patches and the matrix to all patches. With fewer
+    /// than three colored patches only the white balance, i.e. a diagonal matrix, is fitted.
+    pub fn fit(patches: &[ColorPatch], measured: &[[f32; 3]]) -> Result<Self, String> {
+        if patches.is_empty() || patches.len() != measured.len() {
+            return Err("Each patch needs a measured color".to_string());
+        }
+
+        let grays: Vec<(f32, f32)> = patches
+            .iter()
+            .zip(measured.iter())
+            .filter(|(patch, _)| patch.is_gray())
+            .map(|(patch, m)| (mean(m), srgb_to_linear(mean(&patch.reference) / 255.0)))
+            .collect();
+        let tone_curve = fit_tone_curve(&grays);
+
+        let linearize =
+            |c: &[f32; 3]| Vector3::from_iterator(c.iter().map(|v| lookup(&tone_curve, *v)));
+        let sources: Vec<Vector3<f32>> = measured.iter().map(linearize).collect();
+        let targets: Vec<Vector3<f32>> = patches
+            .iter()
+            .map(|p| Vector3::from_iterator(p.reference.iter().map(|v| srgb_to_linear(v / 255.0))))
+            .collect();
+
+        let colored = patches.iter().filter(|p| !p.is_gray()).count();
+        let matrix = if colored >= 3 {
+            fit_matrix(&sources, &targets)
+        } else {
+            fit_diagonal(&sources, &targets)
+        }
+        .ok_or_else(|| "The color card patches are degenerate".to_string())?;
+
+        let mut calibration = ColorCalibration {
+            matrix,
+            tone_curve,
+            residual: 0.0,
+        };
+        let squared: f32 = patches
+            .iter()
+            .zip(measured.iter())
+            .map(|(patch, m)| {
+                let corrected = calibration.correct(m);
+                (0..3)
+                    .map(|c| (corrected[c] - patch.reference[c]).powi(2))
+                    .sum::<f32>()
+            })
+            .sum();
+        calibration.residual = (squared / (3 * patches.len()) as f32).sqrt();
+        Ok(calibration)
+    }
+
+    /// The corrected sRGB color of a camera color, both in 0 to 255
+    pub fn correct(&self, color: &[f32; 3]) -> [f32; 3] {
+        let linear = Vector3::from_iterator(color.iter().map(|v| lookup(&self.tone_curve, *v)));
+        let corrected = self.matrix * linear;
+        [0, 1, 2].map(|c| linear_to_srgb(corrected[c].clamp(0.0, 1.0)) * 255.0)
+    }
+
+    /// Corrects every pixel, before the image is converted to luma or split into channels
+    pub fn apply(&self, image: &DynamicImage) -> DynamicImage {
+        let encode: Vec<u8> = (0..ENCODE_STEPS)
+            .map(|i| {
+                let linear = i as f32 / (ENCODE_STEPS - 1) as f32;
+                (linear_to_srgb(linear) * 255.0).round() as u8
+            })
+            .collect();
+        let rgb = image.to_rgb8();
+        let corrected = RgbImage::from_fn(rgb.width(), rgb.height(), |x, y| {
+            let p = rgb.get_pixel(x, y);
+            let linear = Vector3::new(
+                self.tone_curve[p[0] as usize],
+                self.tone_curve[p[1] as usize],
+                self.tone_curve[p[2] as usize],
+            );
+            let mapped = self.matrix * linear;
+            Rgb([0, 1, 2].map(|c| {
+                let index = mapped[c].clamp(0.0, 1.0) * (ENCODE_STEPS - 1) as f32;
+                encode[index.round() as usize]
+            }))
+        });
+        DynamicImage::ImageRgb8(corrected)
+    }
+}
+
+fn mean(color: &[f32; 3]) -> f32 {
+    color.iter().sum::<f32>() / 3.0
+}
+
+pub(crate) fn srgb_to_linear(value: f32) -> f32 {
+    if value >= 0.04045 {
+        ((value + 0.055) / 1.055).powf(2.4)
+    } else {
+        value / 12.92
+    }
+}
+
+pub(crate) fn linear_to_srgb(value: f32) -> f32 {
+    if value >= 0.0031308 {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    } else {
+        value * 12.92
+    }
+}
+
+/// Linearly interpolated tone curve value of a camera value in 0 to 255
+fn lookup(tone_curve: &[f32], value: f32) -> f32 {
+    let value = value.clamp(0.0, 255.0);
+    let low = value.floor() as usize;
+    let high = (low + 1).min(255);
+    let t = value - low as f32;
+    tone_curve[low] * (1.0 - t) + tone_curve[high] * t
+}
+
+/// Power law `linear = gain * (value / 255)^gamma` through the gray patches, fitted in the
+/// log domain. Without two distinct gray levels the camera is assumed to encode sRGB.
+fn fit_tone_curve(grays: &[(f32, f32)]) -> Vec<f32> {
+    let points: Vec<(f32, f32)> = grays
+        .iter()
+        .filter(|(camera, linear)| *camera > 2.0 && *linear > 1e-3)
+        .map(|(camera, linear)| ((camera / 255.0).ln(), linear.ln()))
+        .collect();
+
+    let count = points.len() as f32;
+    let (mean_x, mean_y) = (
+        points.iter().map(|p| p.0).sum::<f32>() / count,
+        points.iter().map(|p| p.1).sum::<f32>() / count,
+    );
+    let sxx: f32 = points.iter().map(|p| (p.0 - mean_x).powi(2)).sum();
+    let sxy: f32 = points.iter().map(|p| (p.0 - mean_x) * (p.1 - mean_y)).sum();
+
+    if points.len() < 2 || sxx < 1e-4 {
+        return (0..256).map(|v| srgb_to_linear(v as f32 / 255.0)).collect();
+    }
+    let gamma = sxy / sxx;
+    let gain = (mean_y - gamma * mean_x).exp();
+    (0..256)
+        .map(|v| gain * (v as f32 / 255.0).powf(gamma))
+        .collect()
+}
+
+/// Least squares matrix with `targets ≈ matrix * sources`
+fn fit_matrix(sources: &[Vector3<f32>], targets: &[Vector3<f32>]) -> Option<Matrix3<f32>> {
+    let mut sst = Matrix3::<f32>::zeros();
+    let mut tst = Matrix3::<f32>::zeros();
+    for (s, t) in sources.iter().zip(targets.iter()) {
+        sst += s * s.transpose();
+        tst += t * s.transpose();
+    }
+    Some(tst * sst.try_inverse()?)
+}
+
+/// Per channel gains, i.e. a white balance
+fn fit_diagonal(sources: &[Vector3<f32>], targets: &[Vector3<f32>]) -> Option<Matrix3<f32>> {
+    let mut gains = Vector3::zeros();
+    for c in 0..3 {
+        let ss: f32 = sources.iter().map(|s| s[c] * s[c]).sum();
+        if ss <= f32::EPSILON {
+            return None;
+        }
+        gains[c] = sources
+            .iter()
+            .zip(targets.iter())
+            .map(|(s, t)| s[c] * t[c])
+            .sum::<f32>()
+            / ss;
+    }
+    Some(Matrix3::from_diagonal(&gains))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::color_calibration::{linear_to_srgb, srgb_to_linear};
+    use crate::{ColorCalibration, ColorTarget, Quad};
+    use image::{DynamicImage, Rgb, RgbImage};
+    use nalgebra::{Matrix3, Point2, Vector3};
+
+    fn card_colors() -> Vec<[f32; 3]> {
+        vec![
+            [243.0, 243.0, 242.0],
+            [200.0, 200.0, 200.0],
+            [160.0, 160.0, 160.0],
+            [122.0, 122.0, 121.0],
+            [85.0, 85.0, 85.0],
+            [52.0, 52.0, 52.0],
+            [175.0, 54.0, 60.0],
+            [70.0, 148.0, 73.0],
+            [56.0, 61.0, 150.0],
+            [231.0, 199.0, 31.0],
+            [187.0, 86.0, 149.0],
+            [8.0, 133.0, 161.0],
+        ]
+    }
+
+    /// A camera with a warm white balance, channel cross talk and a gamma of 2.0
+    fn camera(color: &[f32; 3]) -> [f32; 3] {
+        let linear = Vector3::from_iterator(color.iter().map(|v| srgb_to_linear(v / 255.0)));
+        let mixing = Matrix3::new(1.1, 0.05, 0.0, 0.03, 0.95, 0.02, 0.0, 0.06, 0.7);
+        let seen = mixing * linear;
+        [0, 1, 2].map(|c| seen[c].clamp(0.0, 1.0).powf(1.0 / 2.0) * 255.0)
+    }
+
+    fn card_photo(colors: &[[f32; 3]], columns: u32) -> (RgbImage, Quad) {
+        let rows = colors.len() as u32 / columns;
+        let photo = RgbImage::from_fn(columns * 40 + 20, rows * 40 + 20, |x, y| {
+            if x < 10 || y < 10 || x >= columns * 40 + 10 || y >= rows * 40 + 10 {
+                return Rgb([20, 20, 20]);
+            }
+            let index = ((y - 10) / 40 * columns + (x - 10) / 40) as usize;
+            Rgb(camera(&colors[index]).map(|v| v.round() as u8))
+        });
+        let quad = Quad {
+            top_left: Point2::new(9.5, 9.5),
+            top_right: Point2::new(columns as f32 * 40.0 + 9.5, 9.5),
+            bottom_right: Point2::new(columns as f32 * 40.0 + 9.5, rows as f32 * 40.0 + 9.5),
+            bottom_left: Point2::new(9.5, rows as f32 * 40.0 + 9.5),
+        };
+        (photo, quad)
+    }
+
+    #[test]
+    fn test_srgb_round_trip() {
+        for v in [0.0, 0.002, 0.1, 0.5, 1.0] {
+            assert!((srgb_to_linear(linear_to_srgb(v)) - v).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn test_calibrates_color_card() {
+        let colors = card_colors();
+        let (photo, card) = card_photo(&colors, 6);
+        let target = ColorTarget::grid(&card, 6, 2, &colors).unwrap();
+
+        let when = ColorCalibration::from_target(&photo, &target).unwrap();
+
+        assert!(when.residual < 4.0, "{}", when.residual);
+        let corrected = when.apply(&DynamicImage::ImageRgb8(photo)).to_rgb8();
+        // The red patch in the second row
+        let red = corrected.get_pixel(30, 70);
+        assert!((red[0] as f32 - 175.0).abs() < 6.0, "{:?}", red);
+        assert!((red[2] as f32 - 60.0).abs() < 6.0, "{:?}", red);
+    }
+
+    #[test]
+    fn test_gray_card_fits_white_balance() {
+        let grays: Vec<[f32; 3]> = card_colors()[..6].to_vec();
+        let (photo, card) = card_photo(&grays, 6);
+        let target = ColorTarget::grid(&card, 6, 1, &grays).unwrap();
+
+        let when = ColorCalibration::from_target(&photo, &target).unwrap();
+
+        assert_eq!(when.matrix[(0, 1)], 0.0);
+        let corrected = when.correct(&camera(&[122.0, 122.0, 122.0]));
+        let spread = corrected
+            .iter()
+            .fold(0f32, |m, v| m.max((v - corrected[0]).abs()));
+        assert!(spread < 4.0, "{:?}", corrected);
+    }
+
+    #[test]
+    fn test_invalid_targets() {
+        let card = Quad::from_simple_vec(vec![0, 0, 60, 0, 60, 20, 0, 20]);
+
+        assert!(ColorTarget::grid(&card, 3, 2, &card_colors()).is_err());
+        let target = ColorTarget::grid(&card, 6, 2, &card_colors()).unwrap();
+        assert!(target.measure(&RgbImage::new(30, 30)).is_err());
+    }
+}
@@ -0,0 +1,263 @@
+use crate::color_calibration::srgb_to_linear;
+use crate::{CaptureMetadata, HDRGrayImage};
+use image::imageops::FilterType;
+use image::{GrayImage, Luma};
+
+/// Values this close to 0 or 255 are clipped and carry no radiance information
+const CLIP_MARGIN: u8 = 3;
+/// ISO assumed for photos without one in their metadata
+const DEFAULT_ISO: f64 = 100.0;
+
+/// One photo of a bracketed series, warped to the same plate crop as the others
+#[derive(Debug, Clone, PartialEq)]
+pub struct Exposure {
+    pub image: GrayImage,
+    /// Relative amount of light collected, e.g. exposure time times ISO
+    pub exposure: f64,
+}
+
+impl Exposure {
+    pub fn new(image: GrayImage, exposure: f64) -> Self {
+        Exposure { image, exposure }
+    }
+
+    /// The exposure from the capture settings of the photo
+    pub fn from_metadata(image: GrayImage, metadata: &CaptureMetadata) -> Result<Self, String> {
+        let time = metadata
+            .exposure_time
+            .ok_or_else(|| "The photo has no exposure time".to_string())?;
+        let iso = metadata.iso.map_or(DEFAULT_ISO, |iso| iso as f64);
+        Ok(Exposure::new(image, time * iso / DEFAULT_ISO))
+    }
+}
+
+/// Merges bracketed photos into a linear image in the units of the first exposure, so
+/// values above 255 are brighter than the first photo could record. Each photo is aligned
+/// to the first by up to `max_shift` pixels first.
+pub fn fuse_exposures(exposures: &[Exposure], max_shift: u32) -> Result<HDRGrayImage, String> {
+    let reference = exposures
+        .first()
+        .ok_or_else(|| "There are no exposures to fuse".to_string())?;
+    let (width, height) = reference.image.dimensions();
+    if exposures
+        .iter()
+        .any(|e| e.image.dimensions() != (width, height))
+    {
+        return Err("The exposures differ in size".to_string());
+    }
+    if exposures.iter().any(|e| e.exposure <= 0.0) {
+        return Err("Exposures must be positive".to_string());
+    }
+
+    let aligned: Vec<(GrayImage, f64)> = exposures
+        .iter()
+        .map(|e| {
+            let (dx, dy) = align_exposures(reference, e, max_shift);
+            (shifted(&e.image, dx, dy), e.exposure / reference.exposure)
+        })
+        .collect();
+
+    let linear: Vec<f64> = (0..=255)
+        .map(|z| srgb_to_linear(z as f32 / 255.0) as f64 * 255.0)
+        .collect();
+    Ok(HDRGrayImage::from_fn(width, height, |x, y| {
+        let (mut sum, mut weights) = (0.0, 0.0);
+        // Fallback if every exposure is clipped here, the one closest to mid gray
+        let mut closest = (u8::MAX, 0.0);
+        for (image, relative) in aligned.iter() {
+            let z = image.get_pixel(x, y)[0];
+            let radiance = linear[z as usize] / relative;
+            let weight = hat_weight(z);
+            sum += weight * radiance;
+            weights += weight;
+
+            let distance = (z as i16 - 128).unsigned_abs() as u8;
+            if distance < closest.0 {
+                closest = (distance, radiance);
+            }
+        }
+        Luma([if weights > 0.0 {
+            sum / weights
+        } else {
+            closest.1
+        }])
+    }))
+}
+
+/// Trust in a value, highest at mid gray and zero for clipped values
+fn hat_weight(z: u8) -> f64 {
+    if z <= CLIP_MARGIN || z >= u8::MAX - CLIP_MARGIN {
+        0.0
+    } else {
+        z.min(u8::MAX - z) as f64
+    }
+}
+
+/// Offset of `other` against `reference` with `other(x + dx, y + dy) ≈ reference(x, y)`.
+/// Compares the log radiance of both, normalized by their exposures, from coarse to fine
+/// with a one pixel search per pyramid level. Clipped pixels are left out.
+pub fn align_exposures(reference: &Exposure, other: &Exposure, max_shift: u32) -> (i32, i32) {
+    if max_shift == 0 {
+        return (0, 0);
+    }
+    let levels = (32 - max_shift.leading_zeros()) as i32;
+    let (mut dx, mut dy) = (0i32, 0i32);
+    for level in (0..levels).rev() {
+        dx *= 2;
+        dy *= 2;
+        let (a, b) = (Radiance::new(reference, level), Radiance::new(other, level));
+        // The unchanged offset first, so it wins ties in flat regions
+        let mut best = (a.difference(&b, dx, dy), dx, dy);
+        for (sx, sy) in (-1..=1).flat_map(|sy| (-1..=1).map(move |sx| (sx, sy))) {
+            let error = a.difference(&b, dx + sx, dy + sy);
+            if error < best.0 {
+                best = (error, dx + sx, dy + sy);
+            }
+        }
+        dx = best.1;
+        dy = best.2;
+    }
+    let max_shift = max_shift as i32;
+    (
+        dx.clamp(-max_shift, max_shift),
+        dy.clamp(-max_shift, max_shift),
+    )
+}
+
+fn shifted(image: &GrayImage, dx: i32, dy: i32) -> GrayImage {
+    let (width, height) = image.dimensions();
+    GrayImage::from_fn(width, height, |x, y| {
+        *image.get_pixel(
+            (x as i32 + dx).clamp(0, width as i32 - 1) as u32,
+            (y as i32 + dy).clamp(0, height as i32 - 1) as u32,
+        )
+    })
+}
+
+/// Downscaled log radiance of an exposure, `None` where it is clipped
+struct Radiance {
+    width: i32,
+    height: i32,
+    values: Vec<Option<f32>>,
+}
+
+impl Radiance {
+    fn new(exposure: &Exposure, level: i32) -> Self {
+        let factor = 1 << level;
+        let (width, height) = (
+            (exposure.image.width() / factor).max(1),
+            (exposure.image.height() / factor).max(1),
+        );
+        let small = image::imageops::resize(&exposure.image, width, height, FilterType::Triangle);
+        let offset = exposure.exposure.ln() as f32;
+        Radiance {
+            width: width as i32,
+            height: height as i32,
+            values: small
+                .pixels()
+                .map(|p| match hat_weight(p[0]) > 0.0 {
+                    true => Some(srgb_to_linear(p[0] as f32 / 255.0).ln() - offset),
+                    false => None,
+                })
+                .collect(),
+        }
+    }
+
+    /// Mean absolute difference in the overlap with `other` moved by the offset
+    fn difference(&self, other: &Radiance, dx: i32, dy: i32) -> f64 {
+        let (mut sum, mut compared) = (0f64, 0usize);
+        for y in 0.max(-dy)..self.height.min(other.height - dy) {
+            for x in 0.max(-dx)..self.width.min(other.width - dx) {
+                let a = self.values[(y * self.width + x) as usize];
+                let b = other.values[((y + dy) * other.width + x + dx) as usize];
+                if let (Some(a), Some(b)) = (a, b) {
+                    sum += (a - b).abs() as f64;
+                    compared += 1;
+                }
+            }
+        }
+        if compared == 0 {
+            return f64::MAX;
+        }
+        sum / compared as f64
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::color_calibration::linear_to_srgb;
+    use crate::{align_exposures, fuse_exposures, Exposure, HDRGrayImage};
+    use image::{GrayImage, Luma};
+
+    /// Linear radiance of a plate with a faint and a dense spot, in units of the middle exposure
+    fn scene() -> HDRGrayImage {
+        HDRGrayImage::from_fn(160, 120, |x, y| {
+            let spot = |cx: f64, cy: f64| {
+                let d2 = (x as f64 - cx).powi(2) + (y as f64 - cy).powi(2);
+                (-d2 / (2.0 * 8.0 * 8.0)).exp()
+            };
+            let texture = 15.0 * ((x as f64 / 7.0).sin() * (y as f64 / 5.0).cos());
+            Luma([60.0 + texture + 8.0 * spot(50.0, 60.0) + 900.0 * spot(110.0, 60.0)])
+        })
+    }
+
+    fn photograph(scene: &HDRGrayImage, exposure: f64, (dx, dy): (i32, i32)) -> Exposure {
+        let (width, height) = scene.dimensions();
+        let image = GrayImage::from_fn(width, height, |x, y| {
+            let sx = (x as i32 - dx).clamp(0, width as i32 - 1) as u32;
+            let sy = (y as i32 - dy).clamp(0, height as i32 - 1) as u32;
+            let linear = (scene.get_pixel(sx, sy)[0] * exposure / 255.0).min(1.0);
+            Luma([(linear_to_srgb(linear as f32) * 255.0).round() as u8])
+        });
+        Exposure::new(image, exposure)
+    }
+
+    #[test]
+    fn test_fuses_to_linear_radiance() {
+        let given = scene();
+        let exposures = vec![
+            photograph(&given, 1.0, (0, 0)),
+            photograph(&given, 0.2, (0, 0)),
+            photograph(&given, 4.0, (0, 0)),
+        ];
+
+        let when = fuse_exposures(&exposures, 0).unwrap();
+
+        // The dense spot saturates in the first photo but not in the fusion
+        assert_eq!(exposures[0].image.get_pixel(110, 60)[0], 255);
+        for (x, y) in [(110, 60), (50, 60), (5, 5), (150, 100)] {
+            let (fused, truth) = (when.get_pixel(x, y)[0], given.get_pixel(x, y)[0]);
+            assert!((fused / truth - 1.0).abs() < 0.03, "{} {}", fused, truth);
+        }
+        // The faint spot keeps its contrast despite the quantization of single photos
+        let contrast =
+            |image: &HDRGrayImage| image.get_pixel(50, 60)[0] - image.get_pixel(50, 90)[0];
+        assert!((contrast(&when) - contrast(&given)).abs() < 1.0);
+    }
+
+    #[test]
+    fn test_aligns_shifted_exposures() {
+        let given = scene();
+        let reference = photograph(&given, 1.0, (0, 0));
+        let other = photograph(&given, 0.2, (3, -2));
+
+        let when = align_exposures(&reference, &other, 8);
+
+        assert_eq!(when, (3, -2));
+        let fused = fuse_exposures(&[reference, other], 8).unwrap();
+        let dense = fused.get_pixel(110, 60)[0];
+        assert!((dense / given.get_pixel(110, 60)[0] - 1.0).abs() < 0.05);
+    }
+
+    #[test]
+    fn test_rejects_mismatched_exposures() {
+        let given = vec![
+            Exposure::new(GrayImage::new(10, 10), 1.0),
+            Exposure::new(GrayImage::new(10, 12), 2.0),
+        ];
+
+        assert!(fuse_exposures(&given, 0).is_err());
+        assert!(fuse_exposures(&[], 0).is_err());
+        assert!(fuse_exposures(&given[..1], 0).is_ok());
+    }
+}
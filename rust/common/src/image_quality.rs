@@ -0,0 +1,335 @@
+use crate::StatsImage;
+use image::{DynamicImage, GrayImage, ImageBuffer, Luma};
+use imageproc::filter::filter3x3;
+use imageproc::gradients::{horizontal_sobel, vertical_sobel};
+use imageproc::region_labelling::{connected_components, Connectivity};
+use std::collections::HashMap;
+use std::fmt;
+
+type FloatImage = ImageBuffer<Luma<f32>, Vec<f32>>;
+
+/// Channel values at or above this are clipped highlights
+const OVEREXPOSED_LEVEL: u8 = 250;
+/// Luma values at or below this are clipped shadows
+const UNDEREXPOSED_LEVEL: u8 = 5;
+/// Glare is bright, nearly colorless and far brighter than the plate
+const GLARE_LEVEL: f32 = 230.0;
+const GLARE_CONTRAST: f32 = 40.0;
+const GLARE_MAX_CHROMA: u8 = 25;
+/// Largest fraction of the image a single reflection covers
+const GLARE_MAX_AREA: f32 = 0.02;
+/// Gradients below this magnitude are noise and left out of the motion blur estimate
+const MIN_GRADIENT: f32 = 20.0;
+
+/// Limits beyond which a photo should be retaken
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QualityThresholds {
+    /// Minimal variance of the Laplacian of the luma
+    pub min_sharpness: f32,
+    /// Maximal fraction of pixels with a clipped channel
+    pub max_overexposed: f32,
+    /// Maximal fraction of black pixels
+    pub max_underexposed: f32,
+    /// Maximal fraction of pixels in specular hot spots
+    pub max_glare: f32,
+    /// Maximal anisotropy of the gradients, 0 for no preferred direction and 1 for a streak
+    pub max_motion_blur: f32,
+}
+
+impl Default for QualityThresholds {
+    fn default() -> Self {
+        QualityThresholds {
+            min_sharpness: 15.0,
+            max_overexposed: 0.02,
+            max_underexposed: 0.05,
+            max_glare: 0.005,
+            max_motion_blur: 0.5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QualityWarning {
+    Blurry { sharpness: f32 },
+    Overexposed { fraction: f32 },
+    Underexposed { fraction: f32 },
+    Glare { fraction: f32 },
+    MotionBlur { anisotropy: f32 },
+}
+
+impl QualityWarning {
+    /// Stable name for the app to choose a message
+    pub fn code(&self) -> &'static str {
+        match self {
+            QualityWarning::Blurry { .. } => "blurry",
+            QualityWarning::Overexposed { .. } => "overexposed",
+            QualityWarning::Underexposed { .. } => "underexposed",
+            QualityWarning::Glare { .. } => "glare",
+            QualityWarning::MotionBlur { .. } => "motion_blur",
+        }
+    }
+}
+
+impl fmt::Display for QualityWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QualityWarning::Blurry { sharpness } => {
+                write!(f, "The photo is out of focus (sharpness {:.1})", sharpness)
+            }
+            QualityWarning::Overexposed { fraction } => {
+                write!(f, "{:.1}% of the plate is overexposed", fraction * 100.0)
+            }
+            QualityWarning::Underexposed { fraction } => {
+                write!(f, "{:.1}% of the plate is underexposed", fraction * 100.0)
+            }
+            QualityWarning::Glare { fraction } => {
+                write!(
+                    f,
+                    "{:.1}% of the plate is covered by glare",
+                    fraction * 100.0
+                )
+            }
+            QualityWarning::MotionBlur { anisotropy } => {
+                write!(f, "The photo is blurred by motion ({:.2})", anisotropy)
+            }
+        }
+    }
+}
+
+/// Quality scores of a plate crop and the warnings for the scores beyond the thresholds
+#[derive(Debug, Clone, PartialEq)]
+pub struct QualityReport {
+    pub sharpness: f32,
+    pub overexposed: f32,
+    pub underexposed: f32,
+    pub glare: f32,
+    pub motion_blur: f32,
+    pub warnings: Vec<QualityWarning>,
+}
+
+impl QualityReport {
+    pub fn assess(image: &DynamicImage, thresholds: &QualityThresholds) -> Self {
+        let rgb = image.to_rgb8();
+        let gray = image.to_luma8();
+        let pixels = gray.len().max(1) as f32;
+
+        let sharpness = laplacian_variance(&gray);
+        let overexposed = rgb
+            .pixels()
+            .filter(|p| p.0.iter().any(|c| *c >= OVEREXPOSED_LEVEL))
+            .count() as f32
+            / pixels;
+        let underexposed =
+            gray.pixels().filter(|p| p[0] <= UNDEREXPOSED_LEVEL).count() as f32 / pixels;
+        let glare = glare_fraction(image, &gray);
+        let motion_blur = gradient_anisotropy(&gray);
+
+        let mut warnings = vec![];
+        if sharpness < thresholds.min_sharpness {
+            warnings.push(QualityWarning::Blurry { sharpness });
+        }
+        if overexposed > thresholds.max_overexposed {
+            warnings.push(QualityWarning::Overexposed {
+                fraction: overexposed,
+            });
+        }
+        if underexposed > thresholds.max_underexposed {
+            warnings.push(QualityWarning::Underexposed {
+                fraction: underexposed,
+            });
+        }
+        if glare > thresholds.max_glare {
+            warnings.push(QualityWarning::Glare { fraction: glare });
+        }
+        if motion_blur > thresholds.max_motion_blur {
+            warnings.push(QualityWarning::MotionBlur {
+                anisotropy: motion_blur,
+            });
+        }
+
+        QualityReport {
+            sharpness,
+            overexposed,
+            underexposed,
+            glare,
+            motion_blur,
+            warnings,
+        }
+    }
+
+    pub fn is_acceptable(&self) -> bool {
+        self.warnings.is_empty()
+    }
+}
+
+/// Focus measure, edges of a sharp photo give a strong Laplacian response
+fn laplacian_variance(gray: &GrayImage) -> f32 {
+    let laplacian: FloatImage = filter3x3(
+        &gray.convert_to_f32(),
+        &[0.0, 1.0, 0.0, 1.0, -4.0, 1.0, 0.0, 1.0, 0.0],
+    );
+    let values = laplacian.as_raw();
+    let count = values.len().max(1) as f32;
+    let mean = values.iter().sum::<f32>() / count;
+    values.iter().map(|v| (v - mean).powi(2)).sum::<f32>() / count
+}
+
+/// Fraction of pixels in small, bright and colorless regions standing out of the plate.
+/// Larger bright regions are overexposure rather than reflections.
+fn glare_fraction(image: &DynamicImage, gray: &GrayImage) -> f32 {
+    let rgb = image.to_rgb8();
+    let level = (gray.median()[0] as f32 + GLARE_CONTRAST).max(GLARE_LEVEL);
+    let bright = GrayImage::from_fn(gray.width(), gray.height(), |x, y| {
+        let p = rgb.get_pixel(x, y);
+        let chroma = p.0.iter().max().unwrap() - p.0.iter().min().unwrap();
+        let hot = gray.get_pixel(x, y)[0] as f32 >= level && chroma <= GLARE_MAX_CHROMA;
+        Luma([hot as u8])
+    });
+
+    let labels = connected_components(&bright, Connectivity::Eight, Luma([0]));
+    let mut areas: HashMap<u32, usize> = HashMap::new();
+    labels
+        .pixels()
+        .filter(|p| p[0] > 0)
+        .for_each(|p| *areas.entry(p[0]).or_insert(0) += 1);
+
+    let pixels = gray.len().max(1) as f32;
+    let glare: usize = areas
+        .values()
+        .filter(|area| (**area as f32) / pixels <= GLARE_MAX_AREA)
+        .sum();
+    glare as f32 / pixels
+}
+
+/// Coherence of the gradient structure tensor. Motion blur smears out the gradients along
+/// the direction of motion, so the remaining ones share a direction.
+fn gradient_anisotropy(gray: &GrayImage) -> f32 {
+    let (gx, gy) = (horizontal_sobel(gray), vertical_sobel(gray));
+    let (mut xx, mut yy, mut xy) = (0f64, 0f64, 0f64);
+    for (px, py) in gx.pixels().zip(gy.pixels()) {
+        let (dx, dy) = (px[0] as f64, py[0] as f64);
+        if dx.hypot(dy) < MIN_GRADIENT as f64 {
+            continue;
+        }
+        xx += dx * dx;
+        yy += dy * dy;
+        xy += dx * dy;
+    }
+    if xx + yy <= 0.0 {
+        return 0.0;
+    }
+    (((xx - yy).powi(2) + 4.0 * xy * xy).sqrt() / (xx + yy)) as f32
+}
+
+trait ConvertToF32 {
+    fn convert_to_f32(&self) -> FloatImage;
+}
+
+impl ConvertToF32 for GrayImage {
+    fn convert_to_f32(&self) -> FloatImage {
+        FloatImage::from_fn(self.width(), self.height(), |x, y| {
+            Luma([self.get_pixel(x, y)[0] as f32])
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{QualityReport, QualityThresholds, QualityWarning};
+    use image::{DynamicImage, GrayImage, Luma, Rgb, RgbImage};
+    use imageproc::filter::{box_filter, gaussian_blur_f32};
+
+    /// Round dark spots on a light plate with some texture
+    fn plate() -> GrayImage {
+        GrayImage::from_fn(200, 300, |x, y| {
+            let spot = (0..3).any(|i| {
+                let (cx, cy) = (50 + 50 * i, 80 + 60 * i);
+                (x as i32 - cx).pow(2) + (y as i32 - cy).pow(2) < 15 * 15
+            });
+            let texture = ((x * 7 + y * 13) % 5) as u8 * 4;
+            Luma([if spot { 70 } else { 190 } + texture])
+        })
+    }
+
+    fn codes(report: &QualityReport) -> Vec<&'static str> {
+        report.warnings.iter().map(QualityWarning::code).collect()
+    }
+
+    fn assess(image: GrayImage) -> QualityReport {
+        QualityReport::assess(
+            &DynamicImage::ImageLuma8(image),
+            &QualityThresholds::default(),
+        )
+    }
+
+    #[test]
+    fn test_good_plate_is_acceptable() {
+        let when = assess(plate());
+
+        assert!(when.is_acceptable(), "{:?}", when);
+    }
+
+    #[test]
+    fn test_detects_defocus() {
+        let blurred = gaussian_blur_f32(&plate(), 4.0);
+
+        let when = assess(blurred);
+
+        assert!(codes(&when).contains(&"blurry"), "{:?}", when);
+        assert!(!codes(&when).contains(&"motion_blur"), "{:?}", when);
+    }
+
+    #[test]
+    fn test_detects_motion_blur() {
+        let streaked = box_filter(&plate(), 12, 0);
+
+        let when = assess(streaked);
+
+        assert!(codes(&when).contains(&"motion_blur"), "{:?}", when);
+    }
+
+    #[test]
+    fn test_detects_clipping() {
+        let mut given = plate();
+        for y in 0..100 {
+            for x in 0..200 {
+                given.put_pixel(x, y, Luma([255]));
+            }
+        }
+
+        let when = assess(given);
+
+        assert_eq!(codes(&when), vec!["overexposed"]);
+        assert!((when.overexposed - 1.0 / 3.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_detects_glare() {
+        let mut given = DynamicImage::ImageLuma8(plate()).to_rgb8();
+        for y in 140..160 {
+            for x in 120..140 {
+                given.put_pixel(x, y, Rgb([248, 248, 245]));
+            }
+        }
+
+        let when = QualityReport::assess(
+            &DynamicImage::ImageRgb8(given),
+            &QualityThresholds::default(),
+        );
+
+        assert!(codes(&when).contains(&"glare"), "{:?}", when);
+        assert!(!codes(&when).contains(&"overexposed"), "{:?}", when);
+    }
+
+    #[test]
+    fn test_dark_photo_is_underexposed() {
+        let given = RgbImage::from_pixel(100, 100, Rgb([2, 3, 2]));
+
+        let when = QualityReport::assess(
+            &DynamicImage::ImageRgb8(given),
+            &QualityThresholds::default(),
+        );
+
+        assert!(codes(&when).contains(&"underexposed"));
+    }
+}
@@ -1,10 +1,29 @@
 extern crate num;
 
-use image::{DynamicImage, GrayImage, ImageBuffer, ImageResult, Luma, Pixel};
+pub use camera_model::{CameraModel, DeviceProfile, DeviceProfiles, Distortion};
+pub use capture_metadata::{CaptureMetadata, Orientation, WhiteBalance};
+pub use color_calibration::{ColorCalibration, ColorPatch, ColorTarget};
+pub use hdr_fusion::{align_exposures, fuse_exposures, Exposure};
+pub use image_quality::{QualityReport, QualityThresholds, QualityWarning};
+pub use plate_scale::{PlateScale, PlateSize};
+pub use replicates::ReplicateStatistics;
+pub use yuv::{nv21_to_rgb, yuv420_to_rgb, YuvPlane};
+
+mod camera_model;
+mod capture_metadata;
+mod color_calibration;
+mod hdr_fusion;
+mod image_quality;
+mod plate_scale;
+mod replicates;
+mod yuv;
+
+use image::{DynamicImage, GrayImage, ImageBuffer, ImageError, ImageResult, Luma, Pixel};
 use imageproc::map::map_pixels;
 use log::{debug, error};
 use nalgebra::Point2;
 use num::{FromPrimitive, ToPrimitive};
+use std::ops::Range;
 
 pub type HDRGrayImage = ImageBuffer<Luma<f64>, Vec<f64>>;
 
@@ -22,6 +41,7 @@ impl InvertGrayImage for GrayImage {
     }
 }
 
+#[allow(clippy::legacy_numeric_constants)]
 impl InvertGrayImage for HDRGrayImage {
     fn invert(&self) -> Self {
         map_pixels(self, |_x, _y, p| {
@@ -62,6 +82,7 @@ pub trait ColorSpaceConversion {
     fn to_srgb(&mut self);
 }
 
+#[allow(clippy::legacy_numeric_constants)]
 impl ColorSpaceConversion for HDRGrayImage {
     fn to_linear(&mut self) {
         self.pixels_mut().for_each(|p| {
@@ -121,7 +142,7 @@ impl StatsImage<Luma<u8>> for GrayImage {
 
         let mid = nums.len() / 2;
         Luma([if nums.len() % 2 == 0 {
-            (nums[mid - 1] + nums[mid]) / 2
+            ((nums[mid - 1] as u16 + nums[mid] as u16) / 2) as u8
         } else {
             nums[mid]
         }])
@@ -143,52 +164,32 @@ impl StatsImage<Luma<u8>> for GrayImage {
 }
 
 pub fn auto_canny(image: &GrayImage) -> GrayImage {
-    let otsu_level = imageproc::contrast::otsu_level(image);
+    // A zero threshold marks every pixel as edge, e.g. for flat images
+    let otsu_level = imageproc::contrast::otsu_level(image).max(1);
     let low_canny_threshold = (otsu_level / 2) as f32;
     let high_canny_threshold = otsu_level as f32;
     imageproc::edges::canny(image, low_canny_threshold, high_canny_threshold)
 }
 
-pub fn read_image(path: String) -> ImageResult<DynamicImage> {
-    let res_image = image::open(path.clone());
-
-    match res_image {
-        Ok(image) => {
-            let mut ret_image = image;
-            match rexif::parse_file(&path) {
-                Ok(exif) => {
-                    for entry in &exif.entries {
-                        if entry.tag == rexif::ExifTag::Orientation {
-                            match entry.value {
-                                rexif::TagValue::U16(ref v) => {
-                                    let n = v[0];
-                                    match n {
-                                        3 => {
-                                            // Upside down
-                                            ret_image = ret_image.rotate180();
-                                        }
-                                        6 => {
-                                            // Rotated left
-                                            ret_image = ret_image.rotate90();
-                                        }
-                                        8 => {
-                                            // Rotated right
-                                            ret_image = ret_image.rotate270();
-                                        }
-                                        _ => {} // Do nothing
-                                    }
-                                }
-                                _ => {}
-                            }
-                        }
-                    }
-                }
-                Err(e) => error!("Error in {}: {}", &path, e),
-            }
-            Ok(ret_image)
+/// Reads an image in its upright orientation together with its capture metadata
+pub fn read_image(path: String) -> ImageResult<(DynamicImage, CaptureMetadata)> {
+    let buffer = std::fs::read(&path).map_err(ImageError::IoError)?;
+    read_image_from_memory(&buffer)
+}
+
+/// Decodes an encoded image (e.g. JPEG) in its upright orientation together with its capture metadata
+pub fn read_image_from_memory(buffer: &[u8]) -> ImageResult<(DynamicImage, CaptureMetadata)> {
+    let image = image::load_from_memory(buffer)?;
+
+    let metadata = match rexif::parse_buffer(buffer) {
+        Ok(exif) => CaptureMetadata::from_exif(&exif),
+        Err(e) => {
+            error!("Error reading EXIF data: {}", e);
+            CaptureMetadata::default()
         }
-        Err(err) => Err(err),
-    }
+    };
+
+    Ok((metadata.orientation.apply(image), metadata))
 }
 
 pub fn attenuate_generic<T: PartialOrd + FromPrimitive + ToPrimitive + std::fmt::Debug>(
@@ -205,7 +206,19 @@ pub fn attenuate_generic<T: PartialOrd + FromPrimitive + ToPrimitive + std::fmt:
     }
 }
 
-#[derive(Debug)]
+/// First and past the last pixel with its centre between `start` and `end`, which may lie
+/// beyond the image
+pub fn pixel_span(start: f32, end: f32) -> (i64, i64) {
+    (start.ceil() as i64, end.floor() as i64 + 1)
+}
+
+/// `span` clipped to an image side of `size` pixels
+pub fn clip_span((start, end): (i64, i64), size: u32) -> Range<u32> {
+    let clip = |v: i64| v.clamp(0, size as i64) as u32;
+    clip(start)..clip(end)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Quad {
     pub top_left: Point2<f32>,
     pub top_right: Point2<f32>,
@@ -227,6 +240,14 @@ impl Quad {
         width / height
     }
 
+    /// Columns and rows of the pixels inside the axis aligned quad, see `pixel_span`
+    pub fn pixel_spans(&self) -> ((i64, i64), (i64, i64)) {
+        (
+            pixel_span(self.top_left.x, self.bottom_right.x),
+            pixel_span(self.top_left.y, self.bottom_right.y),
+        )
+    }
+
     pub fn to_tuple_vec(&self) -> Vec<(f32, f32)> {
         vec![
             (self.top_left.x, self.top_left.y),
@@ -245,10 +266,36 @@ impl Quad {
             self.bottom_right.x as i32,
             self.bottom_right.y as i32,
             self.bottom_left.x as i32,
-            self.bottom_right.y as i32,
+            self.bottom_left.y as i32,
         ]
     }
 
+    /// The corners as x, y pairs from the top left clockwise, without rounding
+    pub fn to_corner_vec(&self) -> Vec<f32> {
+        self.to_tuple_vec()
+            .into_iter()
+            .flat_map(|(x, y)| [x, y])
+            .collect()
+    }
+
+    /// Reads corners in the order of `to_corner_vec`. Unlike `from_simple_vec` the order is
+    /// kept, so rotated plates keep their orientation.
+    pub fn from_corner_vec(coords: &[f32]) -> Result<Self, String> {
+        if coords.len() != 8 {
+            return Err(format!(
+                "Expected 8 corner coordinates, got {}",
+                coords.len()
+            ));
+        }
+        let point = |i: usize| Point2::new(coords[2 * i], coords[2 * i + 1]);
+        Ok(Quad {
+            top_left: point(0),
+            top_right: point(1),
+            bottom_right: point(2),
+            bottom_left: point(3),
+        })
+    }
+
     pub fn from_simple_vec(coords: Vec<i32>) -> Self {
         let points: Vec<Point2<f32>> = coords
             .chunks(2)
@@ -280,7 +327,7 @@ impl Quad {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Circle {
     pub center: Point2<f32>,
     pub radius: f32,
@@ -307,6 +354,19 @@ impl Circle {
         }
     }
 
+    /// Pixels with their centre inside the circle, clipped to an image of `width` x `height`
+    pub fn pixels(&self, width: u32, height: u32) -> impl Iterator<Item = (u32, u32)> {
+        let circle = *self;
+        let (columns, rows) = circle.to_quad().pixel_spans();
+        let columns = clip_span(columns, width);
+        clip_span(rows, height)
+            .flat_map(move |y| columns.clone().map(move |x| (x, y)))
+            .filter(move |(x, y)| {
+                let (dx, dy) = (*x as f32 - circle.center.x, *y as f32 - circle.center.y);
+                dx * dx + dy * dy <= circle.radius * circle.radius
+            })
+    }
+
     pub fn to_tuples(&self) -> (f32, f32, f32) {
         (self.center.x, self.center.y, self.radius)
     }
@@ -326,3 +386,117 @@ impl Circle {
         }
     }
 }
+
+/// Samples per pixel side for pixels on the outline
+const SUBSAMPLES: u32 = 16;
+
+/// An elliptic spot, `angle` turns the x radius against the image x axis in radians
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ellipse {
+    pub center: Point2<f32>,
+    pub radius_x: f32,
+    pub radius_y: f32,
+    pub angle: f32,
+}
+
+impl Ellipse {
+    pub fn new(center_x: f32, center_y: f32, radius_x: f32, radius_y: f32, angle: f32) -> Self {
+        Ellipse {
+            center: Point2::new(center_x, center_y),
+            radius_x,
+            radius_y,
+            angle,
+        }
+    }
+
+    fn contains(&self, x: f32, y: f32) -> bool {
+        let (sin, cos) = self.angle.sin_cos();
+        let (dx, dy) = (x - self.center.x, y - self.center.y);
+        let u = (dx * cos + dy * sin) / self.radius_x;
+        let v = (dy * cos - dx * sin) / self.radius_y;
+        u * u + v * v <= 1.0
+    }
+
+    /// Fraction of the pixel centred at `x`, `y` covered by the ellipse
+    pub fn coverage(&self, x: u32, y: u32) -> f32 {
+        let (px, py) = (x as f32, y as f32);
+        let reach = self.radius_x.max(self.radius_y);
+        let distance = ((px - self.center.x).powi(2) + (py - self.center.y).powi(2)).sqrt();
+        if distance > reach + std::f32::consts::FRAC_1_SQRT_2 {
+            return 0.0;
+        }
+        // The ellipse is convex, so a pixel with all corners inside is covered
+        let corners = [(-0.5, -0.5), (0.5, -0.5), (0.5, 0.5), (-0.5, 0.5)];
+        if corners
+            .iter()
+            .all(|(cx, cy)| self.contains(px + cx, py + cy))
+        {
+            return 1.0;
+        }
+        let step = 1.0 / SUBSAMPLES as f32;
+        let inside = (0..SUBSAMPLES)
+            .flat_map(|j| (0..SUBSAMPLES).map(move |i| (i, j)))
+            .filter(|(i, j)| {
+                self.contains(
+                    px - 0.5 + (*i as f32 + 0.5) * step,
+                    py - 0.5 + (*j as f32 + 0.5) * step,
+                )
+            })
+            .count();
+        inside as f32 / (SUBSAMPLES * SUBSAMPLES) as f32
+    }
+}
+
+impl From<Circle> for Ellipse {
+    fn from(circle: Circle) -> Self {
+        Ellipse::new(
+            circle.center.x,
+            circle.center.y,
+            circle.radius,
+            circle.radius,
+            0.0,
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{Circle, Ellipse, Quad};
+
+    #[test]
+    fn test_corner_vec_keeps_order_of_rotated_quad() {
+        // A plate turned by 45 degrees, the top left corner is the leftmost one
+        let given = [10.0, 50.5, 50.0, 10.0, 90.0, 50.0, 50.0, 90.5];
+
+        let when = Quad::from_corner_vec(&given).unwrap();
+
+        assert_eq!(when.top_left.x, 10.0);
+        assert_eq!(when.bottom_left.y, 90.5);
+        assert_eq!(when.to_corner_vec(), given.to_vec());
+        assert_eq!(when.to_simple_vec()[7], 90);
+        assert!(Quad::from_corner_vec(&given[..6]).is_err());
+    }
+
+    #[test]
+    fn test_circle_pixels_are_clipped_alike_on_each_border() {
+        let given = Circle::new(5.0, 5.0, 2.0);
+        assert_eq!(given.pixels(10, 10).count(), 13);
+
+        for (x, y) in [(0.0, 5.0), (9.0, 5.0), (5.0, 0.0), (5.0, 9.0)] {
+            let when: Vec<(u32, u32)> = Circle::new(x, y, 2.0).pixels(10, 10).collect();
+            assert_eq!(when.len(), 9, "{} {}", x, y);
+            assert!(when.iter().all(|(x, y)| *x < 10 && *y < 10));
+        }
+        assert_eq!(Circle::new(-5.0, 5.0, 2.0).pixels(10, 10).count(), 0);
+    }
+
+    #[test]
+    fn test_pixel_coverage() {
+        let spot = Ellipse::new(10.0, 10.0, 3.0, 3.0, 0.0);
+
+        assert_eq!(spot.coverage(10, 10), 1.0);
+        assert_eq!(spot.coverage(20, 10), 0.0);
+        // The outline runs through the middle of this pixel
+        assert!((spot.coverage(13, 10) - 0.5).abs() < 0.05);
+    }
+}
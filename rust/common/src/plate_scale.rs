@@ -0,0 +1,88 @@
+use crate::Circle;
+use nalgebra::Point2;
+
+/// Physical size of a plate, e.g. 50 x 100 mm. The width runs along the top edge.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PlateSize {
+    pub width_mm: f32,
+    pub height_mm: f32,
+}
+
+impl PlateSize {
+    pub fn new(width_mm: f32, height_mm: f32) -> Self {
+        PlateSize {
+            width_mm,
+            height_mm,
+        }
+    }
+
+    /// Size in whole pixels at the given scale
+    pub fn pixels(&self, scale: &PlateScale) -> (u32, u32) {
+        (
+            scale.to_pixels(self.width_mm).round() as u32,
+            scale.to_pixels(self.height_mm).round() as u32,
+        )
+    }
+}
+
+/// Scale of a rectified plate image, so measurements can be reported in millimeters
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PlateScale {
+    pub pixels_per_mm: f32,
+}
+
+impl PlateScale {
+    pub fn new(pixels_per_mm: f32) -> Self {
+        PlateScale { pixels_per_mm }
+    }
+
+    pub fn to_mm(&self, pixels: f32) -> f32 {
+        pixels / self.pixels_per_mm
+    }
+
+    pub fn to_pixels(&self, mm: f32) -> f32 {
+        mm * self.pixels_per_mm
+    }
+
+    pub fn area_to_mm2(&self, pixels: f32) -> f32 {
+        pixels / (self.pixels_per_mm * self.pixels_per_mm)
+    }
+
+    /// Position in mm from the top left plate corner, for pixel centre coordinates
+    pub fn point_to_mm(&self, point: &Point2<f32>) -> Point2<f32> {
+        Point2::new(self.to_mm(point.x + 0.5), self.to_mm(point.y + 0.5))
+    }
+
+    pub fn circle_to_mm(&self, circle: &Circle) -> Circle {
+        Circle {
+            center: self.point_to_mm(&circle.center),
+            radius: self.to_mm(circle.radius),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{Circle, PlateScale, PlateSize};
+
+    #[test]
+    fn test_conversions() {
+        let given = PlateScale::new(10.0);
+
+        assert_eq!(given.to_mm(25.0), 2.5);
+        assert_eq!(given.to_pixels(2.5), 25.0);
+        assert_eq!(given.area_to_mm2(200.0), 2.0);
+        assert_eq!(
+            given.circle_to_mm(&Circle::new(9.5, 19.5, 5.0)),
+            Circle::new(1.0, 2.0, 0.5)
+        );
+    }
+
+    #[test]
+    fn test_plate_pixels() {
+        let given = PlateSize::new(50.0, 100.0);
+
+        assert_eq!(given.pixels(&PlateScale::new(10.0)), (500, 1000));
+        assert_eq!(given.pixels(&PlateScale::new(2.55)), (128, 255));
+    }
+}
@@ -0,0 +1,83 @@
+use std::collections::HashMap;
+
+/// Spread of one value over replicate captures of the same plate
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReplicateStatistics {
+    pub mean: f64,
+    /// Sample standard deviation
+    pub sd: f64,
+    /// Relative standard deviation in percent of the mean
+    pub rsd: f64,
+    pub count: usize,
+}
+
+impl ReplicateStatistics {
+    /// Needs at least two values for a standard deviation
+    pub fn from_values(values: &[f64]) -> Option<Self> {
+        let count = values.len();
+        if count < 2 {
+            return None;
+        }
+        let mean = values.iter().sum::<f64>() / count as f64;
+        let variance = values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (count - 1) as f64;
+        let sd = variance.sqrt();
+        let rsd = if mean != 0.0 {
+            sd / mean.abs() * 100.0
+        } else {
+            f64::NAN
+        };
+        Some(ReplicateStatistics {
+            mean,
+            sd,
+            rsd,
+            count,
+        })
+    }
+
+    /// Statistics of each spot over the replicates, spots found in fewer than two are left out
+    pub fn per_spot(replicates: &[HashMap<u32, f64>]) -> HashMap<u32, ReplicateStatistics> {
+        let mut values: HashMap<u32, Vec<f64>> = HashMap::new();
+        for replicate in replicates {
+            for (key, value) in replicate {
+                values.entry(*key).or_default().push(*value);
+            }
+        }
+        values
+            .into_iter()
+            .filter_map(|(key, v)| ReplicateStatistics::from_values(&v).map(|s| (key, s)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::ReplicateStatistics;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_statistics_of_values() {
+        let given = [98.0, 100.0, 102.0];
+
+        let when = ReplicateStatistics::from_values(&given).unwrap();
+
+        assert_eq!(when.mean, 100.0);
+        assert_eq!(when.sd, 2.0);
+        assert_eq!(when.rsd, 2.0);
+        assert_eq!(ReplicateStatistics::from_values(&[1.0]), None);
+    }
+
+    #[test]
+    fn test_statistics_per_spot() {
+        let given: Vec<HashMap<u32, f64>> = vec![
+            vec![(1, 10.0), (2, 50.0)].into_iter().collect(),
+            vec![(1, 14.0), (2, 50.0), (3, 7.0)].into_iter().collect(),
+        ];
+
+        let when = ReplicateStatistics::per_spot(&given);
+
+        assert_eq!(when.len(), 2);
+        assert_eq!(when[&1].mean, 12.0);
+        assert_eq!(when[&2].sd, 0.0);
+        assert!(!when.contains_key(&3));
+    }
+}
@@ -0,0 +1,222 @@
+use image::{Rgb, RgbImage};
+
+/// A single plane of a YUV 4:2:0 frame as delivered by the Android camera
+pub struct YuvPlane<'a> {
+    pub data: &'a [u8],
+    pub row_stride: usize,
+    pub pixel_stride: usize,
+}
+
+impl<'a> YuvPlane<'a> {
+    pub fn new(data: &'a [u8], row_stride: usize, pixel_stride: usize) -> Self {
+        YuvPlane {
+            data,
+            row_stride,
+            pixel_stride,
+        }
+    }
+
+    fn validate(&self, width: usize, height: usize, name: &str) -> Result<(), String> {
+        let invalid = || {
+            format!(
+                "Invalid strides for {} plane: row {} pixel {}",
+                name, self.row_stride, self.pixel_stride
+            )
+        };
+        // The strides come from the camera, so their products may overflow
+        let row_length = (width - 1)
+            .checked_mul(self.pixel_stride)
+            .and_then(|v| v.checked_add(1))
+            .ok_or_else(invalid)?;
+        if self.pixel_stride == 0 || self.row_stride < row_length {
+            return Err(invalid());
+        }
+
+        // The last row of a plane is usually not padded to the full row stride
+        let required = (height - 1)
+            .checked_mul(self.row_stride)
+            .and_then(|v| v.checked_add(row_length))
+            .ok_or_else(invalid)?;
+        if self.data.len() < required {
+            return Err(format!(
+                "{} plane too small: {} bytes, expected at least {}",
+                name,
+                self.data.len(),
+                required
+            ));
+        }
+        Ok(())
+    }
+
+    fn sample(&self, x: usize, y: usize) -> u8 {
+        self.data[y * self.row_stride + x * self.pixel_stride]
+    }
+}
+
+/// Converts a YUV_420_888 frame (full range BT.601) to RGB
+pub fn yuv420_to_rgb(
+    width: u32,
+    height: u32,
+    y_plane: &YuvPlane,
+    u_plane: &YuvPlane,
+    v_plane: &YuvPlane,
+) -> Result<RgbImage, String> {
+    if width == 0 || height == 0 {
+        return Err("Empty YUV frame".to_string());
+    }
+
+    let (w, h) = (width as usize, height as usize);
+    let (cw, ch) = (w.div_ceil(2), h.div_ceil(2));
+    y_plane.validate(w, h, "Y")?;
+    u_plane.validate(cw, ch, "U")?;
+    v_plane.validate(cw, ch, "V")?;
+
+    Ok(RgbImage::from_fn(width, height, |x, y| {
+        let (x, y) = (x as usize, y as usize);
+        yuv_to_rgb(
+            y_plane.sample(x, y),
+            u_plane.sample(x / 2, y / 2),
+            v_plane.sample(x / 2, y / 2),
+        )
+    }))
+}
+
+/// Converts a NV21 frame (Y plane followed by interleaved V/U samples) to RGB
+pub fn nv21_to_rgb(width: u32, height: u32, data: &[u8]) -> Result<RgbImage, String> {
+    if width == 0 || height == 0 {
+        return Err("Empty NV21 frame".to_string());
+    }
+    let luma_len = (width as usize)
+        .checked_mul(height as usize)
+        .ok_or_else(|| format!("NV21 frame too large: {}x{}", width, height))?;
+    if data.len() <= luma_len {
+        return Err(format!(
+            "NV21 frame too small: {} bytes for {}x{}",
+            data.len(),
+            width,
+            height
+        ));
+    }
+
+    let (luma, chroma) = data.split_at(luma_len);
+    let row_stride = width as usize + width as usize % 2;
+    yuv420_to_rgb(
+        width,
+        height,
+        &YuvPlane::new(luma, width as usize, 1),
+        &YuvPlane::new(&chroma[1..], row_stride, 2),
+        &YuvPlane::new(chroma, row_stride, 2),
+    )
+}
+
+fn yuv_to_rgb(y: u8, u: u8, v: u8) -> Rgb<u8> {
+    let y = y as f32;
+    let u = u as f32 - 128.0;
+    let v = v as f32 - 128.0;
+
+    let r = v.mul_add(1.402, y);
+    let g = y - 0.344_136 * u - 0.714_136 * v;
+    let b = u.mul_add(1.772, y);
+
+    Rgb([
+        r.round().clamp(0.0, 255.0) as u8,
+        g.round().clamp(0.0, 255.0) as u8,
+        b.round().clamp(0.0, 255.0) as u8,
+    ])
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{nv21_to_rgb, yuv420_to_rgb, YuvPlane};
+    use image::Rgb;
+
+    #[test]
+    fn test_neutral_chroma_is_gray() {
+        let luma: Vec<u8> = (0..16).map(|i| i * 16).collect();
+        let chroma = vec![128u8; 4];
+
+        let when = yuv420_to_rgb(
+            4,
+            4,
+            &YuvPlane::new(&luma, 4, 1),
+            &YuvPlane::new(&chroma, 2, 1),
+            &YuvPlane::new(&chroma, 2, 1),
+        )
+        .unwrap();
+
+        for (x, y, p) in when.enumerate_pixels() {
+            let l = luma[(x + y * 4) as usize];
+            assert_eq!(*p, Rgb([l, l, l]));
+        }
+    }
+
+    #[test]
+    fn test_nv21_matches_planar() {
+        // 4x2 frame with two distinct chroma blocks
+        let luma = vec![80u8, 90, 100, 110, 120, 130, 140, 150];
+        let u = vec![90u8, 200];
+        let v = vec![240u8, 60];
+
+        let mut nv21 = luma.clone();
+        nv21.extend(v.iter().zip(u.iter()).flat_map(|(v, u)| vec![*v, *u]));
+
+        let then = yuv420_to_rgb(
+            4,
+            2,
+            &YuvPlane::new(&luma, 4, 1),
+            &YuvPlane::new(&u, 2, 1),
+            &YuvPlane::new(&v, 2, 1),
+        )
+        .unwrap();
+        let when = nv21_to_rgb(4, 2, &nv21).unwrap();
+
+        assert_eq!(when.into_vec(), then.into_vec());
+    }
+
+    #[test]
+    fn test_padded_rows() {
+        // Row stride of 6 for a 4 pixel wide frame, the last row is not padded
+        let luma = vec![10u8, 20, 30, 40, 0, 0, 50, 60, 70, 80];
+        let chroma = vec![128u8; 4];
+
+        let when = yuv420_to_rgb(
+            4,
+            2,
+            &YuvPlane::new(&luma, 6, 1),
+            &YuvPlane::new(&chroma, 4, 2),
+            &YuvPlane::new(&chroma[1..], 4, 2),
+        )
+        .unwrap();
+
+        assert_eq!(
+            when.pixels().map(|p| p[0]).collect::<Vec<u8>>(),
+            vec![10, 20, 30, 40, 50, 60, 70, 80]
+        );
+    }
+
+    #[test]
+    fn test_too_small_buffer() {
+        assert!(nv21_to_rgb(4, 4, &[0u8; 10]).is_err());
+        assert!(nv21_to_rgb(0, 4, &[0u8; 10]).is_err());
+        assert!(nv21_to_rgb(u32::MAX, u32::MAX, &[0u8; 10]).is_err());
+    }
+
+    #[test]
+    fn test_overflowing_strides() {
+        let data = vec![128u8; 16];
+        let plane = |row_stride: usize, pixel_stride: usize| {
+            yuv420_to_rgb(
+                4,
+                4,
+                &YuvPlane::new(&data, row_stride, pixel_stride),
+                &YuvPlane::new(&data, 2, 1),
+                &YuvPlane::new(&data, 2, 1),
+            )
+        };
+
+        assert!(plane(4, 1).is_ok());
+        assert!(plane(usize::MAX, 1).is_err());
+        assert!(plane(usize::MAX, usize::MAX / 2).is_err());
+        assert!(plane(usize::MAX / 2, 1).is_err());
+    }
+}
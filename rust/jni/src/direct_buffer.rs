@@ -0,0 +1,43 @@
+use jni_sys::{jobject, JNIEnv};
+
+/// `java.nio.ByteBuffer` as seen by JNI
+pub type JByteBuffer = jobject;
+
+/// Borrowed view on the memory of a direct `java.nio.ByteBuffer`, e.g. a camera image plane
+pub struct DirectByteBuffer {
+    data: *const u8,
+    len: usize,
+}
+
+impl DirectByteBuffer {
+    /// # Safety
+    ///
+    /// `env` must be the JNIEnv of the current call and `buffer` a live reference to a
+    /// `java.nio.ByteBuffer`. The view is only valid for the duration of the JNI call.
+    pub unsafe fn new(env: *mut JNIEnv, buffer: JByteBuffer) -> Self {
+        let data = (**env).GetDirectBufferAddress.unwrap()(env, buffer) as *const u8;
+        let capacity = (**env).GetDirectBufferCapacity.unwrap()(env, buffer);
+
+        // Heap buffers have no accessible address and report a capacity of -1
+        if data.is_null() || capacity < 0 {
+            DirectByteBuffer {
+                data: std::ptr::null(),
+                len: 0,
+            }
+        } else {
+            DirectByteBuffer {
+                data,
+                len: capacity as usize,
+            }
+        }
+    }
+
+    /// Fails for heap buffers, e.g. from `ByteBuffer.wrap`, which JNI cannot access
+    pub fn as_slice(&self) -> Result<&[u8], String> {
+        if self.data.is_null() {
+            Err("Expected a direct ByteBuffer".to_string())
+        } else {
+            Ok(unsafe { std::slice::from_raw_parts(self.data, self.len) })
+        }
+    }
+}
@@ -1 +1,16 @@
+// Lints of the code generated by flapigen, which we cannot change
+#![allow(
+    mismatched_lifetime_syntaxes,
+    clippy::empty_docs,
+    clippy::legacy_numeric_constants,
+    clippy::let_and_return,
+    clippy::let_unit_value,
+    clippy::missing_safety_doc,
+    clippy::not_unsafe_ptr_arg_deref,
+    clippy::possible_missing_else,
+    clippy::redundant_field_names,
+    clippy::unnecessary_cast,
+    clippy::unused_unit
+)]
+
 include!(concat!(env!("OUT_DIR"), "/java_glue.rs"));
@@ -1,89 +1,858 @@
 #![allow(dead_code)]
 
-use image::DynamicImage;
+use image::{DynamicImage, GrayImage, Luma};
 use log::{debug, info};
+use nalgebra::Point2;
 use std::collections::HashMap;
 use std::path::PathBuf;
-use tlc_background_removal::BackgroundFitter;
-use tlc_blob_detection;
-use tlc_common::{read_image, Circle, Quad};
-use tlc_plate_detection::Detector;
-use tlc_plate_extraction;
+use tlc_background_removal::{BackgroundFitter, FlatField};
+use tlc_blob_detection::{
+    Blob, BlobDescriptors, BlobDetectionParams, BlobDetector, Connectivity, LanePrior,
+    ThresholdDetector, ThresholdMethod, WatershedSeeds,
+};
+use tlc_common::{
+    attenuate_generic, nv21_to_rgb, read_image, read_image_from_memory, yuv420_to_rgb,
+    CaptureMetadata, Circle, ColorCalibration, ColorTarget, DeviceProfiles, Ellipse, Exposure,
+    HDRGrayImage, PlateScale, PlateSize, Quad, QualityReport, QualityThresholds,
+    ReplicateStatistics, YuvPlane,
+};
+use tlc_plate_detection::{
+    register_markers, CombinedDetector, DetectionResult, Detector, MarkerKind, MarkerLayout,
+    PlateTracker, TrackingState,
+};
+use tlc_plate_extraction::{PlateTransform, WarpOptions};
 use tlc_reference_percent_fitter::ReferencePercentFitter;
 
+mod direct_buffer;
 mod java_glue;
+use crate::direct_buffer::DirectByteBuffer;
 pub use crate::java_glue::*;
 
 struct TlcProcessor {
     input: DynamicImage,
+    metadata: CaptureMetadata,
     save_path: PathBuf,
+    plate_detection: Option<DetectionResult>,
+    scale: Option<PlateScale>,
+    plate_warp: Option<PlateWarp>,
+    previous_plate_warp: Option<PlateWarp>,
+    warp_options: WarpOptions,
+    exposures: Vec<Exposure>,
+    /// The corrected crop of the warped plate and of its replicate photos
+    replicates: Vec<DynamicImage>,
+    fused_exposures: bool,
     background_removed: Option<DynamicImage>,
+    background_removed_hdr: Option<HDRGrayImage>,
     background_fitter: Option<BackgroundFitter>,
+    flat_field: Option<FlatField>,
+    color_calibration: Option<ColorCalibration>,
+    quality: Option<QualityReport>,
+    quality_thresholds: QualityThresholds,
+    lane_layout: Option<LanePrior>,
+    /// The blobs of the last detection with the descriptors of their labeled components
+    detected_blobs: Option<HashMap<u32, Blob>>,
     integrated_blobs: Option<HashMap<u32, u64>>,
 }
 
+/// The crop transform of a warp, which works on the input rotated clockwise by `orientation`
+#[derive(Debug, Clone, Copy)]
+struct PlateWarp {
+    transform: PlateTransform,
+    orientation: u32,
+}
+
+impl PlateWarp {
+    fn circle_to_input(&self, circle: &Circle, (width, height): (u32, u32)) -> Circle {
+        let rotated = self.transform.circle_to_photo(circle);
+        let (w, h) = ((width - 1) as f32, (height - 1) as f32);
+        let (x, y) = (rotated.center.x, rotated.center.y);
+        let center = match self.orientation {
+            90 => Point2::new(y, h - x),
+            180 => Point2::new(w - x, h - y),
+            270 => Point2::new(w - y, x),
+            _ => Point2::new(x, y),
+        };
+        Circle { center, ..rotated }
+    }
+
+    fn circle_from_input(&self, circle: &Circle, (width, height): (u32, u32)) -> Circle {
+        let (w, h) = ((width - 1) as f32, (height - 1) as f32);
+        let (x, y) = (circle.center.x, circle.center.y);
+        let center = match self.orientation {
+            90 => Point2::new(h - y, x),
+            180 => Point2::new(w - x, h - y),
+            270 => Point2::new(y, w - x),
+            _ => Point2::new(x, y),
+        };
+        self.transform.circle_to_crop(&Circle { center, ..*circle })
+    }
+}
+
+fn init_logging() {
+    #[cfg(target_os = "android")]
+    android_logger::init_once(
+        android_logger::Config::default()
+            .with_min_level(log::Level::Debug)
+            .with_tag("TlcNi"),
+    );
+    log_panics::init(); // log panics rather than printing them
+    info!("init log system - done");
+}
+
+fn as_bytes(data: &[i8]) -> &[u8] {
+    // Java bytes are signed, the memory layout is identical
+    unsafe { std::slice::from_raw_parts(data.as_ptr() as *const u8, data.len()) }
+}
+
+/// Java has no unsigned integers, so sizes and strides arrive as i32
+fn positive(value: i32, name: &str) -> Result<u32, String> {
+    if value > 0 {
+        Ok(value as u32)
+    } else {
+        Err(format!("The {} must be positive, got {}", name, value))
+    }
+}
+
+/// The image rotated clockwise by `orientation` degrees
+fn rotated(image: &DynamicImage, orientation: u32) -> DynamicImage {
+    match orientation {
+        90 => image.rotate90(),
+        180 => image.rotate180(),
+        270 => image.rotate270(),
+        _ => image.clone(),
+    }
+}
+
+/// Blobs are passed as id, x, y and radius each
+fn check_blobs(blobs: &[i32]) -> Result<(), String> {
+    if blobs.len() % 4 == 0 {
+        Ok(())
+    } else {
+        Err(format!(
+            "Expected 4 values per blob, got {} values",
+            blobs.len()
+        ))
+    }
+}
+
+/// Blobs as id, x, y and radius
+fn parse_blobs(blobs: &[i32]) -> Result<HashMap<u32, Circle>, String> {
+    check_blobs(blobs)?;
+    Ok(blobs
+        .chunks(4)
+        .map(|blob| (blob[0] as u32, Circle::from_simple_vec(blob[1..].to_vec())))
+        .collect())
+}
+
+/// Elliptic spots as id, x, y, x radius, y radius and angle
+fn parse_ellipses(spots: &[f32]) -> Result<HashMap<u32, Ellipse>, String> {
+    if spots.len() % 6 != 0 {
+        return Err(format!(
+            "Expected 6 values per ellipse, got {} values",
+            spots.len()
+        ));
+    }
+    Ok(spots
+        .chunks(6)
+        .map(|spot| {
+            let ellipse = Ellipse::new(spot[1], spot[2], spot[3], spot[4], spot[5]);
+            (spot[0] as u32, ellipse)
+        })
+        .collect())
+}
+
+/// Reference percentages as id and percentage
+fn parse_percentages(key_percentage: &[f32]) -> HashMap<u32, f32> {
+    key_percentage
+        .chunks(2)
+        .map(|chunk| (chunk[0] as u32, chunk[1]))
+        .collect()
+}
+
+/// Applies `mapping` to blobs given as id, x, y and radius
+fn map_blobs<F>(blobs: &[i32], mapping: F) -> Result<Vec<i32>, String>
+where
+    F: Fn(&Circle) -> Circle,
+{
+    check_blobs(blobs)?;
+    Ok(blobs
+        .chunks(4)
+        .flat_map(|blob| {
+            let circle = mapping(&Circle::from_simple_vec(blob[1..].to_vec()));
+            vec![
+                blob[0],
+                circle.center.x.round() as i32,
+                circle.center.y.round() as i32,
+                circle.radius.round() as i32,
+            ]
+        })
+        .collect())
+}
+
 impl TlcProcessor {
     fn new(path: String) -> Self {
-        #[cfg(target_os = "android")]
-        android_logger::init_once(
-            android_logger::Config::default()
-                .with_min_level(log::Level::Debug)
-                .with_tag("TlcNi"),
-        );
-        log_panics::init(); // log panics rather than printing them
-        info!("init log system - done");
+        init_logging();
 
-        let image = read_image(path.clone()).unwrap();
-        let mut path_buf = PathBuf::from(path.clone());
+        let (image, metadata) = read_image(path.clone()).unwrap();
+        let mut path_buf = PathBuf::from(path);
         path_buf.pop();
+        TlcProcessor::from_image(image, metadata, path_buf)
+    }
+
+    fn from_encoded(buffer: &[i8], save_dir: String) -> Result<Self, String> {
+        init_logging();
+
+        let (image, metadata) = read_image_from_memory(as_bytes(buffer))
+            .map_err(|e| format!("Decoding the image failed: {}", e))?;
+        Ok(TlcProcessor::from_image(
+            image,
+            metadata,
+            PathBuf::from(save_dir),
+        ))
+    }
+
+    fn from_encoded_buffer(buffer: DirectByteBuffer, save_dir: String) -> Result<Self, String> {
+        init_logging();
+
+        let (image, metadata) = read_image_from_memory(buffer.as_slice()?)
+            .map_err(|e| format!("Decoding the image failed: {}", e))?;
+        Ok(TlcProcessor::from_image(
+            image,
+            metadata,
+            PathBuf::from(save_dir),
+        ))
+    }
+
+    fn from_nv21(data: &[i8], width: i32, height: i32, save_dir: String) -> Result<Self, String> {
+        init_logging();
+
+        let rgb = nv21_to_rgb(
+            positive(width, "width")?,
+            positive(height, "height")?,
+            as_bytes(data),
+        )?;
+        Ok(TlcProcessor::from_image(
+            DynamicImage::ImageRgb8(rgb),
+            CaptureMetadata::default(),
+            PathBuf::from(save_dir),
+        ))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn from_yuv420(
+        y_plane: DirectByteBuffer,
+        u_plane: DirectByteBuffer,
+        v_plane: DirectByteBuffer,
+        width: i32,
+        height: i32,
+        y_row_stride: i32,
+        uv_row_stride: i32,
+        uv_pixel_stride: i32,
+        save_dir: String,
+    ) -> Result<Self, String> {
+        init_logging();
+
+        let uv_row_stride = positive(uv_row_stride, "UV row stride")? as usize;
+        let uv_pixel_stride = positive(uv_pixel_stride, "UV pixel stride")? as usize;
+        let rgb = yuv420_to_rgb(
+            positive(width, "width")?,
+            positive(height, "height")?,
+            &YuvPlane::new(
+                y_plane.as_slice()?,
+                positive(y_row_stride, "Y row stride")? as usize,
+                1,
+            ),
+            &YuvPlane::new(u_plane.as_slice()?, uv_row_stride, uv_pixel_stride),
+            &YuvPlane::new(v_plane.as_slice()?, uv_row_stride, uv_pixel_stride),
+        )?;
+        Ok(TlcProcessor::from_image(
+            DynamicImage::ImageRgb8(rgb),
+            CaptureMetadata::default(),
+            PathBuf::from(save_dir),
+        ))
+    }
+
+    fn from_image(image: DynamicImage, metadata: CaptureMetadata, save_path: PathBuf) -> Self {
         TlcProcessor {
             input: image,
-            save_path: path_buf,
+            metadata,
+            save_path,
+            plate_detection: None,
+            scale: None,
+            plate_warp: None,
+            previous_plate_warp: None,
+            warp_options: WarpOptions::default(),
+            exposures: vec![],
+            replicates: vec![],
+            fused_exposures: false,
             background_removed: None,
+            background_removed_hdr: None,
             background_fitter: None,
+            flat_field: None,
+            color_calibration: None,
+            quality: None,
+            quality_thresholds: QualityThresholds::default(),
+            lane_layout: None,
+            detected_blobs: None,
             integrated_blobs: None,
         }
     }
 
-    fn detect_plate(&self) -> Vec<i32> {
-        let detector = Detector::new(&self.input);
-        let plate = detector.corners_or_default();
+    fn capture_timestamp(&self) -> Option<String> {
+        self.metadata.timestamp.clone()
+    }
+
+    fn capture_device(&self) -> Option<String> {
+        self.metadata.device()
+    }
 
-        plate.to_simple_vec()
+    fn capture_exposure_time(&self) -> Option<f64> {
+        self.metadata.exposure_time
     }
 
-    fn warp_plate(&mut self, coords: &[i32], orientation: u32) -> bool {
-        let plate = Quad::from_simple_vec(coords.to_vec());
+    fn capture_iso(&self) -> Option<i32> {
+        self.metadata.iso.map(|iso| iso as i32)
+    }
 
-        let mut save_path = self.save_path.clone();
-        save_path.push("warped.png");
-        debug!("Warp Save path {:#?}", save_path);
+    fn capture_focal_length(&self) -> Option<f64> {
+        self.metadata.focal_length
+    }
+
+    /// Plate corners as x, y pairs from the top left clockwise, in the order of the detector
+    fn detect_plate(&mut self) -> Vec<f32> {
+        let detector =
+            Detector::new(&self.input).with_strategy(Box::new(CombinedDetector::default()));
+        let detection = detector.detect_refined(&self.input.to_luma8());
+        let plate = match detection.quad {
+            Some(quad) => quad,
+            None => detector.default_corners(),
+        };
+        info!(
+            "Plate detection confidence {}, inferred {:?}, failure {:?}",
+            detection.confidence, detection.inferred_corners, detection.failure
+        );
+        self.plate_detection = Some(detection);
+
+        plate.to_corner_vec()
+    }
+
+    fn plate_confidence(&self) -> f32 {
+        self.plate_detection
+            .as_ref()
+            .map_or(0.0, |detection| detection.confidence)
+    }
+
+    /// Indices of the inferred corners in top left, top right, bottom right, bottom left order
+    fn plate_inferred_corners(&self) -> Vec<i32> {
+        self.plate_detection.as_ref().map_or(vec![], |detection| {
+            detection
+                .inferred_corners
+                .iter()
+                .map(|corner| corner.index() as i32)
+                .collect()
+        })
+    }
+
+    fn plate_detection_failure(&self) -> Option<String> {
+        self.plate_detection
+            .as_ref()
+            .and_then(|detection| detection.failure)
+            .map(|failure| failure.to_string())
+    }
+
+    /// Plate corners registered with fiducial markers on the capture box.
+    /// `markers` holds code, x and y of each marker and `plate` the four plate corners
+    /// in the same layout coordinates, e.g. mm. The corners are returned like `detect_plate`.
+    fn detect_plate_from_markers(
+        &self,
+        circular: bool,
+        marker_size: f32,
+        markers: &[f32],
+        plate: &[f32],
+    ) -> Result<Vec<f32>, String> {
+        if markers.len() % 3 != 0 || plate.len() != 8 {
+            return Err("Invalid marker layout".to_string());
+        }
+        if !marker_size.is_finite() || markers.iter().chain(plate).any(|v| !v.is_finite()) {
+            return Err("Marker layout must be finite".to_string());
+        }
+        let layout = MarkerLayout {
+            kind: if circular {
+                MarkerKind::Circle
+            } else {
+                MarkerKind::Square
+            },
+            marker_size,
+            markers: markers
+                .chunks(3)
+                .map(|m| (m[0] as u16, Point2::new(m[1], m[2])))
+                .collect(),
+            plate: Quad::from_corner_vec(plate)?,
+        };
+
+        let registration = register_markers(&self.input.to_luma8(), &layout)?;
+        info!(
+            "Registered {} fiducial markers with an error of {} px",
+            registration.markers_found, registration.reprojection_error
+        );
+        Ok(registration.plate_quad(&layout).to_corner_vec())
+    }
+
+    /// Loads the lens model of the capturing device from a device profile file, so warping
+    /// removes the lens distortion. Returns false if the file has no profile for the device.
+    fn load_camera_profile(&mut self, profiles_path: String) -> Result<bool, String> {
+        let profiles = DeviceProfiles::load(&profiles_path)?;
+        let camera = match self
+            .metadata
+            .device()
+            .and_then(|device| profiles.find(&device).copied())
+        {
+            Some(camera) => camera,
+            None => return Ok(false),
+        };
+
+        let (width, height) = (self.input.width(), self.input.height());
+        // Profiles may have been calibrated in the other orientation
+        let fitted = camera
+            .scaled_to(width, height)
+            .or_else(|_| camera.rotated(90).scaled_to(width, height))?;
+        info!("Using the lens model {:?}", fitted);
+        self.warp_options.camera = Some(fitted);
+        Ok(true)
+    }
+
+    /// Sets the plate resampling: nearest, bilinear, bicubic, lanczos3 or area
+    fn set_warp_interpolation(&mut self, interpolation: String) -> Result<(), String> {
+        self.warp_options.interpolation = interpolation.parse()?;
+        Ok(())
+    }
+
+    fn rotated_warp_options(&self, orientation: u32) -> WarpOptions {
+        WarpOptions {
+            camera: self
+                .warp_options
+                .camera
+                .map(|camera| camera.rotated(orientation)),
+            ..self.warp_options
+        }
+    }
 
-        let correct_rotation = match orientation {
-            90 => self.input.rotate90(),
-            180 => self.input.rotate180(),
-            270 => self.input.rotate270(),
-            _ => self.input.clone(),
+    /// Warps the plate with corners in the order of `detect_plate`
+    fn warp_plate(&mut self, coords: &[f32], orientation: u32) -> bool {
+        let plate = match Quad::from_corner_vec(coords) {
+            Ok(plate) => plate,
+            Err(_) => return false,
         };
+        let save_path = self.warp_save_path();
+
         let maybe_crop = tlc_plate_extraction::unwarp_crop(
-            &correct_rotation,
+            &self.rotated_input(orientation),
             &plate,
-            save_path.into_os_string().into_string().unwrap(),
+            &self.rotated_warp_options(orientation),
+            save_path,
         );
 
         match maybe_crop {
-            Ok(crop) => {
-                let mut blob_save_path = self.save_path.clone();
-                blob_save_path.push("blobs.png");
-                debug!("Blobs Save path {:#?}", blob_save_path);
-
-                self.background_fitter = Some(BackgroundFitter::new(
-                    &crop,
-                    blob_save_path.into_os_string().into_string().unwrap(),
-                ));
-                return true;
+            Ok((crop, transform)) => {
+                self.scale = None;
+                self.set_plate_warp(transform, orientation);
+                self.fit_crop(&crop);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Warps the plate to its physical size, so blobs can be measured in millimeters
+    fn warp_plate_metric(
+        &mut self,
+        coords: &[f32],
+        orientation: u32,
+        width_mm: f32,
+        height_mm: f32,
+        pixels_per_mm: f32,
+    ) -> Result<(), String> {
+        let plate = Quad::from_corner_vec(coords)?;
+        let save_path = self.warp_save_path();
+
+        let (crop, scale, transform) = tlc_plate_extraction::unwarp_crop_metric(
+            &self.rotated_input(orientation),
+            &plate,
+            &PlateSize::new(width_mm, height_mm),
+            pixels_per_mm,
+            &self.rotated_warp_options(orientation),
+            save_path,
+        )?;
+        self.scale = Some(scale);
+        self.set_plate_warp(transform, orientation);
+        self.fit_crop(&crop);
+        Ok(())
+    }
+
+    fn set_plate_warp(&mut self, transform: PlateTransform, orientation: u32) {
+        self.previous_plate_warp = self.plate_warp.replace(PlateWarp {
+            transform,
+            orientation,
+        });
+    }
+
+    /// Maps blobs as returned by `detect_blobs` from the crop onto the input photo
+    fn blobs_to_photo(&self, blobs: &[i32]) -> Result<Vec<i32>, String> {
+        let warp = self
+            .plate_warp
+            .ok_or_else(|| "The plate was not warped".to_string())?;
+        let size = (self.input.width(), self.input.height());
+        map_blobs(blobs, |blob| warp.circle_to_input(blob, size))
+    }
+
+    /// Maps blobs of the previous crop into the current one, e.g. after correcting the corners
+    fn blobs_from_previous_warp(&self, blobs: &[i32]) -> Result<Vec<i32>, String> {
+        match (self.previous_plate_warp, self.plate_warp) {
+            (Some(previous), Some(current)) => {
+                let size = (self.input.width(), self.input.height());
+                map_blobs(blobs, |blob| {
+                    current.circle_from_input(&previous.circle_to_input(blob, size), size)
+                })
             }
-            _ => return false,
+            _ => Err("The plate was not warped twice".to_string()),
+        }
+    }
+
+    /// Pixels per mm of the warped plate, if it was warped to its physical size
+    fn plate_scale(&self) -> Option<f64> {
+        self.scale.map(|scale| scale.pixels_per_mm as f64)
+    }
+
+    /// Converts blobs as returned by `detect_blobs` to mm from the top left plate corner
+    fn blobs_in_mm(&self, blobs: &[i32]) -> Result<Vec<f32>, String> {
+        let scale = self
+            .scale
+            .ok_or_else(|| "The plate was not warped to its physical size".to_string())?;
+        check_blobs(blobs)?;
+        Ok(blobs
+            .chunks(4)
+            .flat_map(|blob| {
+                let circle = scale.circle_to_mm(&Circle::from_simple_vec(blob[1..].to_vec()));
+                vec![
+                    blob[0] as f32,
+                    circle.center.x,
+                    circle.center.y,
+                    circle.radius,
+                ]
+            })
+            .collect())
+    }
+
+    fn warp_save_path(&self) -> String {
+        let mut save_path = self.save_path.clone();
+        save_path.push("warped.png");
+        debug!("Warp Save path {:#?}", save_path);
+        save_path.into_os_string().into_string().unwrap()
+    }
+
+    fn rotated_input(&self, orientation: u32) -> DynamicImage {
+        rotated(&self.input, orientation)
+    }
+
+    /// Measures the lighting of the capture setup on a photo of a blank plate, warped with
+    /// the same corners as the plates. `dark_path` may be empty if there is no dark frame.
+    /// The profile is stored at `profile_path` for later sessions.
+    fn create_flat_field(
+        &mut self,
+        blank_path: String,
+        dark_path: String,
+        coords: &[f32],
+        orientation: u32,
+        profile_path: String,
+    ) -> Result<(), String> {
+        let plate = Quad::from_corner_vec(coords)?;
+        let blank =
+            self.warp_reference(&blank_path, &plate, orientation, "flat_field_blank.png")?;
+        let dark = if dark_path.is_empty() {
+            None
+        } else {
+            Some(self.warp_reference(&dark_path, &plate, orientation, "flat_field_dark.png")?)
+        };
+
+        let flat_field = FlatField::from_reference(&blank, dark.as_ref())?;
+        flat_field.save(&profile_path)?;
+        self.flat_field = Some(flat_field);
+        Ok(())
+    }
+
+    /// Loads a flat field stored by `create_flat_field`, it applies to the next warps
+    fn load_flat_field(&mut self, profile_path: String) -> Result<(), String> {
+        self.flat_field = Some(FlatField::load(&profile_path)?);
+        Ok(())
+    }
+
+    /// Fits the colors of a card with `columns` x `rows` patches inside `card` of the input
+    /// photo to the sRGB `references`, three values per patch row by row. The calibration
+    /// applies to the next warps. Returns the remaining RMS error in 8 bit values.
+    fn calibrate_colors(
+        &mut self,
+        card: &[f32],
+        columns: u32,
+        rows: u32,
+        references: &[f32],
+    ) -> Result<f32, String> {
+        let references: Vec<[f32; 3]> = references
+            .chunks_exact(3)
+            .map(|c| [c[0], c[1], c[2]])
+            .collect();
+        let target = ColorTarget::grid(
+            &Quad::from_corner_vec(card)?,
+            columns as usize,
+            rows as usize,
+            &references,
+        )?;
+        let calibration = ColorCalibration::from_target(&self.input.to_rgb8(), &target)?;
+        info!("Color calibration residual {}", calibration.residual);
+
+        let residual = calibration.residual;
+        self.color_calibration = Some(calibration);
+        Ok(residual)
+    }
+
+    fn warp_reference(
+        &self,
+        path: &str,
+        plate: &Quad,
+        orientation: u32,
+        name: &str,
+    ) -> Result<DynamicImage, String> {
+        let (image, _) = read_image(path.to_string())
+            .map_err(|e| format!("Reading the reference {} failed: {}", path, e))?;
+        let mut save_path = self.save_path.clone();
+        save_path.push(name);
+        let (crop, _) = tlc_plate_extraction::unwarp_crop(
+            &rotated(&image, orientation),
+            plate,
+            &self.rotated_warp_options(orientation),
+            save_path.into_os_string().into_string().unwrap(),
+        )?;
+        Ok(crop)
+    }
+
+    fn fit_crop(&mut self, crop: &DynamicImage) {
+        let mut blob_save_path = self.save_path.clone();
+        blob_save_path.push("blobs.png");
+        debug!("Blobs Save path {:#?}", blob_save_path);
+
+        let quality = QualityReport::assess(crop, &self.quality_thresholds);
+        info!("Plate quality {:?}", quality);
+        self.quality = Some(quality);
+
+        let corrected = self.corrected_crop(crop);
+        self.replicates = vec![corrected.clone()];
+        // Brackets of this plate are fused in units of this photo
+        self.exposures = Exposure::from_metadata(corrected.to_luma8(), &self.metadata)
+            .into_iter()
+            .collect();
+        self.fused_exposures = false;
+        self.background_fitter = Some(BackgroundFitter::new(
+            &corrected,
+            blob_save_path.into_os_string().into_string().unwrap(),
+        ));
+    }
+
+    /// The crop with the flat field and the color calibration applied
+    fn corrected_crop(&self, crop: &DynamicImage) -> DynamicImage {
+        let mut corrected = self
+            .flat_field
+            .as_ref()
+            .map(|flat_field| flat_field.apply(crop));
+        if let Some(calibration) = &self.color_calibration {
+            corrected = Some(calibration.apply(corrected.as_ref().unwrap_or(crop)));
+        }
+        corrected.unwrap_or_else(|| crop.clone())
+    }
+
+    /// Adds another exposure of the warped plate, warped with its own corners `coords`.
+    /// Both photos need an exposure time in their metadata.
+    fn add_exposure(
+        &mut self,
+        path: String,
+        coords: &[f32],
+        orientation: u32,
+    ) -> Result<(), String> {
+        if self.exposures.is_empty() {
+            return Err("The warped plate has no exposure time".to_string());
+        }
+        let name = format!("exposure_{}.png", self.exposures.len());
+        let (crop, metadata) = self.warp_like_plate(&path, coords, orientation, &name)?;
+        let exposure = Exposure::from_metadata(crop.to_luma8(), &metadata)?;
+        self.exposures.push(exposure);
+        Ok(())
+    }
+
+    /// Warps another photo of the plate with its own corners to the size of the warped plate
+    /// and applies the same corrections
+    fn warp_like_plate(
+        &self,
+        path: &str,
+        coords: &[f32],
+        orientation: u32,
+        name: &str,
+    ) -> Result<(DynamicImage, CaptureMetadata), String> {
+        let size = match self.replicates.first() {
+            Some(first) => (first.width(), first.height()),
+            None => return Err("Plane warping failed!".to_string()),
+        };
+        let (image, metadata) = read_image(path.to_string())
+            .map_err(|e| format!("Reading the photo {} failed: {}", path, e))?;
+        let mut save_path = self.save_path.clone();
+        save_path.push(name);
+
+        let (crop, _) = tlc_plate_extraction::unwarp_crop_sized(
+            &rotated(&image, orientation),
+            &Quad::from_corner_vec(coords)?,
+            size,
+            &self.rotated_warp_options(orientation),
+            save_path.into_os_string().into_string().unwrap(),
+        )?;
+        Ok((self.corrected_crop(&crop), metadata))
+    }
+
+    /// Adds a replicate photo of the warped plate, warped with its own corners `coords`
+    fn add_replicate(
+        &mut self,
+        path: String,
+        coords: &[f32],
+        orientation: u32,
+    ) -> Result<(), String> {
+        let name = format!("replicate_{}.png", self.replicates.len());
+        let (crop, _) = self.warp_like_plate(&path, coords, orientation, &name)?;
+        self.replicates.push(crop);
+        Ok(())
+    }
+
+    /// Fits the background to the mean of the warped plate and its replicates
+    fn average_replicates(&mut self) -> Result<(), String> {
+        let average = tlc_plate_extraction::average_crops(&self.replicates)?;
+        let mut blob_save_path = self.save_path.clone();
+        blob_save_path.push("blobs.png");
+        self.background_fitter = Some(BackgroundFitter::new(
+            &average,
+            blob_save_path.into_os_string().into_string().unwrap(),
+        ));
+        self.fused_exposures = false;
+        Ok(())
+    }
+
+    /// Analyzes the warped plate and each replicate separately with the same blobs.
+    /// Returns id, mean, SD and RSD of the integral and of the percentage per spot, sorted
+    /// by id. The percentages are NaN without reference percentages.
+    fn replicate_statistics(
+        &self,
+        blobs: &[i32],
+        dark_blobs: bool,
+        cut_off_percentage: f32,
+        key_percentage: &[f32],
+    ) -> Result<Vec<f32>, String> {
+        let blob_map = parse_blobs(blobs)?;
+        let references = parse_percentages(key_percentage);
+
+        let mut integrals: Vec<HashMap<u32, f64>> = vec![];
+        let mut percentages: Vec<HashMap<u32, f64>> = vec![];
+        for (i, crop) in self.replicates.iter().enumerate() {
+            let mut save_path = self.save_path.clone();
+            save_path.push(format!("replicate_{}_blobs.png", i));
+            let cleaned =
+                BackgroundFitter::new(crop, save_path.into_os_string().into_string().unwrap())
+                    .remove_background(dark_blobs)?;
+            let integrated =
+                tlc_blob_integration::integrate_spots(&cleaned, &blob_map, cut_off_percentage);
+
+            if !references.is_empty() {
+                let fitter = ReferencePercentFitter::new(&integrated, &references);
+                percentages.push(
+                    fitter
+                        .evaluate(&integrated)
+                        .iter()
+                        .map(|(k, v)| (*k, *v as f64))
+                        .collect(),
+                );
+            }
+            integrals.push(integrated.iter().map(|(k, v)| (*k, *v as f64)).collect());
+        }
+
+        let integral_stats = ReplicateStatistics::per_spot(&integrals);
+        if integral_stats.is_empty() {
+            return Err("At least two replicates are needed".to_string());
+        }
+        let percentage_stats = ReplicateStatistics::per_spot(&percentages);
+        let stats_values = |stats: Option<&ReplicateStatistics>| match stats {
+            Some(s) => vec![s.mean as f32, s.sd as f32, s.rsd as f32],
+            None => vec![f32::NAN; 3],
+        };
+        let mut integral_stats: Vec<(&u32, &ReplicateStatistics)> = integral_stats.iter().collect();
+        integral_stats.sort_by_key(|(key, _)| **key);
+        Ok(integral_stats
+            .into_iter()
+            .flat_map(|(key, stats)| {
+                let mut values = vec![*key as f32];
+                values.extend(stats_values(Some(stats)));
+                values.extend(stats_values(percentage_stats.get(key)));
+                values
+            })
+            .collect())
+    }
+
+    /// Fuses the warped plate and the added exposures, aligned by up to `max_shift` pixels.
+    /// The background is fitted to the fused plate.
+    fn fuse_exposures(&mut self, max_shift: u32) -> Result<(), String> {
+        let fused = tlc_common::fuse_exposures(&self.exposures, max_shift)?;
+        let mut blob_save_path = self.save_path.clone();
+        blob_save_path.push("blobs.png");
+        self.background_fitter = Some(BackgroundFitter::from_hdr(
+            &fused,
+            blob_save_path.into_os_string().into_string().unwrap(),
+        ));
+        self.fused_exposures = true;
+        Ok(())
+    }
+
+    fn set_quality_thresholds(
+        &mut self,
+        min_sharpness: f32,
+        max_overexposed: f32,
+        max_underexposed: f32,
+        max_glare: f32,
+        max_motion_blur: f32,
+    ) {
+        self.quality_thresholds = QualityThresholds {
+            min_sharpness,
+            max_overexposed,
+            max_underexposed,
+            max_glare,
+            max_motion_blur,
+        };
+    }
+
+    /// Codes of the quality problems of the last warped plate, empty if it is fine to process
+    fn quality_warnings(&self) -> Result<Vec<String>, String> {
+        match &self.quality {
+            Some(quality) => Ok(quality
+                .warnings
+                .iter()
+                .map(|warning| warning.code().to_string())
+                .collect()),
+            None => Err("Plane warping failed!".to_string()),
+        }
+    }
+
+    /// Sharpness, overexposed, underexposed and glare fractions and the motion blur
+    fn quality_scores(&self) -> Result<Vec<f32>, String> {
+        match &self.quality {
+            Some(q) => Ok(vec![
+                q.sharpness,
+                q.overexposed,
+                q.underexposed,
+                q.glare,
+                q.motion_blur,
+            ]),
+            None => Err("Plane warping failed!".to_string()),
         }
     }
 
@@ -96,12 +865,28 @@ impl TlcProcessor {
 
     fn fit_background(&mut self, dark_blobs: bool) -> Result<(), String> {
         match &self.background_fitter {
+            Some(fitter) if self.fused_exposures => {
+                let cleaned = fitter.remove_background_hdr(dark_blobs)?;
+                // Blob detection works on 8 bit, so the brightest blob is scaled to 255
+                let max = cleaned.iter().cloned().fold(f64::EPSILON, f64::max);
+                let scaled = GrayImage::from_fn(cleaned.width(), cleaned.height(), |x, y| {
+                    Luma([attenuate_generic(cleaned.get_pixel(x, y)[0] / max * 255.0)])
+                });
+
+                self.background_removed = Some(DynamicImage::ImageLuma8(scaled));
+                self.background_removed_hdr = Some(cleaned);
+                self.detected_blobs = None;
+
+                Ok(())
+            }
             Some(fitter) => {
                 let cleaned = fitter
                     .remove_background(dark_blobs)
                     .expect("Removing background failed");
 
                 self.background_removed = Some(DynamicImage::ImageLuma8(cleaned));
+                self.background_removed_hdr = None;
+                self.detected_blobs = None;
 
                 Ok(())
             }
@@ -109,25 +894,174 @@ impl TlcProcessor {
         }
     }
 
-    fn detect_blobs(&self) -> Result<Vec<i32>, String> {
+    /// Expected lanes for `detect_blobs`, spacing and baseline as fractions of the crop width
+    /// and height. No lanes turn the prior off.
+    fn set_lane_layout(&mut self, lanes: u32, spacing: f32, baseline: f32) {
+        self.lane_layout = if lanes > 0 {
+            Some(LanePrior::new(lanes, spacing, baseline))
+        } else {
+            None
+        };
+    }
+
+    /// Detects blobs with the default settings
+    fn detect_blobs(&mut self) -> Result<Vec<i32>, String> {
+        let params = BlobDetectionParams::builder()
+            .layout(self.lane_layout)
+            .build()?;
+        self.detect_blobs_with(&params)
+    }
+
+    /// Detects blobs with the given settings, so the app can offer presets for micro spots
+    /// or bands. `threshold` is mean, otsu or adaptive, `watershed` none, distance or
+    /// intensity, sizes and the opening radius are fractions of the larger crop side.
+    #[allow(clippy::too_many_arguments)]
+    fn detect_blobs_with_params(
+        &mut self,
+        threshold: String,
+        min_size: f32,
+        max_size: f32,
+        aspect_ratio_tolerance: f32,
+        opening_radius: f32,
+        eight_connected: bool,
+        watershed: String,
+    ) -> Result<Vec<i32>, String> {
+        let watershed = match watershed.to_lowercase().as_str() {
+            "none" => None,
+            "distance" => Some(WatershedSeeds::DistanceTransform),
+            "intensity" => Some(WatershedSeeds::IntensityMaxima),
+            _ => return Err(format!("Unknown watershed seeds {}", watershed)),
+        };
+        let params = BlobDetectionParams::builder()
+            .threshold(ThresholdMethod::from_name(&threshold)?)
+            .size(min_size, max_size)
+            .aspect_ratio_tolerance(aspect_ratio_tolerance)
+            .opening_radius(opening_radius)
+            .connectivity(if eight_connected {
+                Connectivity::Eight
+            } else {
+                Connectivity::Four
+            })
+            .watershed(watershed)
+            .layout(self.lane_layout)
+            .build()?;
+        self.detect_blobs_with(&params)
+    }
+
+    fn detect_blobs_with(&mut self, params: &BlobDetectionParams) -> Result<Vec<i32>, String> {
         match &self.background_removed {
             Some(cleaned) => {
-                let blobs = tlc_blob_detection::detect_blobs(&cleaned.to_luma8());
+                let blobs = ThresholdDetector::new(*params).detect(&cleaned.to_luma8());
                 let ret: Vec<i32> = blobs
                     .iter()
-                    .map(|(k, v)| {
-                        let mut coord_vec = v.to_simple_vec();
+                    .flat_map(|(k, v)| {
+                        let mut coord_vec = v.circle.to_simple_vec();
                         coord_vec.insert(0, *k as i32);
                         coord_vec
                     })
-                    .flatten()
                     .collect();
+                self.detected_blobs = Some(blobs);
                 Ok(ret)
             }
             None => Err("Background removal failed".to_string()),
         }
     }
 
+    /// Shape and intensity descriptors of blobs as returned by `detect_blobs`, per blob the
+    /// id, area, perimeter, circularity, eccentricity, orientation, mean and max intensity,
+    /// signal to background ratio and tailing factor. Blobs of the last detection keep the
+    /// descriptors of their labeled component, moved or added blobs are measured in their circle.
+    fn describe_blobs(&self, blobs: &[i32]) -> Result<Vec<f32>, String> {
+        let cleaned = self
+            .background_removed
+            .as_ref()
+            .ok_or_else(|| "Background removal failed".to_string())?
+            .to_luma8();
+        let mut blob_map: Vec<(u32, Circle)> = parse_blobs(blobs)?.into_iter().collect();
+        blob_map.sort_by_key(|(key, _)| *key);
+        Ok(blob_map
+            .iter()
+            .flat_map(|(key, circle)| {
+                let detected = self
+                    .detected_blobs
+                    .as_ref()
+                    .and_then(|detected| detected.get(key))
+                    .filter(|blob| blob.circle.to_simple_vec() == circle.to_simple_vec());
+                let mut values = match detected {
+                    Some(blob) => blob.descriptors,
+                    None => BlobDescriptors::within_circle(&cleaned, circle),
+                }
+                .to_vec();
+                values.insert(0, *key as f32);
+                values
+            })
+            .collect())
+    }
+
+    /// Compares the spots of each lane to its strongest spot, per spot the lane index, id,
+    /// x, y, intensity in percent of the main spot and 1 for a potential impurity, else 0
+    fn analyse_purity(&self, blobs: &[i32], impurity_threshold: f32) -> Result<Vec<f32>, String> {
+        let cleaned = self
+            .background_removed
+            .as_ref()
+            .ok_or_else(|| "Background removal failed".to_string())?
+            .to_luma8();
+        let lanes = tlc_blob_integration::analyse_purity(
+            &cleaned,
+            &parse_blobs(blobs)?,
+            impurity_threshold,
+        );
+        Ok(lanes
+            .iter()
+            .enumerate()
+            .flat_map(|(lane, purity)| {
+                std::iter::once(&purity.main)
+                    .chain(purity.secondary.iter())
+                    .flat_map(move |spot| {
+                        vec![
+                            lane as f32,
+                            spot.id as f32,
+                            spot.circle.center.x,
+                            spot.circle.center.y,
+                            spot.relative_intensity,
+                            if spot.impurity { 1.0 } else { 0.0 },
+                        ]
+                    })
+            })
+            .collect())
+    }
+
+    /// Integrates blobs weighting each pixel by its area inside the blob, as id and integral
+    fn integrate_blobs_subpixel(&self, blobs: &[i32]) -> Result<Vec<f32>, String> {
+        let spots: HashMap<u32, Ellipse> = parse_blobs(blobs)?
+            .into_iter()
+            .map(|(key, circle)| (key, Ellipse::from(circle)))
+            .collect();
+        self.integrate_subpixel(&spots)
+    }
+
+    /// Like `integrate_blobs_subpixel`, for elliptic spots given as id, x, y, x radius,
+    /// y radius and angle in radians
+    fn integrate_ellipses_subpixel(&self, spots: &[f32]) -> Result<Vec<f32>, String> {
+        self.integrate_subpixel(&parse_ellipses(spots)?)
+    }
+
+    fn integrate_subpixel(&self, spots: &HashMap<u32, Ellipse>) -> Result<Vec<f32>, String> {
+        let integrated = match (&self.background_removed_hdr, &self.background_removed) {
+            (Some(hdr), _) => tlc_blob_integration::integrate_spots_subpixel(hdr, spots),
+            (None, Some(cleaned)) => {
+                tlc_blob_integration::integrate_spots_subpixel(&cleaned.to_luma8(), spots)
+            }
+            (None, None) => return Err("Background removal failed".to_string()),
+        };
+        let mut ret: Vec<(u32, f64)> = integrated.into_iter().collect();
+        ret.sort_by_key(|(key, _)| *key);
+        Ok(ret
+            .into_iter()
+            .flat_map(|(key, value)| vec![key as f32, value as f32])
+            .collect())
+    }
+
     fn integrate_blobs(
         &mut self,
         blobs: &[i32],
@@ -135,27 +1069,25 @@ impl TlcProcessor {
     ) -> Result<Vec<i32>, String> {
         match &self.background_removed {
             Some(cleaned) => {
-                let blob_chunks = blobs.chunks(4);
-
-                let mut blob_map: HashMap<u32, Circle> = HashMap::new();
-                for chunk in blob_chunks {
-                    let blob: Vec<i32> = chunk.into_iter().map(|v| *v).collect();
-                    let key = blob[0];
-                    let circle = Circle::from_simple_vec(blob[1..].to_vec());
-                    blob_map.insert(key as u32, circle);
-                }
+                let blob_map = parse_blobs(blobs)?;
 
-                let integrated = tlc_blob_integration::integrate_spots(
-                    &cleaned.to_luma8(),
-                    &blob_map,
-                    cut_off_percentage,
-                );
+                let integrated = match &self.background_removed_hdr {
+                    Some(hdr) => tlc_blob_integration::integrate_spots_hdr(
+                        hdr,
+                        &blob_map,
+                        cut_off_percentage,
+                    ),
+                    None => tlc_blob_integration::integrate_spots(
+                        &cleaned.to_luma8(),
+                        &blob_map,
+                        cut_off_percentage,
+                    ),
+                };
                 self.integrated_blobs = Some(integrated.clone());
 
                 let ret: Vec<i32> = integrated
                     .iter()
-                    .map(|(k, v)| vec![*k as i32, *v as i32])
-                    .flatten()
+                    .flat_map(|(k, v)| vec![*k as i32, *v as i32])
                     .collect();
                 Ok(ret)
             }
@@ -163,17 +1095,31 @@ impl TlcProcessor {
         }
     }
 
+    /// Fraction of each blob beyond the plate, as id and fraction
+    fn blobs_outside(&self, blobs: &[i32]) -> Result<Vec<f32>, String> {
+        let dimensions = match &self.background_removed {
+            Some(cleaned) => (cleaned.width(), cleaned.height()),
+            None => return Err("Background removal failed".to_string()),
+        };
+        let mut ret: Vec<(u32, f32)> = parse_blobs(blobs)?
+            .iter()
+            .map(|(key, circle)| {
+                let outside = tlc_blob_integration::fraction_outside(dimensions, circle);
+                (*key, outside)
+            })
+            .collect();
+        ret.sort_by_key(|(key, _)| *key);
+        Ok(ret
+            .into_iter()
+            .flat_map(|(key, outside)| vec![key as f32, outside])
+            .collect())
+    }
+
+    #[allow(clippy::map_flatten, clippy::needless_borrow, clippy::unnecessary_cast)]
     fn fit_percentages(&self, key_percentage: &[f32]) -> Result<Vec<f32>, String> {
         match &self.integrated_blobs {
             Some(integrants) => {
-                let perc_chunks = key_percentage.chunks(2);
-                let mut perc_map: HashMap<u32, f32> = HashMap::new();
-                for chunk in perc_chunks {
-                    let key_perc: Vec<f32> = chunk.into_iter().map(|v| *v).collect();
-                    let key = key_perc[0] as u32;
-                    let perc = key_perc[1] as f32;
-                    perc_map.insert(key, perc);
-                }
+                let perc_map = parse_percentages(key_percentage);
 
                 let perc_fitter = ReferencePercentFitter::new(&integrants, &perc_map);
                 let percentages = perc_fitter.evaluate(&integrants);
@@ -191,6 +1137,57 @@ impl TlcProcessor {
     }
 }
 
+struct PreviewTracker {
+    tracker: PlateTracker,
+    state: Option<TrackingState>,
+}
+
+impl PreviewTracker {
+    fn new() -> Self {
+        PreviewTracker {
+            tracker: PlateTracker::default(),
+            state: None,
+        }
+    }
+
+    /// Tracks the plate on the Y plane of a preview frame and returns the smoothed corners
+    fn track_frame(
+        &mut self,
+        y_plane: DirectByteBuffer,
+        width: i32,
+        height: i32,
+        row_stride: i32,
+    ) -> Result<Vec<f32>, String> {
+        let state = self.tracker.update_luma(
+            y_plane.as_slice()?,
+            positive(width, "width")?,
+            positive(height, "height")?,
+            positive(row_stride, "row stride")? as usize,
+        )?;
+        self.state = Some(state);
+
+        Ok(state.quad.map_or(vec![], |quad| quad.to_corner_vec()))
+    }
+
+    fn stability(&self) -> f32 {
+        self.state.map_or(0.0, |state| state.stability)
+    }
+
+    fn is_fully_visible(&self) -> bool {
+        self.state.is_some_and(|state| state.fully_visible)
+    }
+
+    fn is_steady(&self, min_stability: f32) -> bool {
+        self.state
+            .is_some_and(|state| state.is_steady(min_stability))
+    }
+
+    fn reset(&mut self) {
+        self.tracker.reset();
+        self.state = None;
+    }
+}
+
 /*
     let mut ref_data = HashMap::new();
     ref_data.insert(left_key, 100u8);
@@ -202,3 +1199,153 @@ impl TlcProcessor {
     println!("Blobs: {:?}", blobs);
     println!("Percentages: {:?}", percentages);
 */
+
+#[cfg(test)]
+mod test {
+    use crate::TlcProcessor;
+    use image::{DynamicImage, GrayImage, Luma};
+    use std::path::PathBuf;
+    use tlc_common::CaptureMetadata;
+
+    #[test]
+    fn test_rotated_plate_keeps_corner_order_from_detection_to_warp() {
+        // A 180 x 300 plate turned by 50 degrees around the image centre
+        let (sin, cos) = 50f32.to_radians().sin_cos();
+        let image = GrayImage::from_fn(512, 512, |x, y| {
+            let (dx, dy) = (x as f32 - 256.0, y as f32 - 256.0);
+            let (u, v) = (dx * cos + dy * sin, dy * cos - dx * sin);
+            Luma([if u.abs() <= 90.0 && v.abs() <= 150.0 {
+                220
+            } else {
+                40
+            }])
+        });
+        let save_path: PathBuf = std::env::temp_dir().join("tlc_jni_rotated_plate");
+        std::fs::create_dir_all(&save_path).unwrap();
+        let mut processor = TlcProcessor::from_image(
+            DynamicImage::ImageLuma8(image),
+            CaptureMetadata::default(),
+            save_path,
+        );
+
+        let detected = processor.detect_plate();
+        let warped = processor.warp_plate(&detected, 0);
+
+        assert_eq!(detected.len(), 8);
+        assert!(warped);
+        // Sorting the corners by y would turn the crop by 90 degrees
+        let side = |a: usize, b: usize| {
+            (detected[2 * a] - detected[2 * b]).hypot(detected[2 * a + 1] - detected[2 * b + 1])
+        };
+        let (width, height) = processor.replicates[0].to_luma8().dimensions();
+        assert!((width as f32 - side(0, 1)).abs() < 3.0, "{:?}", detected);
+        assert!((height as f32 - side(1, 2)).abs() < 3.0, "{:?}", detected);
+    }
+
+    #[test]
+    fn test_describe_blobs_keeps_descriptors_of_detected_components() {
+        // A spot stretched downwards, its circle is round but the component is not
+        let image = GrayImage::from_fn(200, 160, |x, y| {
+            let (dx, dy) = (x as f32 - 100.0, y as f32 - 70.0);
+            let sigma_y = if dy > 0.0 { 10.0 } else { 4.0 };
+            Luma([(200.0 * (-dx * dx / 32.0 - dy * dy / (2.0 * sigma_y * sigma_y)).exp()) as u8])
+        });
+        let mut processor = TlcProcessor::from_image(
+            DynamicImage::ImageLuma8(GrayImage::new(200, 160)),
+            CaptureMetadata::default(),
+            std::env::temp_dir(),
+        );
+        processor.background_removed = Some(DynamicImage::ImageLuma8(image));
+
+        let blobs = processor.detect_blobs().unwrap();
+        let when = processor.describe_blobs(&blobs).unwrap();
+        let mut moved = blobs.clone();
+        moved[1] += 1;
+        let within_circle = processor.describe_blobs(&moved).unwrap();
+
+        assert_eq!(blobs.len(), 4);
+        // Eccentricity after the id, area, perimeter and circularity
+        assert!(when[4] > 0.6, "{:?}", when);
+        assert!(within_circle[4] < 0.3, "{:?}", within_circle);
+    }
+
+    #[test]
+    fn test_rejects_non_positive_frame_sizes() {
+        let save_dir = std::env::temp_dir().to_string_lossy().to_string();
+
+        assert!(TlcProcessor::from_nv21(&[0; 24], -4, 4, save_dir.clone()).is_err());
+        assert!(TlcProcessor::from_nv21(&[0; 24], 4, 0, save_dir.clone()).is_err());
+        assert!(TlcProcessor::from_nv21(&[0; 24], 4, 4, save_dir).is_ok());
+    }
+
+    #[test]
+    fn test_rejects_incomplete_blobs() {
+        let mut processor = TlcProcessor::from_image(
+            DynamicImage::ImageLuma8(GrayImage::new(20, 20)),
+            CaptureMetadata::default(),
+            std::env::temp_dir(),
+        );
+        processor.background_removed = Some(DynamicImage::ImageLuma8(GrayImage::new(20, 20)));
+
+        assert!(processor.describe_blobs(&[1, 10, 10, 3]).is_ok());
+        assert!(processor.describe_blobs(&[1, 10, 10]).is_err());
+        assert!(processor.integrate_blobs(&[1, 10, 10, 3, 2], 1.0).is_err());
+    }
+
+    #[test]
+    fn test_integrates_ellipses_like_circles() {
+        let mut processor = TlcProcessor::from_image(
+            DynamicImage::ImageLuma8(GrayImage::new(40, 40)),
+            CaptureMetadata::default(),
+            std::env::temp_dir(),
+        );
+        processor.background_removed = Some(DynamicImage::ImageLuma8(GrayImage::from_pixel(
+            40,
+            40,
+            Luma([1]),
+        )));
+
+        let circle = processor.integrate_blobs_subpixel(&[1, 20, 20, 5]).unwrap();
+        let ellipse = processor
+            .integrate_ellipses_subpixel(&[1.0, 20.0, 20.0, 5.0, 5.0, 0.0])
+            .unwrap();
+        assert_eq!(circle, ellipse);
+
+        let when = processor
+            .integrate_ellipses_subpixel(&[2.0, 20.0, 20.0, 8.0, 4.0, 0.5])
+            .unwrap();
+        assert_eq!(when[0], 2.0);
+        let expected = std::f32::consts::PI * 8.0 * 4.0;
+        assert!((when[1] - expected).abs() / expected < 0.01, "{:?}", when);
+        assert!(processor
+            .integrate_ellipses_subpixel(&[1.0, 20.0, 20.0, 5.0, 5.0])
+            .is_err());
+    }
+
+    #[test]
+    fn test_rejects_non_finite_marker_layout() {
+        let processor = TlcProcessor::from_image(
+            DynamicImage::ImageLuma8(GrayImage::new(20, 20)),
+            CaptureMetadata::default(),
+            std::env::temp_dir(),
+        );
+        let plate = [0.0, 0.0, 10.0, 0.0, 10.0, 10.0, 0.0, 10.0];
+        let markers = [0.0, 0.0, 0.0, 1.0, 10.0, 0.0, 2.0, 10.0, 10.0];
+
+        for (index, value) in [(1, f32::NAN), (5, f32::INFINITY)] {
+            let mut given = markers;
+            given[index] = value;
+            assert!(processor
+                .detect_plate_from_markers(true, 2.0, &given, &plate)
+                .is_err());
+        }
+        let mut given = plate;
+        given[2] = f32::NAN;
+        assert!(processor
+            .detect_plate_from_markers(true, 2.0, &markers, &given)
+            .is_err());
+        assert!(processor
+            .detect_plate_from_markers(true, f32::NAN, &markers, &plate)
+            .is_err());
+    }
+}
@@ -0,0 +1,746 @@
+use crate::detection_result::{corners, order_corners};
+use crate::homography::{fit_homography, transform_point};
+use image::{GrayImage, ImageBuffer, Luma};
+use na::{DMatrix, DVector, Matrix3, Point2, Rotation3, Vector3};
+use tlc_common::{CameraModel, Distortion};
+
+type FloatImage = ImageBuffer<Luma<f32>, Vec<f32>>;
+
+/// Smoothing of the photo before looking for the saddle points of the corners
+const SADDLE_SIGMA: f32 = 2.0;
+
+/// Radius of the ring around a corner candidate, which has to cross four squares
+const RING_RADIUS: f32 = 3.0 * SADDLE_SIGMA;
+
+/// Window radius of the sub-pixel corner refinement
+const REFINE_RADIUS: i32 = 5;
+
+/// Fewer views cannot separate focal length, principal point and distortion
+const MIN_VIEWS: usize = 3;
+
+const MAX_ITERATIONS: usize = 100;
+
+/// Number of camera parameters: fx, fy, cx, cy, k1, k2, p1, p2, k3
+const CAMERA_PARAMETERS: usize = 9;
+
+/// Number of parameters per view: rotation vector and translation
+const VIEW_PARAMETERS: usize = 6;
+
+/// Checkerboard with `columns` x `rows` inner corners and squares of `square_size`, e.g. in mm
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Checkerboard {
+    pub columns: usize,
+    pub rows: usize,
+    pub square_size: f32,
+}
+
+impl Checkerboard {
+    pub fn new(columns: usize, rows: usize, square_size: f32) -> Self {
+        Checkerboard {
+            columns,
+            rows,
+            square_size,
+        }
+    }
+
+    /// Inner corners on the board, row by row
+    pub fn points(&self) -> Vec<Point2<f32>> {
+        (0..self.rows)
+            .flat_map(|row| {
+                (0..self.columns).map(move |column| {
+                    Point2::new(
+                        column as f32 * self.square_size,
+                        row as f32 * self.square_size,
+                    )
+                })
+            })
+            .collect()
+    }
+
+    /// The outer inner corners in clockwise order
+    fn outer_points(&self) -> [Point2<f32>; 4] {
+        let (right, bottom) = (
+            (self.columns - 1) as f32 * self.square_size,
+            (self.rows - 1) as f32 * self.square_size,
+        );
+        [
+            Point2::new(0.0, 0.0),
+            Point2::new(right, 0.0),
+            Point2::new(right, bottom),
+            Point2::new(0.0, bottom),
+        ]
+    }
+
+    /// Inner corners in the photo in the order of `points`, if all of them are visible.
+    /// The board may be labeled starting from any of its outer corners.
+    pub fn find_corners(&self, image: &GrayImage) -> Option<Vec<Point2<f32>>> {
+        if self.columns < 2 || self.rows < 2 {
+            return None;
+        }
+        let smooth = imageproc::filter::gaussian_blur_f32(&to_float(image), SADDLE_SIGMA);
+        let candidates: Vec<Point2<f32>> = saddle_points(&smooth)
+            .into_iter()
+            .filter(|p| crosses_four_squares(&smooth, p))
+            .map(|p| refine_saddle(&smooth, &p))
+            .collect();
+        if candidates.len() < self.columns * self.rows {
+            return None;
+        }
+
+        let outline = corners(&order_corners(outermost(&candidates)?));
+        let grid = self.points();
+        (0..4)
+            .filter_map(|shift| {
+                let board: Vec<Point2<f32>> = (0..4)
+                    .map(|i| self.outer_points()[(i + shift) % 4])
+                    .collect();
+                self.match_grid(&grid, &board, &outline, &candidates)
+            })
+            .next()
+    }
+
+    /// Assigns candidates to the grid, starting with the homography of the outer corners
+    /// and refitting it with all matches to follow moderate lens distortions
+    fn match_grid(
+        &self,
+        grid: &[Point2<f32>],
+        board: &[Point2<f32>],
+        outline: &[Point2<f32>; 4],
+        candidates: &[Point2<f32>],
+    ) -> Option<Vec<Point2<f32>>> {
+        let mut homography = fit_homography(board, outline)?;
+        let mut matches = vec![];
+        for _ in 0..4 {
+            matches = grid
+                .iter()
+                .map(|p| {
+                    let predicted = transform_point(&homography, p);
+                    let neighbour = Point2::new(p.x + self.square_size, p.y);
+                    let tolerance =
+                        0.35 * na::distance(&predicted, &transform_point(&homography, &neighbour));
+                    candidates
+                        .iter()
+                        .filter(|c| na::distance(c, &predicted) < tolerance)
+                        .min_by(|a, b| {
+                            na::distance(a, &predicted).total_cmp(&na::distance(b, &predicted))
+                        })
+                        .copied()
+                })
+                .collect::<Option<Vec<Point2<f32>>>>()?;
+            homography = fit_homography(grid, &matches)?;
+        }
+
+        let unique = matches
+            .iter()
+            .enumerate()
+            .all(|(i, a)| matches[..i].iter().all(|b| na::distance(a, b) > 1.0));
+        Some(matches).filter(|_| unique)
+    }
+}
+
+/// Result of a camera calibration with the RMS reprojection error in pixels
+#[derive(Debug, Clone, PartialEq)]
+pub struct CameraCalibration {
+    pub camera: CameraModel,
+    pub reprojection_error: f32,
+    pub views: usize,
+}
+
+/// Calibrates the camera from photos of a checkerboard from different angles.
+/// Photos in which the board is not found completely are skipped.
+pub fn calibrate_camera(
+    images: &[GrayImage],
+    board: &Checkerboard,
+) -> Result<CameraCalibration, String> {
+    let size = images
+        .first()
+        .map(|image| image.dimensions())
+        .ok_or_else(|| "No calibration images".to_string())?;
+    if images.iter().any(|image| image.dimensions() != size) {
+        return Err("All calibration images need the same size".to_string());
+    }
+
+    let views: Vec<Vec<Point2<f32>>> = images
+        .iter()
+        .filter_map(|image| board.find_corners(image))
+        .collect();
+    calibrate_from_points(&views, board, size)
+}
+
+/// Calibrates the camera from the checkerboard corners found in each view
+pub fn calibrate_from_points(
+    views: &[Vec<Point2<f32>>],
+    board: &Checkerboard,
+    (width, height): (u32, u32),
+) -> Result<CameraCalibration, String> {
+    if views.len() < MIN_VIEWS {
+        return Err(format!(
+            "The checkerboard was found in {} images, at least {} are needed",
+            views.len(),
+            MIN_VIEWS
+        ));
+    }
+    let grid = board.points();
+    if views.iter().any(|view| view.len() != grid.len()) {
+        return Err("Each view needs all checkerboard corners".to_string());
+    }
+
+    let homographies = views
+        .iter()
+        .map(|view| fit_homography(&grid, view).map(|h| h.cast::<f64>()))
+        .collect::<Option<Vec<Matrix3<f64>>>>()
+        .ok_or_else(|| "The checkerboard corners are degenerate".to_string())?;
+    let center = ((width as f64 - 1.0) / 2.0, (height as f64 - 1.0) / 2.0);
+    let focal = initial_focal_lengths(&homographies, center)?;
+
+    let mut parameters = vec![
+        focal.0, focal.1, center.0, center.1, 0.0, 0.0, 0.0, 0.0, 0.0,
+    ];
+    for homography in homographies.iter() {
+        parameters.extend_from_slice(&initial_pose(homography, focal, center));
+    }
+
+    let model = Reprojection {
+        grid: grid
+            .iter()
+            .map(|p| Vector3::new(p.x as f64, p.y as f64, 0.0))
+            .collect(),
+        views: views
+            .iter()
+            .map(|view| view.iter().map(|p| (p.x as f64, p.y as f64)).collect())
+            .collect(),
+    };
+    let parameters = model.optimize(DVector::from_vec(parameters));
+    let residuals = model.residuals(&parameters);
+    let point_count = (grid.len() * views.len()) as f64;
+
+    let p = |i: usize| parameters[i] as f32;
+    Ok(CameraCalibration {
+        camera: CameraModel::new(
+            (width, height),
+            (p(0), p(1)),
+            (p(2), p(3)),
+            Distortion {
+                k1: p(4),
+                k2: p(5),
+                p1: p(6),
+                p2: p(7),
+                k3: p(8),
+            },
+        ),
+        reprojection_error: (residuals.norm_squared() / point_count).sqrt() as f32,
+        views: views.len(),
+    })
+}
+
+/// Focal lengths from the orthogonality of the rotation columns in each homography,
+/// assuming the principal point at the image center
+fn initial_focal_lengths(
+    homographies: &[Matrix3<f64>],
+    (cx, cy): (f64, f64),
+) -> Result<(f64, f64), String> {
+    let mut normal = na::Matrix2::<f64>::zeros();
+    let mut rhs = na::Vector2::<f64>::zeros();
+    for h in homographies {
+        let column = |i: usize| {
+            (
+                h[(0, i)] - cx * h[(2, i)],
+                h[(1, i)] - cy * h[(2, i)],
+                h[(2, i)],
+            )
+        };
+        let ((a1, b1, c1), (a2, b2, c2)) = (column(0), column(1));
+        // Unknowns are 1/fx² and 1/fy²
+        let rows = [
+            (a1 * a2, b1 * b2, -c1 * c2),
+            (a1 * a1 - a2 * a2, b1 * b1 - b2 * b2, c2 * c2 - c1 * c1),
+        ];
+        for (u, v, r) in rows.iter() {
+            let row = na::Vector2::new(*u, *v);
+            normal += row * row.transpose();
+            rhs += row * *r;
+        }
+    }
+
+    let solution = normal
+        .try_inverse()
+        .map(|inverse| inverse * rhs)
+        .filter(|s| s.x > 0.0 && s.y > 0.0)
+        .ok_or_else(|| {
+            "The checkerboard needs to be tilted differently in each image".to_string()
+        })?;
+    Ok((1.0 / solution.x.sqrt(), 1.0 / solution.y.sqrt()))
+}
+
+/// Rotation vector and translation of the board from its homography
+fn initial_pose(homography: &Matrix3<f64>, (fx, fy): (f64, f64), (cx, cy): (f64, f64)) -> [f64; 6] {
+    let camera = Matrix3::new(fx, 0.0, cx, 0.0, fy, cy, 0.0, 0.0, 1.0);
+    let m = camera.try_inverse().unwrap() * homography;
+    let (c1, c2, c3): (Vector3<f64>, Vector3<f64>, Vector3<f64>) =
+        (m.column(0).into(), m.column(1).into(), m.column(2).into());
+
+    let mut scale = 2.0 / (c1.norm() + c2.norm());
+    // The board lies in front of the camera
+    if c3.z < 0.0 {
+        scale = -scale;
+    }
+    let (r1, r2, t) = (c1 * scale, c2 * scale, c3 * scale);
+    let rotation = Rotation3::from_matrix(&Matrix3::from_columns(&[r1, r2, r1.cross(&r2)]));
+    let axis = rotation.scaled_axis();
+    [axis.x, axis.y, axis.z, t.x, t.y, t.z]
+}
+
+/// Reprojection of the board into all views for the Levenberg–Marquardt refinement
+struct Reprojection {
+    grid: Vec<Vector3<f64>>,
+    views: Vec<Vec<(f64, f64)>>,
+}
+
+impl Reprojection {
+    fn residuals(&self, parameters: &DVector<f64>) -> DVector<f64> {
+        let p = parameters.as_slice();
+        let (fx, fy, cx, cy) = (p[0], p[1], p[2], p[3]);
+        let (k1, k2, p1, p2, k3) = (p[4], p[5], p[6], p[7], p[8]);
+
+        let mut residuals = Vec::with_capacity(2 * self.grid.len() * self.views.len());
+        for (v, view) in self.views.iter().enumerate() {
+            let pose = &p[CAMERA_PARAMETERS + v * VIEW_PARAMETERS..];
+            let rotation = Rotation3::new(Vector3::new(pose[0], pose[1], pose[2]));
+            let translation = Vector3::new(pose[3], pose[4], pose[5]);
+
+            for (point, observed) in self.grid.iter().zip(view.iter()) {
+                let camera = rotation * point + translation;
+                let (x, y) = (camera.x / camera.z, camera.y / camera.z);
+                let r2 = x * x + y * y;
+                let radial = 1.0 + r2 * (k1 + r2 * (k2 + r2 * k3));
+                let xd = x * radial + 2.0 * p1 * x * y + p2 * (r2 + 2.0 * x * x);
+                let yd = y * radial + p1 * (r2 + 2.0 * y * y) + 2.0 * p2 * x * y;
+                residuals.push(fx * xd + cx - observed.0);
+                residuals.push(fy * yd + cy - observed.1);
+            }
+        }
+        DVector::from_vec(residuals)
+    }
+
+    /// Central difference Jacobian of the residuals
+    fn jacobian(&self, parameters: &DVector<f64>) -> DMatrix<f64> {
+        let rows = 2 * self.grid.len() * self.views.len();
+        let mut jacobian = DMatrix::zeros(rows, parameters.len());
+        for j in 0..parameters.len() {
+            let step = 1e-6 * parameters[j].abs().max(1.0);
+            let mut forward = parameters.clone();
+            forward[j] += step;
+            let mut backward = parameters.clone();
+            backward[j] -= step;
+            let column = (self.residuals(&forward) - self.residuals(&backward)) / (2.0 * step);
+            jacobian.set_column(j, &column);
+        }
+        jacobian
+    }
+
+    fn optimize(&self, mut parameters: DVector<f64>) -> DVector<f64> {
+        let mut cost = self.residuals(&parameters).norm_squared();
+        let mut damping = 1e-3;
+        for _ in 0..MAX_ITERATIONS {
+            let jacobian = self.jacobian(&parameters);
+            let gradient = jacobian.transpose() * self.residuals(&parameters);
+            let approximation = jacobian.transpose() * &jacobian;
+
+            let mut improved = false;
+            while damping < 1e10 {
+                let mut system = approximation.clone();
+                for i in 0..system.nrows() {
+                    system[(i, i)] *= 1.0 + damping;
+                }
+                let step = match system.lu().solve(&gradient) {
+                    Some(step) => step,
+                    None => break,
+                };
+                let candidate = &parameters - step;
+                let candidate_cost = self.residuals(&candidate).norm_squared();
+                if candidate_cost < cost {
+                    let converged = cost - candidate_cost < 1e-12 * cost;
+                    parameters = candidate;
+                    cost = candidate_cost;
+                    damping = (damping / 10.0).max(1e-12);
+                    improved = !converged;
+                    break;
+                }
+                damping *= 10.0;
+            }
+            if !improved {
+                break;
+            }
+        }
+        parameters
+    }
+}
+
+fn to_float(image: &GrayImage) -> FloatImage {
+    FloatImage::from_fn(image.width(), image.height(), |x, y| {
+        Luma([image.get_pixel(x, y)[0] as f32])
+    })
+}
+
+fn value(image: &FloatImage, x: i32, y: i32) -> f32 {
+    let x = x.clamp(0, image.width() as i32 - 1) as u32;
+    let y = y.clamp(0, image.height() as i32 - 1) as u32;
+    image.get_pixel(x, y)[0]
+}
+
+/// Local maxima of the negative Hessian determinant, which peaks where four squares meet
+fn saddle_points(image: &FloatImage) -> Vec<Point2<f32>> {
+    let (width, height) = (image.width() as i32, image.height() as i32);
+    let response = FloatImage::from_fn(image.width(), image.height(), |x, y| {
+        let (x, y) = (x as i32, y as i32);
+        let center = value(image, x, y);
+        let dxx = value(image, x + 1, y) - 2.0 * center + value(image, x - 1, y);
+        let dyy = value(image, x, y + 1) - 2.0 * center + value(image, x, y - 1);
+        let dxy =
+            (value(image, x + 1, y + 1) - value(image, x + 1, y - 1) - value(image, x - 1, y + 1)
+                + value(image, x - 1, y - 1))
+                / 4.0;
+        Luma([(dxy * dxy - dxx * dyy).max(0.0)])
+    });
+    let strongest = response.pixels().fold(0f32, |m, p| m.max(p[0]));
+    if strongest <= 0.0 {
+        return vec![];
+    }
+
+    let border = RING_RADIUS.ceil() as i32 + 1;
+    let radius = REFINE_RADIUS - 1;
+    let mut maxima = vec![];
+    for y in border..height - border {
+        for x in border..width - border {
+            let r = value(&response, x, y);
+            if r < 0.05 * strongest {
+                continue;
+            }
+            let is_maximum = (-radius..=radius).all(|dy| {
+                (-radius..=radius).all(|dx| {
+                    let other = value(&response, x + dx, y + dy);
+                    // Ties are resolved towards the first pixel in scan order
+                    other < r || (other == r && (dy, dx) >= (0, 0))
+                })
+            });
+            if is_maximum {
+                maxima.push(Point2::new(x as f32, y as f32));
+            }
+        }
+    }
+    maxima
+}
+
+/// Checkerboard corners see bright, dark, bright and dark squares on a ring around them
+fn crosses_four_squares(image: &FloatImage, center: &Point2<f32>) -> bool {
+    const SAMPLES: usize = 32;
+    let ring: Vec<f32> = (0..SAMPLES)
+        .map(|i| {
+            let angle = i as f32 * std::f32::consts::TAU / SAMPLES as f32;
+            value(
+                image,
+                (center.x + RING_RADIUS * angle.cos()).round() as i32,
+                (center.y + RING_RADIUS * angle.sin()).round() as i32,
+            )
+        })
+        .collect();
+    let (min, max) = ring
+        .iter()
+        .fold((f32::MAX, f32::MIN), |(lo, hi), &v| (lo.min(v), hi.max(v)));
+    if max - min < 20.0 {
+        return false;
+    }
+
+    // Samples close to the mean lie on the edges between squares and are skipped
+    let mean = (min + max) / 2.0;
+    let margin = 0.2 * (max - min);
+    let signs: Vec<bool> = ring
+        .iter()
+        .filter(|v| (**v - mean).abs() > margin)
+        .map(|v| *v > mean)
+        .collect();
+    let changes = (0..signs.len())
+        .filter(|&i| signs[i] != signs[(i + 1) % signs.len()])
+        .count();
+    changes == 4
+}
+
+/// Sub-pixel corner position, where the gradients in its window are orthogonal to the
+/// direction towards the corner
+fn refine_saddle(image: &FloatImage, start: &Point2<f32>) -> Point2<f32> {
+    let mut corner = *start;
+    for _ in 0..10 {
+        let (cx, cy) = (corner.x.round() as i32, corner.y.round() as i32);
+        let mut normal = na::Matrix2::<f32>::zeros();
+        let mut rhs = na::Vector2::<f32>::zeros();
+        for dy in -REFINE_RADIUS..=REFINE_RADIUS {
+            for dx in -REFINE_RADIUS..=REFINE_RADIUS {
+                let (x, y) = (cx + dx, cy + dy);
+                let gradient = na::Vector2::new(
+                    (value(image, x + 1, y) - value(image, x - 1, y)) / 2.0,
+                    (value(image, x, y + 1) - value(image, x, y - 1)) / 2.0,
+                );
+                let tensor = gradient * gradient.transpose();
+                normal += tensor;
+                rhs += tensor * na::Vector2::new(x as f32, y as f32);
+            }
+        }
+        let refined = match normal.try_inverse() {
+            Some(inverse) => Point2::from(inverse * rhs),
+            None => break,
+        };
+        // A corner moving out of its window was no checkerboard corner
+        if na::distance(&refined, start) > REFINE_RADIUS as f32 {
+            return *start;
+        }
+        let moved = na::distance(&refined, &corner);
+        corner = refined;
+        if moved < 0.01 {
+            break;
+        }
+    }
+    corner
+}
+
+/// The four candidates spanning the largest quadrilateral, i.e. the outer board corners
+fn outermost(points: &[Point2<f32>]) -> Option<[Point2<f32>; 4]> {
+    let hull = convex_hull(points);
+    if hull.len() < 4 {
+        return None;
+    }
+
+    let mut best = (0.0, [hull[0], hull[1], hull[2], hull[3]]);
+    for a in 0..hull.len() {
+        for b in a + 1..hull.len() {
+            for c in b + 1..hull.len() {
+                for d in c + 1..hull.len() {
+                    let quad = [hull[a], hull[b], hull[c], hull[d]];
+                    let area = polygon_area(&quad);
+                    if area > best.0 {
+                        best = (area, quad);
+                    }
+                }
+            }
+        }
+    }
+    Some(best.1)
+}
+
+/// Monotone chain convex hull in counter clockwise order
+fn convex_hull(points: &[Point2<f32>]) -> Vec<Point2<f32>> {
+    let mut sorted = points.to_vec();
+    sorted.sort_by(|a, b| a.x.total_cmp(&b.x).then(a.y.total_cmp(&b.y)));
+    let cross = |o: &Point2<f32>, a: &Point2<f32>, b: &Point2<f32>| (a - o).perp(&(b - o));
+
+    let mut hull: Vec<Point2<f32>> = vec![];
+    for pass in 0..2 {
+        let start = hull.len();
+        let chain: Vec<Point2<f32>> = if pass == 0 {
+            sorted.clone()
+        } else {
+            sorted.iter().rev().copied().collect()
+        };
+        for p in chain {
+            while hull.len() >= start + 2
+                && cross(&hull[hull.len() - 2], &hull[hull.len() - 1], &p) <= 0.0
+            {
+                hull.pop();
+            }
+            hull.push(p);
+        }
+        // The last point of each chain starts the other one
+        hull.pop();
+    }
+    hull
+}
+
+fn polygon_area(points: &[Point2<f32>]) -> f32 {
+    (0..points.len())
+        .map(|i| {
+            let (a, b) = (points[i], points[(i + 1) % points.len()]);
+            a.x * b.y - b.x * a.y
+        })
+        .sum::<f32>()
+        .abs()
+        / 2.0
+}
+
+#[cfg(test)]
+mod test {
+    use crate::camera_calibration::convex_hull;
+    use crate::{calibrate_camera, calibrate_from_points, Checkerboard};
+    use image::{GrayImage, Luma};
+    use na::{distance, Matrix3, Point2, Rotation3, Vector3};
+    use tlc_common::{CameraModel, Distortion};
+
+    fn phone_camera() -> CameraModel {
+        CameraModel::new(
+            (640, 480),
+            (600.0, 610.0),
+            (322.0, 236.0),
+            Distortion {
+                k1: -0.2,
+                k2: 0.05,
+                k3: 0.0,
+                p1: 0.001,
+                p2: -0.0015,
+            },
+        )
+    }
+
+    /// Board to ideal pixel homographies of a 9 x 6 board with 20 mm squares
+    fn poses() -> Vec<Matrix3<f32>> {
+        [
+            (
+                Vector3::new(0.2, -0.3, 0.1),
+                Vector3::new(-90.0, -60.0, 400.0),
+            ),
+            (
+                Vector3::new(-0.35, 0.1, -0.2),
+                Vector3::new(-70.0, -40.0, 380.0),
+            ),
+            (
+                Vector3::new(0.1, 0.4, 0.3),
+                Vector3::new(-100.0, -30.0, 420.0),
+            ),
+            (
+                Vector3::new(-0.2, -0.25, -0.05),
+                Vector3::new(-60.0, -70.0, 360.0),
+            ),
+        ]
+        .iter()
+        .map(|(axis, t)| {
+            let r = Rotation3::new(*axis).into_inner();
+            let camera = phone_camera();
+            let k = Matrix3::new(
+                camera.fx, 0.0, camera.cx, 0.0, camera.fy, camera.cy, 0.0, 0.0, 1.0,
+            );
+            k * Matrix3::from_columns(&[r.column(0).into(), r.column(1).into(), *t])
+        })
+        .collect()
+    }
+
+    fn project(camera: &CameraModel, pose: &Matrix3<f32>, p: &Point2<f32>) -> Point2<f32> {
+        let ideal = pose * Vector3::new(p.x, p.y, 1.0);
+        camera.distort(&Point2::new(ideal.x / ideal.z, ideal.y / ideal.z))
+    }
+
+    /// A checkerboard with a white margin, sampled 2 x 2 times per pixel
+    fn render(camera: &CameraModel, pose: &Matrix3<f32>, board: &Checkerboard) -> GrayImage {
+        let inverse = pose.try_inverse().unwrap();
+        let size = board.square_size;
+        GrayImage::from_fn(camera.width, camera.height, |x, y| {
+            let sum: f32 = [(-0.25, -0.25), (0.25, -0.25), (-0.25, 0.25), (0.25, 0.25)]
+                .iter()
+                .map(|(dx, dy)| {
+                    let ideal = camera.undistort(&Point2::new(x as f32 + dx, y as f32 + dy));
+                    let p = inverse * Vector3::new(ideal.x, ideal.y, 1.0);
+                    let (u, v) = (p.x / p.z, p.y / p.z);
+                    let (i, j) = ((u / size).floor() as i32, (v / size).floor() as i32);
+                    let on_board =
+                        i >= -1 && j >= -1 && i < board.columns as i32 && j < board.rows as i32;
+                    if on_board && (i + j) % 2 == 0 {
+                        30.0
+                    } else {
+                        220.0
+                    }
+                })
+                .sum();
+            Luma([(sum / 4.0).round() as u8])
+        })
+    }
+
+    #[test]
+    fn test_convex_hull() {
+        let given: Vec<Point2<f32>> = [(0.0, 0.0), (2.0, 0.0), (1.0, 1.0), (2.0, 2.0), (0.0, 2.0)]
+            .iter()
+            .map(|&(x, y)| Point2::new(x, y))
+            .collect();
+
+        let when = convex_hull(&given);
+
+        assert_eq!(when.len(), 4);
+        assert!(!when.contains(&Point2::new(1.0, 1.0)));
+    }
+
+    #[test]
+    fn test_finds_checkerboard_corners() {
+        let camera = phone_camera();
+        let board = Checkerboard::new(9, 6, 20.0);
+        let pose = poses()[0];
+        let given = render(&camera, &pose, &board);
+
+        let when = board.find_corners(&given).unwrap();
+
+        let then: Vec<Point2<f32>> = board
+            .points()
+            .iter()
+            .map(|p| project(&camera, &pose, p))
+            .collect();
+        // The board may be labeled from its opposite corner
+        let reversed: Vec<Point2<f32>> = then.iter().rev().copied().collect();
+        let error = |expected: &[Point2<f32>]| {
+            expected
+                .iter()
+                .zip(when.iter())
+                .map(|(a, b)| distance(a, b))
+                .fold(0.0, f32::max)
+        };
+        assert!(error(&then).min(error(&reversed)) < 0.25);
+    }
+
+    #[test]
+    fn test_missing_checkerboard() {
+        let given = GrayImage::from_pixel(200, 100, Luma([128u8]));
+
+        assert_eq!(Checkerboard::new(9, 6, 20.0).find_corners(&given), None);
+    }
+
+    #[test]
+    fn test_calibrates_from_points() {
+        let camera = phone_camera();
+        let board = Checkerboard::new(9, 6, 20.0);
+        let given: Vec<Vec<Point2<f32>>> = poses()
+            .iter()
+            .map(|pose| {
+                board
+                    .points()
+                    .iter()
+                    .map(|p| project(&camera, pose, p))
+                    .collect()
+            })
+            .collect();
+
+        let when = calibrate_from_points(&given, &board, (640, 480)).unwrap();
+
+        assert!(when.reprojection_error < 0.01);
+        assert!((when.camera.fx - camera.fx).abs() < 1.0);
+        assert!((when.camera.fy - camera.fy).abs() < 1.0);
+        assert!((when.camera.distortion.k1 - camera.distortion.k1).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_calibrates_from_images() {
+        let camera = phone_camera();
+        let board = Checkerboard::new(9, 6, 20.0);
+        let given: Vec<GrayImage> = poses()
+            .iter()
+            .map(|pose| render(&camera, pose, &board))
+            .collect();
+
+        let when = calibrate_camera(&given, &board).unwrap();
+
+        assert_eq!(when.views, 4);
+        assert!(when.reprojection_error < 0.2);
+        assert!((when.camera.fx - camera.fx).abs() < 2.0);
+        assert!((when.camera.fy - camera.fy).abs() < 2.0);
+        assert!((when.camera.distortion.k1 - camera.distortion.k1).abs() < 0.02);
+    }
+
+    #[test]
+    fn test_too_few_views_fail() {
+        let board = Checkerboard::new(9, 6, 20.0);
+
+        assert!(calibrate_from_points(&[board.points()], &board, (640, 480)).is_err());
+    }
+}
@@ -0,0 +1,201 @@
+use crate::detection_result::{self, Corner, DetectionFailure, DetectionResult};
+use crate::plate_detector::PlateDetector;
+use image::GrayImage;
+use imageproc::contours::BorderType;
+use imageproc::distance_transform::Norm;
+use imageproc::point::Point;
+use na::Point2;
+use tlc_common::Quad;
+
+/// Douglas-Peucker tolerances relative to the outline length, tried until four corners remain
+const APPROXIMATION_EPSILONS: [f64; 6] = [0.01, 0.02, 0.03, 0.04, 0.05, 0.06];
+
+/// Minimal plate area relative to the image area
+const MIN_AREA_FRACTION: f32 = 0.01;
+
+/// Finds the plate as the largest convex quadrilateral outline.
+/// Unlike the Hough line detection this works for any rotation of the plate.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ContourDetector;
+
+impl PlateDetector for ContourDetector {
+    fn detect_scaled(&self, image: &GrayImage, expected_aspect_ratio: f32) -> DetectionResult {
+        let (width, height) = image.dimensions();
+        let foreground =
+            imageproc::contrast::threshold(image, imageproc::contrast::otsu_level(image));
+        let edges = imageproc::edges::canny(&foreground, 50.0, 100.0);
+
+        // Closed edge outlines and the bright plate area both yield the plate outline
+        let outlines = imageproc::morphology::dilate(&edges, Norm::LInf, 1);
+        if edges.pixels().all(|p| p[0] == 0) {
+            return DetectionResult::failed(DetectionFailure::NoLines);
+        }
+
+        let min_area = (width * height) as f32 * MIN_AREA_FRACTION;
+        let best = [outlines, foreground]
+            .iter()
+            .flat_map(imageproc::contours::find_contours::<i32>)
+            .filter(|contour| contour.border_type == BorderType::Outer)
+            .filter_map(|contour| approximate_quad(&contour.points))
+            .filter(|quad| detection_result::is_valid_quad(quad, min_area))
+            .map(|quad| {
+                let inferred = inferred_corners(&quad, width, height);
+                (quad, inferred)
+            })
+            // A quad along the whole image border is the image itself
+            .filter(|(_, inferred)| inferred.len() < 4)
+            .max_by(|(a, _), (b, _)| {
+                let score = |quad: &Quad| {
+                    detection_result::area(quad)
+                        * detection_result::aspect_ratio_score(quad, expected_aspect_ratio)
+                };
+                score(a).total_cmp(&score(b))
+            });
+
+        match best {
+            Some((quad, inferred_corners)) => DetectionResult {
+                quad: Some(quad),
+                confidence: detection_result::confidence(
+                    &quad,
+                    detection_result::edge_support(&quad, &edges),
+                    expected_aspect_ratio,
+                    inferred_corners.len(),
+                ),
+                inferred_corners,
+                failure: None,
+            },
+            None => DetectionResult::failed(DetectionFailure::NoQuadrilateral),
+        }
+    }
+}
+
+/// Simplifies the convex hull of an outline until four corners remain
+pub(crate) fn approximate_quad(outline: &[Point<i32>]) -> Option<Quad> {
+    let mut hull = imageproc::geometry::convex_hull(outline);
+    if hull.len() < 4 {
+        return None;
+    }
+    let length = imageproc::geometry::arc_length(&hull, true);
+
+    // Douglas-Peucker needs distinct end points, so the closed hull is split into two
+    // open chains. The points farthest from any point are always corners of the outline.
+    let start = farthest_point(&hull, hull[0])?;
+    hull.rotate_left(start);
+    let farthest = farthest_point(&hull, hull[0])?;
+    let mut second_half = hull[farthest..].to_vec();
+    second_half.push(hull[0]);
+
+    for epsilon in APPROXIMATION_EPSILONS.iter() {
+        let tolerance = epsilon * length;
+        let mut polygon =
+            imageproc::geometry::approximate_polygon_dp(&hull[..=farthest], tolerance, false);
+        polygon.pop();
+        polygon.extend(imageproc::geometry::approximate_polygon_dp(
+            &second_half,
+            tolerance,
+            false,
+        ));
+        polygon.pop();
+
+        match polygon.len() {
+            4 => {
+                let points: Vec<Point2<f32>> = polygon
+                    .iter()
+                    .map(|p| Point2::new(p.x as f32, p.y as f32))
+                    .collect();
+                return Some(detection_result::order_corners([
+                    points[0], points[1], points[2], points[3],
+                ]));
+            }
+            n if n < 4 => return None,
+            _ => {}
+        }
+    }
+    None
+}
+
+fn farthest_point(points: &[Point<i32>], from: Point<i32>) -> Option<usize> {
+    (0..points.len()).max_by_key(|&i| (points[i].x - from.x).pow(2) + (points[i].y - from.y).pow(2))
+}
+
+/// Corners on the image border are most likely cut off and were not visible
+fn inferred_corners(quad: &Quad, width: u32, height: u32) -> Vec<Corner> {
+    let margin = 1.5;
+    detection_result::corners(quad)
+        .iter()
+        .enumerate()
+        .filter(|(_, p)| {
+            p.x <= margin
+                || p.y <= margin
+                || p.x >= width as f32 - 1.0 - margin
+                || p.y >= height as f32 - 1.0 - margin
+        })
+        .filter_map(|(i, _)| Corner::from_index(i as u8))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test_util::{draw_plate, rotated_plate};
+    use crate::{ContourDetector, Corner, DetectionFailure, PlateDetector};
+    use image::{GrayImage, Luma};
+    use na::{distance, Point2};
+
+    fn max_corner_error(found: &[Point2<f32>; 4], expected: &[Point2<f32>]) -> f32 {
+        found
+            .iter()
+            .zip(expected.iter())
+            .map(|(a, b)| distance(a, b))
+            .fold(0.0, f32::max)
+    }
+
+    #[test]
+    fn test_detect_axis_aligned_plate() {
+        let expected = rotated_plate((128.0, 128.0), (80.0, 160.0), 0.0);
+        let given = draw_plate(256, 256, &expected);
+
+        let when = ContourDetector.detect_scaled(&given, 2.0);
+
+        assert_eq!(when.failure, None);
+        assert!(when.inferred_corners.is_empty());
+        assert!(when.confidence > 0.8);
+        let found = crate::detection_result::corners(&when.quad.unwrap());
+        assert!(max_corner_error(&found, &expected) < 3.0);
+    }
+
+    #[test]
+    fn test_detect_rotated_plate() {
+        let expected = rotated_plate((128.0, 128.0), (80.0, 160.0), 20.0);
+        let given = draw_plate(256, 256, &expected);
+
+        let when = ContourDetector.detect_scaled(&given, 2.0);
+
+        assert_eq!(when.failure, None);
+        assert!(when.confidence > 0.8);
+        let found = crate::detection_result::corners(&when.quad.unwrap());
+        assert!(max_corner_error(&found, &expected) < 3.0);
+    }
+
+    #[test]
+    fn test_cut_off_plate_has_inferred_corners() {
+        let given = draw_plate(256, 256, &rotated_plate((128.0, 200.0), (80.0, 160.0), 0.0));
+
+        let when = ContourDetector.detect_scaled(&given, 2.0);
+
+        assert!(when.is_detected());
+        assert_eq!(
+            when.inferred_corners,
+            vec![Corner::BottomRight, Corner::BottomLeft]
+        );
+    }
+
+    #[test]
+    fn test_flat_image_has_no_plate() {
+        let given = GrayImage::from_pixel(256, 256, Luma([128u8]));
+
+        let when = ContourDetector.detect_scaled(&given, 2.0);
+
+        assert!(!when.is_detected());
+        assert_eq!(when.failure, Some(DetectionFailure::NoLines));
+    }
+}
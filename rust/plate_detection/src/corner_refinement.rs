@@ -0,0 +1,306 @@
+use crate::detection_result::corners;
+use image::GrayImage;
+use na::{Point2, Vector2};
+use tlc_common::Quad;
+
+/// Number of gradient profiles sampled along each side next to a corner
+const PROFILES_PER_SIDE: usize = 16;
+
+/// Minimal number of edge points needed to fit a side
+const MIN_EDGE_POINTS: usize = 6;
+
+/// Edge points farther than this from the first line fit are dropped as outliers
+const MAX_RESIDUAL: f32 = 1.5;
+
+/// Refines coarse plate corners, e.g. found on a downscaled copy, on the full resolution image.
+/// Both sides next to each corner are located with sub-pixel precision inside a window of
+/// `search_radius` pixels around the coarse sides and the corner becomes their intersection.
+/// Corners whose sides cannot be found, e.g. outside the image, keep their coarse position.
+pub fn refine_corners(image: &GrayImage, coarse: &Quad, search_radius: f32) -> Quad {
+    let points = corners(coarse);
+    let mut refined = points;
+
+    for (i, corner) in points.iter().enumerate() {
+        let previous = points[(i + 3) % 4];
+        let next = points[(i + 1) % 4];
+
+        let sides = (
+            fit_side(image, corner, &previous, search_radius),
+            fit_side(image, corner, &next, search_radius),
+        );
+        if let (Some(a), Some(b)) = sides {
+            if let Some(intersection) = a.intersect(&b) {
+                // A refinement far off the search window found some other structure
+                if na::distance(&intersection, corner) <= 2.0 * search_radius {
+                    refined[i] = intersection;
+                }
+            }
+        }
+    }
+
+    Quad {
+        top_left: refined[0],
+        top_right: refined[1],
+        bottom_right: refined[2],
+        bottom_left: refined[3],
+    }
+}
+
+/// A line through `point` along the unit vector `direction`
+#[derive(Debug, Clone, Copy)]
+struct EdgeLine {
+    point: Point2<f32>,
+    direction: Vector2<f32>,
+}
+
+impl EdgeLine {
+    /// Total least squares fit, which works for lines of any orientation
+    fn fit(points: &[Point2<f32>]) -> Option<Self> {
+        if points.len() < 2 {
+            return None;
+        }
+        let count = points.len() as f32;
+        let mean = points
+            .iter()
+            .fold(Vector2::zeros(), |sum: Vector2<f32>, p| sum + p.coords)
+            / count;
+
+        let (mut sxx, mut sxy, mut syy) = (0f32, 0f32, 0f32);
+        for p in points {
+            let d = p.coords - mean;
+            sxx += d.x * d.x;
+            sxy += d.x * d.y;
+            syy += d.y * d.y;
+        }
+        let angle = 0.5 * (2.0 * sxy).atan2(sxx - syy);
+
+        Some(EdgeLine {
+            point: Point2::from(mean),
+            direction: Vector2::new(angle.cos(), angle.sin()),
+        })
+    }
+
+    fn distance(&self, point: &Point2<f32>) -> f32 {
+        (point - self.point).perp(&self.direction).abs()
+    }
+
+    fn intersect(&self, other: &EdgeLine) -> Option<Point2<f32>> {
+        let denominator = self.direction.perp(&other.direction);
+        if denominator.abs() < 1e-3 {
+            return None;
+        }
+        let t = (other.point - self.point).perp(&other.direction) / denominator;
+        Some(self.point + self.direction * t)
+    }
+}
+
+/// Locates the side from `corner` towards `other` next to the corner
+fn fit_side(
+    image: &GrayImage,
+    corner: &Point2<f32>,
+    other: &Point2<f32>,
+    search_radius: f32,
+) -> Option<EdgeLine> {
+    let along: Vector2<f32> = other - corner;
+    let length = along.norm();
+    if length <= 0.0 {
+        return None;
+    }
+    let along = along / length;
+    let normal = Vector2::new(-along.y, along.x);
+
+    // Close to the corner the profiles would cross the other side
+    let start = 1.5 * search_radius;
+    let end = (4.0 * search_radius).min(length / 2.0);
+    if end <= start {
+        return None;
+    }
+
+    let edge_points: Vec<Point2<f32>> = (0..PROFILES_PER_SIDE)
+        .filter_map(|s| {
+            let t = start + (end - start) * s as f32 / (PROFILES_PER_SIDE - 1) as f32;
+            locate_edge(image, &(corner + along * t), &normal, search_radius)
+        })
+        .collect();
+    if edge_points.len() < MIN_EDGE_POINTS {
+        return None;
+    }
+
+    let first_fit = EdgeLine::fit(&edge_points)?;
+    let inliers: Vec<Point2<f32>> = edge_points
+        .into_iter()
+        .filter(|p| first_fit.distance(p) <= MAX_RESIDUAL)
+        .collect();
+    if inliers.len() < MIN_EDGE_POINTS {
+        return None;
+    }
+    EdgeLine::fit(&inliers)
+}
+
+/// Position of the strongest intensity step along `normal` through `center`,
+/// interpolated between pixels with a parabola through the gradient maximum
+fn locate_edge(
+    image: &GrayImage,
+    center: &Point2<f32>,
+    normal: &Vector2<f32>,
+    search_radius: f32,
+) -> Option<Point2<f32>> {
+    let steps = search_radius.ceil() as i32;
+    let profile: Vec<f32> = (-steps - 1..=steps + 1)
+        .map(|t| sample_bilinear(image, &(center + normal * t as f32)))
+        .collect::<Option<Vec<f32>>>()?;
+
+    let gradients: Vec<f32> = profile
+        .windows(3)
+        .map(|w| ((w[2] - w[0]) / 2.0).abs())
+        .collect();
+    let (peak, &strongest) = gradients
+        .iter()
+        .enumerate()
+        .max_by(|a, b| a.1.total_cmp(b.1))?;
+    // Flat profiles, e.g. behind the image border, have no edge
+    if strongest < 4.0 || peak == 0 || peak == gradients.len() - 1 {
+        return None;
+    }
+
+    let (left, right) = (gradients[peak - 1], gradients[peak + 1]);
+    let curvature = left - 2.0 * strongest + right;
+    let offset = if curvature.abs() > f32::EPSILON {
+        0.5 * (left - right) / curvature
+    } else {
+        0.0
+    };
+
+    let t = (peak as i32 - steps) as f32 + offset;
+    Some(center + normal * t)
+}
+
+pub(crate) fn sample_bilinear(image: &GrayImage, point: &Point2<f32>) -> Option<f32> {
+    let (width, height) = image.dimensions();
+    if point.x < 0.0
+        || point.y < 0.0
+        || point.x > (width - 1) as f32
+        || point.y > (height - 1) as f32
+    {
+        return None;
+    }
+
+    let (x0, y0) = (point.x.floor() as u32, point.y.floor() as u32);
+    let (x1, y1) = ((x0 + 1).min(width - 1), (y0 + 1).min(height - 1));
+    let (fx, fy) = (point.x - x0 as f32, point.y - y0 as f32);
+    let value = |x: u32, y: u32| image.get_pixel(x, y)[0] as f32;
+
+    let top = value(x0, y0) * (1.0 - fx) + value(x1, y0) * fx;
+    let bottom = value(x0, y1) * (1.0 - fx) + value(x1, y1) * fx;
+    Some(top * (1.0 - fy) + bottom * fy)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::corner_refinement::{sample_bilinear, EdgeLine};
+    use crate::detection_result::corners;
+    use crate::refine_corners;
+    use image::{GrayImage, Luma};
+    use na::{distance, Point2, Vector2};
+    use tlc_common::Quad;
+
+    /// A bright convex quad with anti-aliased edges on a dark background
+    fn render(width: u32, height: u32, quad: &Quad) -> GrayImage {
+        let points = corners(quad);
+        GrayImage::from_fn(width, height, |x, y| {
+            let p = Point2::new(x as f32, y as f32);
+            // Signed distance to the outline, positive outside
+            let outside = (0..4)
+                .map(|i| {
+                    let side: Vector2<f32> = points[(i + 1) % 4] - points[i];
+                    let normal = Vector2::new(side.y, -side.x).normalize();
+                    normal.dot(&(p - points[i]))
+                })
+                .fold(f32::MIN, f32::max);
+            let coverage = (0.5 - outside).clamp(0.0, 1.0);
+            Luma([(40.0 + 180.0 * coverage).round() as u8])
+        })
+    }
+
+    fn tilted_quad() -> Quad {
+        Quad {
+            top_left: Point2::new(210.3, 150.7),
+            top_right: Point2::new(780.6, 190.2),
+            bottom_right: Point2::new(755.1, 620.8),
+            bottom_left: Point2::new(180.9, 590.4),
+        }
+    }
+
+    fn shifted(quad: &Quad, offsets: [(f32, f32); 4]) -> Quad {
+        let points = corners(quad);
+        let moved: Vec<Point2<f32>> = points
+            .iter()
+            .zip(offsets.iter())
+            .map(|(p, (dx, dy))| Point2::new(p.x + dx, p.y + dy))
+            .collect();
+        Quad {
+            top_left: moved[0],
+            top_right: moved[1],
+            bottom_right: moved[2],
+            bottom_left: moved[3],
+        }
+    }
+
+    fn max_error(a: &Quad, b: &Quad) -> f32 {
+        corners(a)
+            .iter()
+            .zip(corners(b).iter())
+            .map(|(p, q)| distance(p, q))
+            .fold(0.0, f32::max)
+    }
+
+    #[test]
+    fn test_line_fit_and_intersection() {
+        let horizontal = EdgeLine::fit(&[
+            Point2::new(0.0, 5.0),
+            Point2::new(10.0, 5.0),
+            Point2::new(20.0, 5.0),
+        ])
+        .unwrap();
+        let vertical = EdgeLine::fit(&[
+            Point2::new(7.0, 0.0),
+            Point2::new(7.0, 10.0),
+            Point2::new(7.0, 30.0),
+        ])
+        .unwrap();
+
+        let when = horizontal.intersect(&vertical).unwrap();
+
+        assert!(distance(&when, &Point2::new(7.0, 5.0)) < 1e-4);
+    }
+
+    #[test]
+    fn test_bilinear_sampling() {
+        let given = GrayImage::from_fn(2, 2, |x, _| Luma([(x * 100) as u8]));
+
+        assert_eq!(sample_bilinear(&given, &Point2::new(0.25, 0.5)), Some(25.0));
+        assert_eq!(sample_bilinear(&given, &Point2::new(1.5, 0.0)), None);
+    }
+
+    #[test]
+    fn test_refines_to_sub_pixel_precision() {
+        let then = tilted_quad();
+        let given = render(1000, 800, &then);
+        let coarse = shifted(&then, [(6.0, -5.0), (-4.0, 7.0), (5.0, 5.0), (-7.0, -3.0)]);
+
+        let when = refine_corners(&given, &coarse, 12.0);
+
+        assert!(max_error(&coarse, &then) > 5.0);
+        assert!(max_error(&when, &then) < 0.5);
+    }
+
+    #[test]
+    fn test_keeps_corners_without_edges() {
+        let given = GrayImage::from_pixel(1000, 800, Luma([128u8]));
+        let coarse = tilted_quad();
+
+        let when = refine_corners(&given, &coarse, 12.0);
+
+        assert_eq!(when, coarse);
+    }
+}
@@ -0,0 +1,350 @@
+use image::GrayImage;
+use na::{distance, Point2, Vector2};
+use std::fmt;
+use tlc_common::Quad;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Corner {
+    TopLeft,
+    TopRight,
+    BottomRight,
+    BottomLeft,
+}
+
+impl Corner {
+    /// Index in the top left, top right, bottom right, bottom left order of `Quad`
+    pub fn index(self) -> u8 {
+        match self {
+            Corner::TopLeft => 0,
+            Corner::TopRight => 1,
+            Corner::BottomRight => 2,
+            Corner::BottomLeft => 3,
+        }
+    }
+
+    pub fn from_index(index: u8) -> Option<Self> {
+        match index {
+            0 => Some(Corner::TopLeft),
+            1 => Some(Corner::TopRight),
+            2 => Some(Corner::BottomRight),
+            3 => Some(Corner::BottomLeft),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DetectionFailure {
+    /// No straight edges were found at all
+    NoLines,
+    /// Edges were found, but not in two distinct orientations which could form the plate sides
+    NoIntersections,
+    /// Less than four corners could be found or inferred
+    MissingCorners,
+    /// The corners do not form a convex quadrilateral of reasonable size
+    Degenerate,
+    /// No outline could be approximated by a convex quadrilateral
+    NoQuadrilateral,
+}
+
+impl fmt::Display for DetectionFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let message = match self {
+            DetectionFailure::NoLines => "No plate edges found",
+            DetectionFailure::NoIntersections => "No intersecting plate edges found",
+            DetectionFailure::MissingCorners => "Not all plate corners found",
+            DetectionFailure::Degenerate => "Plate corners do not form a valid plate",
+            DetectionFailure::NoQuadrilateral => "No four sided plate outline found",
+        };
+        write!(f, "{}", message)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DetectionResult {
+    /// The plate corners in input image coordinates
+    pub quad: Option<Quad>,
+    /// From 0 (no plate) to 1 (strong edges along a rectangle with the expected aspect ratio)
+    pub confidence: f32,
+    /// Corners which were not visible and are placed where an edge leaves the image
+    pub inferred_corners: Vec<Corner>,
+    pub failure: Option<DetectionFailure>,
+}
+
+impl DetectionResult {
+    pub fn failed(failure: DetectionFailure) -> Self {
+        DetectionResult {
+            quad: None,
+            confidence: 0.0,
+            inferred_corners: vec![],
+            failure: Some(failure),
+        }
+    }
+
+    pub fn is_detected(&self) -> bool {
+        self.quad.is_some()
+    }
+
+    /// Scales the corners, e.g. from detection scale to the input image
+    pub fn scaled(self, factor: f32) -> Self {
+        let quad = self.quad.map(|quad| Quad {
+            top_left: quad.top_left * factor,
+            top_right: quad.top_right * factor,
+            bottom_right: quad.bottom_right * factor,
+            bottom_left: quad.bottom_left * factor,
+        });
+        DetectionResult { quad, ..self }
+    }
+}
+
+/// Confidence penalty for each corner which had to be inferred
+const INFERRED_CORNER_PENALTY: f32 = 0.75;
+
+pub(crate) fn corners(quad: &Quad) -> [Point2<f32>; 4] {
+    [
+        quad.top_left,
+        quad.top_right,
+        quad.bottom_right,
+        quad.bottom_left,
+    ]
+}
+
+/// Orders corners clockwise around their centroid, starting with the top left one
+pub(crate) fn order_corners(points: [Point2<f32>; 4]) -> Quad {
+    let order = corner_order(&points);
+    Quad {
+        top_left: points[order[0]],
+        top_right: points[order[1]],
+        bottom_right: points[order[2]],
+        bottom_left: points[order[3]],
+    }
+}
+
+/// Indices of the top left, top right, bottom right and bottom left point.
+/// Only depends on the positions, so it works for any plate rotation.
+pub(crate) fn corner_order(points: &[Point2<f32>; 4]) -> [usize; 4] {
+    let center = points
+        .iter()
+        .fold(Vector2::zeros(), |sum: Vector2<f32>, p| sum + p.coords)
+        / 4.0;
+    let angle = |i: usize| (points[i].y - center.y).atan2(points[i].x - center.x);
+
+    let mut order = [0, 1, 2, 3];
+    // With y pointing down increasing angles run clockwise
+    order.sort_by(|&a, &b| angle(a).total_cmp(&angle(b)));
+    let sum = |i: usize| points[order[i]].x + points[order[i]].y;
+    let top_left = (0..4).min_by(|&a, &b| sum(a).total_cmp(&sum(b))).unwrap();
+    order.rotate_left(top_left);
+    order
+}
+
+/// Combines the support of the sides, from 0 to 1, with rectangularity and aspect ratio
+pub(crate) fn confidence(
+    quad: &Quad,
+    side_support: f32,
+    expected_aspect_ratio: f32,
+    inferred_corners: usize,
+) -> f32 {
+    let score = 0.5 * side_support.clamp(0.0, 1.0)
+        + 0.25 * rectangularity(quad)
+        + 0.25 * aspect_ratio_score(quad, expected_aspect_ratio);
+
+    score * INFERRED_CORNER_PENALTY.powi(inferred_corners as i32)
+}
+
+/// Fraction of the quad outline which is covered by edge pixels
+pub(crate) fn edge_support(quad: &Quad, edges: &GrayImage) -> f32 {
+    let (width, height) = edges.dimensions();
+    let points = corners(quad);
+    let search_radius = 2i32;
+
+    let mut samples = 0u32;
+    let mut supported = 0u32;
+    for i in 0..4 {
+        let (start, end) = (points[i], points[(i + 1) % 4]);
+        let steps = distance(&start, &end).ceil().max(1.0) as u32;
+        for s in 0..=steps {
+            let p = start + (end - start) * (s as f32 / steps as f32);
+            samples += 1;
+
+            let (px, py) = (p.x.round() as i32, p.y.round() as i32);
+            let has_edge = (-search_radius..=search_radius).any(|dy| {
+                (-search_radius..=search_radius).any(|dx| {
+                    let (x, y) = (px + dx, py + dy);
+                    x >= 0
+                        && y >= 0
+                        && (x as u32) < width
+                        && (y as u32) < height
+                        && edges.get_pixel(x as u32, y as u32)[0] > 0
+                })
+            });
+            if has_edge {
+                supported += 1;
+            }
+        }
+    }
+
+    supported as f32 / samples as f32
+}
+
+/// 1 if all corner angles are right angles, decreasing with the deviation
+pub(crate) fn rectangularity(quad: &Quad) -> f32 {
+    let points = corners(quad);
+    let mean_cos = (0..4)
+        .map(|i| {
+            let prev: Vector2<f32> = points[(i + 3) % 4] - points[i];
+            let next: Vector2<f32> = points[(i + 1) % 4] - points[i];
+            let norm = prev.norm() * next.norm();
+            if norm > 0.0 {
+                (prev.dot(&next) / norm).abs()
+            } else {
+                1.0
+            }
+        })
+        .sum::<f32>()
+        / 4.0;
+
+    1.0 - mean_cos
+}
+
+/// 1 if the ratio of the long to the short side matches the expectation
+pub(crate) fn aspect_ratio_score(quad: &Quad, expected_aspect_ratio: f32) -> f32 {
+    let points = corners(quad);
+    let side = |a: usize, b: usize| distance(&points[a], &points[b]);
+    let horizontal = (side(0, 1) + side(3, 2)) / 2.0;
+    let vertical = (side(0, 3) + side(1, 2)) / 2.0;
+
+    let (long, short) = (horizontal.max(vertical), horizontal.min(vertical));
+    if short <= 0.0 {
+        return 0.0;
+    }
+    let ratio = long / short;
+    let expected = expected_aspect_ratio.max(1.0 / expected_aspect_ratio);
+
+    ratio.min(expected) / ratio.max(expected)
+}
+
+/// Convex and in clockwise order with a minimal area
+pub(crate) fn is_valid_quad(quad: &Quad, min_area: f32) -> bool {
+    let points = corners(quad);
+    let crosses: Vec<f32> = (0..4)
+        .map(|i| {
+            let a: Vector2<f32> = points[(i + 1) % 4] - points[i];
+            let b: Vector2<f32> = points[(i + 2) % 4] - points[(i + 1) % 4];
+            a.perp(&b)
+        })
+        .collect();
+
+    // Image coordinates have y pointing down, so clockwise order has positive cross products
+    let convex = crosses.iter().all(|&c| c > 0.0);
+    convex && area(quad) >= min_area
+}
+
+pub(crate) fn area(quad: &Quad) -> f32 {
+    let points = corners(quad);
+    (0..4)
+        .map(|i| {
+            let (a, b) = (points[i], points[(i + 1) % 4]);
+            a.x * b.y - b.x * a.y
+        })
+        .sum::<f32>()
+        .abs()
+        / 2.0
+}
+
+#[cfg(test)]
+mod test {
+    use crate::detection_result::{
+        aspect_ratio_score, confidence, edge_support, is_valid_quad, order_corners, rectangularity,
+    };
+    use crate::test_util::rect_quad;
+    use image::{GrayImage, Luma};
+    use imageproc::rect::Rect;
+    use na::Point2;
+    use tlc_common::Quad;
+
+    fn outline(width: u32, height: u32, rect: Rect) -> GrayImage {
+        let mut edges = GrayImage::new(width, height);
+        imageproc::drawing::draw_hollow_rect_mut(&mut edges, rect, Luma([255u8]));
+        edges
+    }
+
+    #[test]
+    fn test_rectangle_scores() {
+        let given = rect_quad(10.0, 10.0, 60.0, 110.0);
+
+        assert!((rectangularity(&given) - 1.0).abs() < 1e-6);
+        assert!((aspect_ratio_score(&given, 2.0) - 1.0).abs() < 1e-6);
+        assert!((aspect_ratio_score(&given, 0.5) - 1.0).abs() < 1e-6);
+        assert!((aspect_ratio_score(&given, 1.0) - 0.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_skewed_quad_is_less_rectangular() {
+        let given = Quad {
+            top_left: Point2::new(30.0, 10.0),
+            top_right: Point2::new(80.0, 10.0),
+            bottom_right: Point2::new(60.0, 110.0),
+            bottom_left: Point2::new(10.0, 110.0),
+        };
+
+        assert!(rectangularity(&given) < 0.9);
+    }
+
+    #[test]
+    fn test_edge_support() {
+        let given = rect_quad(10.0, 10.0, 60.0, 110.0);
+
+        let supported = outline(128, 128, Rect::at(10, 10).of_size(51, 101));
+        let shifted = outline(128, 128, Rect::at(40, 10).of_size(51, 101));
+        let empty = GrayImage::new(128, 128);
+
+        assert!(edge_support(&given, &supported) > 0.99);
+        assert!(edge_support(&given, &shifted) < 0.75);
+        assert_eq!(edge_support(&given, &empty), 0.0);
+    }
+
+    #[test]
+    fn test_inferred_corners_lower_confidence() {
+        let given = rect_quad(10.0, 10.0, 60.0, 110.0);
+        let edges = outline(128, 128, Rect::at(10, 10).of_size(51, 101));
+
+        let support = edge_support(&given, &edges);
+
+        let found = confidence(&given, support, 2.0, 0);
+        let inferred = confidence(&given, support, 2.0, 1);
+
+        assert!(found > 0.99);
+        assert!(inferred < found);
+    }
+
+    #[test]
+    fn test_valid_quad() {
+        let given = rect_quad(10.0, 10.0, 60.0, 110.0);
+        let crossed = Quad {
+            top_left: Point2::new(10.0, 10.0),
+            top_right: Point2::new(60.0, 110.0),
+            bottom_right: Point2::new(60.0, 10.0),
+            bottom_left: Point2::new(10.0, 110.0),
+        };
+
+        assert!(is_valid_quad(&given, 100.0));
+        assert!(!is_valid_quad(&given, 10000.0));
+        assert!(!is_valid_quad(&crossed, 100.0));
+    }
+
+    #[test]
+    fn test_order_corners() {
+        let then = rect_quad(10.0, 10.0, 60.0, 110.0);
+
+        let when = order_corners([
+            then.bottom_right,
+            then.top_left,
+            then.bottom_left,
+            then.top_right,
+        ]);
+
+        assert_eq!(when, then);
+        assert!(is_valid_quad(&when, 100.0));
+    }
+}
@@ -0,0 +1,587 @@
+use crate::contour_detector::approximate_quad;
+use crate::corner_refinement::{refine_corners, sample_bilinear};
+use crate::detection_result::{self, corners};
+use crate::homography::{fit_homography, transform_point};
+use image::{GrayImage, Luma};
+use imageproc::contours::{BorderType, Contour};
+use na::{Matrix3, Point2};
+use tlc_common::Quad;
+
+/// Square markers are a grid of 6 x 6 cells, a black border around 4 x 4 code bits
+const MARKER_CELLS: u32 = 6;
+
+/// Minimal square marker or circular target area in pixels
+const MIN_MARKER_AREA: f32 = 100.0;
+
+/// Minimal number of square markers to register the plate.
+/// A single marker gives a homography, but it is unstable far away from the marker.
+const MIN_SQUARE_MARKERS: usize = 2;
+
+/// Points in layout coordinates and the matching image points
+type Correspondences = (Vec<Point2<f32>>, Vec<Point2<f32>>);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MarkerKind {
+    /// ArUco-style square markers with a 16 bit code, see `render_square_marker`
+    Square,
+    /// Dark discs with a concentric bright hole. They have no code, so exactly four
+    /// targets are matched to the layout by their position.
+    Circle,
+}
+
+/// Where the markers and the plate sit on the capture box, in any unit, e.g. mm
+#[derive(Debug, Clone, PartialEq)]
+pub struct MarkerLayout {
+    pub kind: MarkerKind,
+    /// Side length of square markers without the white margin
+    pub marker_size: f32,
+    /// Code and centre of each marker. The code is ignored for circular targets.
+    pub markers: Vec<(u16, Point2<f32>)>,
+    /// The plate corners in layout coordinates
+    pub plate: Quad,
+}
+
+/// A square marker found in the image
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DetectedMarker {
+    pub code: u16,
+    /// The corners in the orientation of the printed marker
+    pub corners: Quad,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MarkerRegistration {
+    /// Maps layout coordinates to image coordinates
+    pub homography: Matrix3<f32>,
+    pub markers_found: usize,
+    /// Root mean square distance of the mapped marker points to the detected ones in pixels
+    pub reprojection_error: f32,
+}
+
+impl MarkerRegistration {
+    pub fn to_image(&self, point: &Point2<f32>) -> Point2<f32> {
+        transform_point(&self.homography, point)
+    }
+
+    /// The plate corners in the image, e.g. for `unwarp_crop`.
+    /// They do not need to be visible, so this works for occluded plates too.
+    pub fn plate_quad(&self, layout: &MarkerLayout) -> Quad {
+        Quad {
+            top_left: self.to_image(&layout.plate.top_left),
+            top_right: self.to_image(&layout.plate.top_right),
+            bottom_right: self.to_image(&layout.plate.bottom_right),
+            bottom_left: self.to_image(&layout.plate.bottom_left),
+        }
+    }
+}
+
+/// Finds the markers of the layout and the homography from the layout to the image
+pub fn register_markers(
+    image: &GrayImage,
+    layout: &MarkerLayout,
+) -> Result<MarkerRegistration, String> {
+    let (layout_points, image_points) = match layout.kind {
+        MarkerKind::Square => square_correspondences(image, layout)?,
+        MarkerKind::Circle => circle_correspondences(image, layout)?,
+    };
+
+    let homography = fit_homography(&layout_points, &image_points)
+        .ok_or_else(|| "The fiducial markers do not span a plane".to_string())?;
+    let squared_error = layout_points
+        .iter()
+        .zip(image_points.iter())
+        .map(|(l, i)| na::distance_squared(&transform_point(&homography, l), i))
+        .sum::<f32>();
+
+    Ok(MarkerRegistration {
+        homography,
+        markers_found: match layout.kind {
+            MarkerKind::Square => image_points.len() / 4,
+            MarkerKind::Circle => image_points.len(),
+        },
+        reprojection_error: (squared_error / image_points.len() as f32).sqrt(),
+    })
+}
+
+fn square_correspondences(
+    image: &GrayImage,
+    layout: &MarkerLayout,
+) -> Result<Correspondences, String> {
+    let codes: Vec<u16> = layout.markers.iter().map(|(code, _)| *code).collect();
+    validate_codes(&codes)?;
+
+    let found = detect_square_markers(image, &codes);
+    if found.len() < MIN_SQUARE_MARKERS {
+        return Err(format!(
+            "Found {} of {} fiducial markers, at least {} are needed",
+            found.len(),
+            codes.len(),
+            MIN_SQUARE_MARKERS
+        ));
+    }
+
+    let half = layout.marker_size / 2.0;
+    let mut layout_points = vec![];
+    let mut image_points = vec![];
+    for marker in &found {
+        let (_, center) = layout
+            .markers
+            .iter()
+            .find(|(c, _)| *c == marker.code)
+            .unwrap();
+        layout_points.extend_from_slice(&[
+            Point2::new(center.x - half, center.y - half),
+            Point2::new(center.x + half, center.y - half),
+            Point2::new(center.x + half, center.y + half),
+            Point2::new(center.x - half, center.y + half),
+        ]);
+        image_points.extend_from_slice(&corners(&marker.corners));
+    }
+    Ok((layout_points, image_points))
+}
+
+fn circle_correspondences(
+    image: &GrayImage,
+    layout: &MarkerLayout,
+) -> Result<Correspondences, String> {
+    if layout.markers.len() != 4 {
+        return Err("Circular targets need a layout with exactly four targets".to_string());
+    }
+    let found = detect_circular_targets(image);
+    if found.len() != 4 {
+        return Err(format!("Found {} of 4 circular targets", found.len()));
+    }
+
+    let to_array = |points: &[Point2<f32>]| [points[0], points[1], points[2], points[3]];
+    let layout_centers: Vec<Point2<f32>> = layout.markers.iter().map(|(_, c)| *c).collect();
+    let layout_order = detection_result::order_corners(to_array(&layout_centers));
+    let image_order = detection_result::order_corners(to_array(&found));
+    Ok((
+        corners(&layout_order).to_vec(),
+        corners(&image_order).to_vec(),
+    ))
+}
+
+/// The code read clockwise by a quarter turn
+fn rotate_code(code: u16) -> u16 {
+    (0..16).fold(0u16, |rotated, index| {
+        let (row, col) = (index / 4, index % 4);
+        // After the turn the cell at (row, col) shows the cell at (3 - col, row)
+        let source = (3 - col) * 4 + row;
+        if code & (1 << (15 - source)) != 0 {
+            rotated | (1 << (15 - index))
+        } else {
+            rotated
+        }
+    })
+}
+
+/// Codes must differ from their own rotations and from all rotations of other codes
+fn validate_codes(codes: &[u16]) -> Result<(), String> {
+    for (i, &code) in codes.iter().enumerate() {
+        let mut rotated = code;
+        for _ in 0..3 {
+            rotated = rotate_code(rotated);
+            if rotated == code {
+                return Err(format!("Marker code {:#06x} is rotation symmetric", code));
+            }
+        }
+        for &other in &codes[i + 1..] {
+            let mut rotated = other;
+            for _ in 0..4 {
+                if rotated == code {
+                    return Err(format!(
+                        "Marker codes {:#06x} and {:#06x} are rotations of each other",
+                        code, other
+                    ));
+                }
+                rotated = rotate_code(rotated);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Whether the cell of a square marker is white, with row and column from 0 to 5
+fn is_white_cell(code: u16, row: u32, col: u32) -> bool {
+    if row == 0 || col == 0 || row == MARKER_CELLS - 1 || col == MARKER_CELLS - 1 {
+        return false;
+    }
+    let index = (row - 1) * 4 + (col - 1);
+    code & (1 << (15 - index)) != 0
+}
+
+/// A printable square marker with a one cell wide white margin
+pub fn render_square_marker(code: u16, cell_size: u32) -> GrayImage {
+    let size = (MARKER_CELLS + 2) * cell_size;
+    GrayImage::from_fn(size, size, |x, y| {
+        let (col, row) = (x / cell_size, y / cell_size);
+        let inside = (1..=MARKER_CELLS).contains(&col) && (1..=MARKER_CELLS).contains(&row);
+        if !inside || is_white_cell(code, row - 1, col - 1) {
+            Luma([255u8])
+        } else {
+            Luma([0u8])
+        }
+    })
+}
+
+/// Dark regions of the image as foreground
+fn dark_mask(image: &GrayImage) -> GrayImage {
+    let level = imageproc::contrast::otsu_level(image);
+    imageproc::map::map_colors(image, |p| {
+        if p[0] <= level {
+            Luma([255u8])
+        } else {
+            Luma([0u8])
+        }
+    })
+}
+
+/// Finds square markers with one of the given codes in any orientation
+pub fn detect_square_markers(image: &GrayImage, codes: &[u16]) -> Vec<DetectedMarker> {
+    let contours = imageproc::contours::find_contours::<i32>(&dark_mask(image));
+    let mut found: Vec<DetectedMarker> = contours
+        .iter()
+        .filter(|contour| contour.border_type == BorderType::Outer)
+        .filter_map(|contour| approximate_quad(&contour.points))
+        .filter(is_square_like)
+        .filter_map(|quad| {
+            // Locate the outline precisely, but stay within the border cells
+            let cell = (detection_result::area(&quad).sqrt() / MARKER_CELLS as f32).max(2.0);
+            let refined = refine_corners(image, &quad, (cell / 2.0).min(6.0));
+            decode_marker(image, &refined, codes)
+        })
+        .collect();
+
+    // Keep a single detection per code, e.g. for markers found in both polarities
+    found.sort_by_key(|marker| marker.code);
+    found.dedup_by_key(|marker| marker.code);
+    found
+}
+
+fn is_square_like(quad: &Quad) -> bool {
+    if !detection_result::is_valid_quad(quad, MIN_MARKER_AREA) {
+        return false;
+    }
+    let points = corners(quad);
+    let sides: Vec<f32> = (0..4)
+        .map(|i| na::distance(&points[i], &points[(i + 1) % 4]))
+        .collect();
+    let longest = sides.iter().cloned().fold(0.0, f32::max);
+    let shortest = sides.iter().cloned().fold(f32::MAX, f32::min);
+    // Perspective distorts squares, but not into long rectangles
+    shortest / longest > 0.5
+}
+
+/// Reads the code bits of a marker candidate and orients its corners
+fn decode_marker(image: &GrayImage, quad: &Quad, codes: &[u16]) -> Option<DetectedMarker> {
+    let cells = MARKER_CELLS as f32;
+    let grid = [
+        Point2::new(0.0, 0.0),
+        Point2::new(cells, 0.0),
+        Point2::new(cells, cells),
+        Point2::new(0.0, cells),
+    ];
+    let to_image = fit_homography(&grid, &corners(quad))?;
+    let sample = |col: f32, row: f32| {
+        sample_bilinear(image, &transform_point(&to_image, &Point2::new(col, row)))
+    };
+
+    // The black border against the white margin sets the threshold
+    let mut border = vec![];
+    let mut margin = vec![];
+    for i in 0..MARKER_CELLS {
+        let along = i as f32 + 0.5;
+        for &(col, row) in [
+            (along, 0.5),
+            (along, cells - 0.5),
+            (0.5, along),
+            (cells - 0.5, along),
+        ]
+        .iter()
+        {
+            border.push(sample(col, row)?);
+        }
+        for &(col, row) in [
+            (along, -0.5),
+            (along, cells + 0.5),
+            (-0.5, along),
+            (cells + 0.5, along),
+        ]
+        .iter()
+        {
+            margin.push(sample(col, row)?);
+        }
+    }
+    let dark = border.iter().sum::<f32>() / border.len() as f32;
+    let bright = margin.iter().sum::<f32>() / margin.len() as f32;
+    if bright - dark < 30.0 {
+        return None;
+    }
+    let threshold = (dark + bright) / 2.0;
+    if border.iter().any(|&v| v > threshold) || margin.iter().any(|&v| v < threshold) {
+        return None;
+    }
+
+    let mut observed = 0u16;
+    for index in 0..16u32 {
+        let (row, col) = ((index / 4) as f32 + 1.5, (index % 4) as f32 + 1.5);
+        if sample(col, row)? > threshold {
+            observed |= 1 << (15 - index);
+        }
+    }
+
+    // A marker turned clockwise by k quarters has its own top left corner at index k
+    let points = corners(quad);
+    let mut code = observed;
+    for turns in 0..4 {
+        if codes.contains(&code) {
+            let corner = |i: usize| points[(i + turns) % 4];
+            return Some(DetectedMarker {
+                code,
+                corners: Quad {
+                    top_left: corner(0),
+                    top_right: corner(1),
+                    bottom_right: corner(2),
+                    bottom_left: corner(3),
+                },
+            });
+        }
+        // Undo one clockwise quarter turn
+        code = rotate_code(rotate_code(rotate_code(code)));
+    }
+    None
+}
+
+/// Centres of dark discs with a concentric bright hole
+pub fn detect_circular_targets(image: &GrayImage) -> Vec<Point2<f32>> {
+    let contours = imageproc::contours::find_contours::<i32>(&dark_mask(image));
+
+    contours
+        .iter()
+        .enumerate()
+        .filter(|(_, contour)| contour.border_type == BorderType::Outer)
+        .filter_map(|(index, outer)| {
+            let (outer_area, outer_center) = polygon_area_centroid(outer)?;
+            let perimeter = imageproc::geometry::arc_length(&outer.points, true) as f32;
+            let circularity = 4.0 * std::f32::consts::PI * outer_area / perimeter.powi(2);
+            if outer_area < MIN_MARKER_AREA || circularity < 0.7 {
+                return None;
+            }
+            let outer_radius = (outer_area / std::f32::consts::PI).sqrt();
+
+            contours
+                .iter()
+                .filter(|hole| hole.border_type == BorderType::Hole && hole.parent == Some(index))
+                .filter_map(polygon_area_centroid)
+                .find(|(hole_area, hole_center)| {
+                    let ratio = (hole_area / outer_area).sqrt();
+                    (0.2..=0.7).contains(&ratio)
+                        && na::distance(hole_center, &outer_center) < 0.15 * outer_radius
+                })
+                .map(|(_, hole_center)| na::center(&outer_center, &hole_center))
+        })
+        .collect()
+}
+
+/// Area and centroid of the polygon through the contour points
+fn polygon_area_centroid(contour: &Contour<i32>) -> Option<(f32, Point2<f32>)> {
+    let points = &contour.points;
+    let (mut area, mut cx, mut cy) = (0f32, 0f32, 0f32);
+    for i in 0..points.len() {
+        let (a, b) = (points[i], points[(i + 1) % points.len()]);
+        let cross = (a.x * b.y - b.x * a.y) as f32;
+        area += cross;
+        cx += (a.x + b.x) as f32 * cross;
+        cy += (a.y + b.y) as f32 * cross;
+    }
+    if area.abs() < f32::EPSILON {
+        return None;
+    }
+    area /= 2.0;
+    Some((
+        area.abs(),
+        Point2::new(cx / (6.0 * area), cy / (6.0 * area)),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::fiducial_markers::{
+        is_white_cell, rotate_code, validate_codes, MarkerKind, MarkerLayout,
+    };
+    use crate::homography::transform_point;
+    use crate::{detect_square_markers, register_markers, render_square_marker};
+    use image::{GrayImage, Luma};
+    use na::{Matrix3, Point2};
+    use tlc_common::Quad;
+
+    const CODES: [u16; 4] = [0x8d6e, 0x4b39, 0xb2c5, 0x1f72];
+
+    fn layout(kind: MarkerKind) -> MarkerLayout {
+        let centers = [(15.0, 15.0), (185.0, 15.0), (185.0, 135.0), (15.0, 135.0)];
+        MarkerLayout {
+            kind,
+            marker_size: 20.0,
+            markers: CODES
+                .iter()
+                .zip(centers.iter())
+                .map(|(&code, &(x, y))| (code, Point2::new(x, y)))
+                .collect(),
+            plate: Quad {
+                top_left: Point2::new(50.0, 30.0),
+                top_right: Point2::new(150.0, 30.0),
+                bottom_right: Point2::new(150.0, 120.0),
+                bottom_left: Point2::new(50.0, 120.0),
+            },
+        }
+    }
+
+    /// Layout coordinates to image pixels with a slight perspective
+    fn camera() -> Matrix3<f32> {
+        Matrix3::new(3.4, 0.3, 60.0, -0.2, 3.3, 50.0, 0.0002, 0.0004, 1.0)
+    }
+
+    fn layout_intensity(layout: &MarkerLayout, p: &Point2<f32>) -> f32 {
+        let half = layout.marker_size / 2.0;
+        for &(code, center) in &layout.markers {
+            let (dx, dy) = (p.x - center.x, p.y - center.y);
+            match layout.kind {
+                MarkerKind::Square => {
+                    if dx.abs() < half && dy.abs() < half {
+                        let cell = layout.marker_size / 6.0;
+                        let col = ((dx + half) / cell) as u32;
+                        let row = ((dy + half) / cell) as u32;
+                        return if is_white_cell(code, row.min(5), col.min(5)) {
+                            250.0
+                        } else {
+                            20.0
+                        };
+                    }
+                }
+                MarkerKind::Circle => {
+                    let r = (dx * dx + dy * dy).sqrt();
+                    if r < half {
+                        return if r < half * 0.4 { 250.0 } else { 20.0 };
+                    }
+                }
+            }
+        }
+        let plate = &layout.plate;
+        if p.x > plate.top_left.x
+            && p.x < plate.bottom_right.x
+            && p.y > plate.top_left.y
+            && p.y < plate.bottom_right.y
+        {
+            200.0
+        } else {
+            240.0
+        }
+    }
+
+    /// Renders the layout as seen through the camera with 2 x 2 supersampling
+    fn render(layout: &MarkerLayout, camera: &Matrix3<f32>) -> GrayImage {
+        let to_layout = camera.try_inverse().unwrap();
+        GrayImage::from_fn(800, 600, |x, y| {
+            let sum: f32 = [(0.25, 0.25), (0.75, 0.25), (0.25, 0.75), (0.75, 0.75)]
+                .iter()
+                .map(|(sx, sy)| {
+                    let p = Point2::new(x as f32 + sx - 0.5, y as f32 + sy - 0.5);
+                    layout_intensity(layout, &transform_point(&to_layout, &p))
+                })
+                .sum();
+            Luma([(sum / 4.0).round() as u8])
+        })
+    }
+
+    fn max_plate_error(found: &Quad, layout: &MarkerLayout, camera: &Matrix3<f32>) -> f32 {
+        let expected = [
+            layout.plate.top_left,
+            layout.plate.top_right,
+            layout.plate.bottom_right,
+            layout.plate.bottom_left,
+        ];
+        crate::detection_result::corners(found)
+            .iter()
+            .zip(expected.iter())
+            .map(|(f, e)| na::distance(f, &transform_point(camera, e)))
+            .fold(0.0, f32::max)
+    }
+
+    #[test]
+    fn test_code_rotation() {
+        let given = 0x8000; // Only the top left bit
+        let when = rotate_code(given);
+
+        assert_eq!(when, 0x1000); // Top right bit
+        assert_eq!(rotate_code(rotate_code(rotate_code(when))), given);
+        assert!(validate_codes(&CODES).is_ok());
+        assert!(validate_codes(&[0x8000, 0x1000]).is_err());
+        assert!(validate_codes(&[0x0000]).is_err());
+    }
+
+    #[test]
+    fn test_detects_rendered_marker_in_any_orientation() {
+        let marker = render_square_marker(CODES[1], 10);
+        let mut given = GrayImage::from_pixel(200, 200, Luma([255u8]));
+        image::imageops::overlay(&mut given, &image::imageops::rotate90(&marker), 60, 60);
+
+        let when = detect_square_markers(&given, &CODES);
+
+        assert_eq!(when.len(), 1);
+        assert_eq!(when[0].code, CODES[1]);
+        // Turned clockwise, the printed top left corner is now the top right one
+        let corner = when[0].corners.top_left;
+        assert!((corner.x - 129.5).abs() < 1.0, "{:?}", corner);
+        assert!((corner.y - 69.5).abs() < 1.0, "{:?}", corner);
+    }
+
+    #[test]
+    fn test_register_square_markers() {
+        let layout = layout(MarkerKind::Square);
+        let given = render(&layout, &camera());
+
+        let when = register_markers(&given, &layout).unwrap();
+
+        assert_eq!(when.markers_found, 4);
+        assert!(when.reprojection_error < 0.5);
+        assert!(max_plate_error(&when.plate_quad(&layout), &layout, &camera()) < 1.0);
+    }
+
+    #[test]
+    fn test_register_with_occluded_marker() {
+        let layout = layout(MarkerKind::Square);
+        let mut given = render(&layout, &camera());
+        let hidden = transform_point(&camera(), &layout.markers[2].1);
+        imageproc::drawing::draw_filled_circle_mut(
+            &mut given,
+            (hidden.x as i32, hidden.y as i32),
+            60,
+            Luma([128u8]),
+        );
+
+        let when = register_markers(&given, &layout).unwrap();
+
+        assert_eq!(when.markers_found, 3);
+        assert!(max_plate_error(&when.plate_quad(&layout), &layout, &camera()) < 1.5);
+    }
+
+    #[test]
+    fn test_register_circular_targets() {
+        let layout = layout(MarkerKind::Circle);
+        let given = render(&layout, &camera());
+
+        let when = register_markers(&given, &layout).unwrap();
+
+        assert_eq!(when.markers_found, 4);
+        assert!(max_plate_error(&when.plate_quad(&layout), &layout, &camera()) < 1.5);
+    }
+
+    #[test]
+    fn test_missing_markers_fail() {
+        let layout = layout(MarkerKind::Square);
+        let given = GrayImage::from_pixel(800, 600, Luma([240u8]));
+
+        assert!(register_markers(&given, &layout).is_err());
+    }
+}
@@ -0,0 +1,104 @@
+use na::{Matrix3, Point2, SMatrix, SymmetricEigen, Vector3};
+
+/// Least squares homography mapping `from` onto `to` with the normalized direct linear
+/// transform. Needs at least four correspondences, not all on one line.
+pub(crate) fn fit_homography(from: &[Point2<f32>], to: &[Point2<f32>]) -> Option<Matrix3<f32>> {
+    if from.len() < 4 || from.len() != to.len() {
+        return None;
+    }
+    let (from_norm, from_points) = normalize(from)?;
+    let (to_norm, to_points) = normalize(to)?;
+
+    // Each correspondence adds two rows to A with A h = 0, accumulated as A^T A
+    let mut ata = SMatrix::<f64, 9, 9>::zeros();
+    for (p, q) in from_points.iter().zip(to_points.iter()) {
+        let rows = [
+            [-p.x, -p.y, -1.0, 0.0, 0.0, 0.0, q.x * p.x, q.x * p.y, q.x],
+            [0.0, 0.0, 0.0, -p.x, -p.y, -1.0, q.y * p.x, q.y * p.y, q.y],
+        ];
+        for row in rows.iter() {
+            let row = SMatrix::<f64, 1, 9>::from_row_slice(row);
+            ata += row.transpose() * row;
+        }
+    }
+
+    let eigen = SymmetricEigen::new(ata);
+    let smallest = eigen.eigenvalues.imin();
+    let h = eigen.eigenvectors.column(smallest);
+    let normalized = Matrix3::new(h[0], h[1], h[2], h[3], h[4], h[5], h[6], h[7], h[8]);
+
+    let homography = to_norm.try_inverse()? * normalized * from_norm;
+    if homography[(2, 2)].abs() < f64::EPSILON {
+        return None;
+    }
+    Some((homography / homography[(2, 2)]).cast::<f32>())
+}
+
+pub(crate) fn transform_point(homography: &Matrix3<f32>, point: &Point2<f32>) -> Point2<f32> {
+    let mapped = homography * Vector3::new(point.x, point.y, 1.0);
+    Point2::new(mapped.x / mapped.z, mapped.y / mapped.z)
+}
+
+/// Moves the centroid to the origin with a mean distance of sqrt(2) for numerical stability
+fn normalize(points: &[Point2<f32>]) -> Option<(Matrix3<f64>, Vec<Point2<f64>>)> {
+    let count = points.len() as f64;
+    let (cx, cy) = points.iter().fold((0.0, 0.0), |(x, y), p| {
+        (x + p.x as f64 / count, y + p.y as f64 / count)
+    });
+    let mean_distance = points
+        .iter()
+        .map(|p| ((p.x as f64 - cx).powi(2) + (p.y as f64 - cy).powi(2)).sqrt())
+        .sum::<f64>()
+        / count;
+    if mean_distance <= f64::EPSILON {
+        return None;
+    }
+
+    let scale = std::f64::consts::SQRT_2 / mean_distance;
+    let transform = Matrix3::new(
+        scale,
+        0.0,
+        -scale * cx,
+        0.0,
+        scale,
+        -scale * cy,
+        0.0,
+        0.0,
+        1.0,
+    );
+    let normalized = points
+        .iter()
+        .map(|p| Point2::new((p.x as f64 - cx) * scale, (p.y as f64 - cy) * scale))
+        .collect();
+    Some((transform, normalized))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::homography::{fit_homography, transform_point};
+    use na::{Matrix3, Point2};
+
+    #[test]
+    fn test_recovers_projection() {
+        let then = Matrix3::new(0.9, -0.1, 40.0, 0.05, 1.1, 25.0, 0.0002, -0.0001, 1.0);
+        let from: Vec<Point2<f32>> = [(0.0, 0.0), (200.0, 0.0), (200.0, 100.0), (0.0, 100.0)]
+            .iter()
+            .chain([(50.0, 30.0), (120.0, 80.0)].iter())
+            .map(|&(x, y)| Point2::new(x, y))
+            .collect();
+        let to: Vec<Point2<f32>> = from.iter().map(|p| transform_point(&then, p)).collect();
+
+        let when = fit_homography(&from, &to).unwrap();
+
+        for (p, q) in from.iter().zip(to.iter()) {
+            assert!(na::distance(&transform_point(&when, p), q) < 1e-2);
+        }
+    }
+
+    #[test]
+    fn test_too_few_points_fail() {
+        let given: Vec<Point2<f32>> = (0..3).map(|i| Point2::new(i as f32, i as f32)).collect();
+
+        assert!(fit_homography(&given, &given).is_none());
+    }
+}
@@ -0,0 +1,580 @@
+use crate::detection_result::{self, Corner, DetectionFailure, DetectionResult};
+use crate::plate_detector::PlateDetector;
+use image::GrayImage;
+use imageproc::hough::PolarLine;
+use log::info;
+use na::{Matrix2, Point2, Vector2};
+use tlc_common::{auto_canny, Quad};
+
+/// Maximal angle between a line and the dominant orientation of its family
+const FAMILY_TOLERANCE_DEGREES: u32 = 10;
+
+/// Minimal angle between the two families, plate sides are roughly perpendicular
+const MIN_FAMILY_SEPARATION_DEGREES: u32 = 45;
+
+/// Parallel lines closer than this fraction of the smaller image side belong to one edge
+const MERGE_DISTANCE_FRACTION: f32 = 0.05;
+
+/// Minimal distance of opposite plate edges as fraction of the smaller image side
+const MIN_EDGE_DISTANCE_FRACTION: f32 = 0.1;
+
+/// How far visible corners may lie outside the image as fraction of the image size
+const OUTSIDE_MARGIN_FRACTION: f32 = 0.05;
+
+/// Smallest angle between two orientations in degrees, which repeat every 180 degrees
+fn angle_distance(a: u32, b: u32) -> u32 {
+    let d = (a as i32 - b as i32).rem_euclid(180) as u32;
+    d.min(180 - d)
+}
+
+/// A line of all points `p` with `normal · p = offset`, with a unit normal
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Line {
+    normal: Vector2<f32>,
+    offset: f32,
+    /// Whether the line is an image border standing in for an invisible plate edge
+    inferred: bool,
+    /// Hough accumulator votes of the strongest detected line of this edge
+    votes: u32,
+}
+
+impl Line {
+    fn new(normal: Vector2<f32>, offset: f32) -> Self {
+        Line {
+            normal,
+            offset,
+            inferred: false,
+            votes: 0,
+        }
+    }
+
+    fn from_polar(line: &PolarLine) -> Self {
+        let (sin, cos) = (line.angle_in_degrees as f32).to_radians().sin_cos();
+        Line::new(Vector2::new(cos, sin), line.r)
+    }
+
+    fn from_voted(&(line, votes): &(PolarLine, u32)) -> Self {
+        Line {
+            votes,
+            ..Line::from_polar(&line)
+        }
+    }
+
+    /// The same line with the normal flipped, if needed, to point along `direction`.
+    /// Offsets of aligned parallel lines are comparable, even across the 0 / 180 degree wrap.
+    fn aligned_to(self, direction: &Vector2<f32>) -> Self {
+        if self.normal.dot(direction) < 0.0 {
+            Line {
+                normal: -self.normal,
+                offset: -self.offset,
+                ..self
+            }
+        } else {
+            self
+        }
+    }
+
+    /// Mean of lines aligned to the same direction, with the votes of the strongest one
+    fn average(lines: &[Line]) -> Self {
+        let count = lines.len() as f32;
+        let normal = lines
+            .iter()
+            .fold(Vector2::zeros(), |sum: Vector2<f32>, l| sum + l.normal);
+        let offset = lines.iter().map(|l| l.offset).sum::<f32>() / count;
+        Line {
+            votes: lines.iter().map(|l| l.votes).max().unwrap_or(0),
+            ..Line::new(normal.normalize(), offset)
+        }
+    }
+
+    /// Whether the line runs close to and along one of the image borders
+    fn is_along_border(&self, width: u32, height: u32, margin: f32) -> bool {
+        let (w, h) = (width as f32, height as f32);
+        let max_angle = (FAMILY_TOLERANCE_DEGREES as f32).to_radians().cos();
+        let vertical = self.normal.x.abs() >= max_angle;
+        let horizontal = self.normal.y.abs() >= max_angle;
+
+        let distance = |x: f32, y: f32| (self.normal.dot(&Vector2::new(x, y)) - self.offset).abs();
+        (vertical && (distance(0.0, h / 2.0) < margin || distance(w, h / 2.0) < margin))
+            || (horizontal && (distance(w / 2.0, 0.0) < margin || distance(w / 2.0, h) < margin))
+    }
+
+    fn intersect(&self, other: &Line) -> Option<Point2<f32>> {
+        let normals = Matrix2::from_rows(&[self.normal.transpose(), other.normal.transpose()]);
+        // Near parallel lines do not intersect in a stable point
+        if normals.determinant().abs() < 1e-3 {
+            return None;
+        }
+        normals
+            .try_inverse()
+            .map(|inverse| Point2::from(inverse * Vector2::new(self.offset, other.offset)))
+    }
+}
+
+/// Lines of similar orientation, merged into the distinct edges they represent
+struct LineFamily {
+    direction: Vector2<f32>,
+    /// Averaged edges sorted by their offset along the direction
+    edges: Vec<Line>,
+}
+
+impl LineFamily {
+    fn new(
+        lines: &[(PolarLine, u32)],
+        orientation: u32,
+        merge_distance: f32,
+        (width, height): (u32, u32),
+    ) -> Self {
+        let direction = Line::from_polar(&PolarLine {
+            r: 0.0,
+            angle_in_degrees: orientation,
+        })
+        .normal;
+
+        let mut members: Vec<Line> = lines
+            .iter()
+            .filter(|(l, _)| {
+                angle_distance(l.angle_in_degrees, orientation) <= FAMILY_TOLERANCE_DEGREES
+            })
+            .map(|l| Line::from_voted(l).aligned_to(&direction))
+            // Edges along the image borders are mostly artifacts of the image acquisition
+            .filter(|l| !l.is_along_border(width, height, merge_distance))
+            .collect();
+        members.sort_by(|a, b| a.offset.total_cmp(&b.offset));
+
+        // Neighbouring lines are duplicates of one edge, e.g. both borders of a thick edge
+        let mut groups: Vec<Vec<Line>> = vec![];
+        for line in members {
+            match groups.last_mut() {
+                Some(group) if line.offset - group.last().unwrap().offset <= merge_distance => {
+                    group.push(line)
+                }
+                _ => groups.push(vec![line]),
+            }
+        }
+
+        LineFamily {
+            direction,
+            edges: groups.iter().map(|group| Line::average(group)).collect(),
+        }
+    }
+
+    /// The outermost edges, which are the opposite plate sides
+    fn extreme_edges(&self, min_distance: f32) -> Option<(Line, Line)> {
+        let (first, last) = (*self.edges.first()?, *self.edges.last()?);
+        if last.offset - first.offset >= min_distance {
+            Some((first, last))
+        } else {
+            None
+        }
+    }
+
+    /// With only one visible side the image border parallel to it stands in for the other
+    /// side. Out of the two borders the one towards which the `across` sides continue is used.
+    fn inferred_edges(
+        &self,
+        across: (Line, Line),
+        edges: &GrayImage,
+        min_distance: f32,
+    ) -> Option<(Line, Line)> {
+        let visible = Line::average(&self.edges);
+        let (w, h) = (edges.width() as f32, edges.height() as f32);
+        let borders = if self.direction.x.abs() > self.direction.y.abs() {
+            [
+                Line::new(Vector2::new(1.0, 0.0), 0.0),
+                Line::new(Vector2::new(1.0, 0.0), w),
+            ]
+        } else {
+            [
+                Line::new(Vector2::new(0.0, 1.0), 0.0),
+                Line::new(Vector2::new(0.0, 1.0), h),
+            ]
+        };
+
+        let support = |border: &Line| {
+            let quad = Quad {
+                top_left: visible.intersect(&across.0)?,
+                top_right: visible.intersect(&across.1)?,
+                bottom_right: border.intersect(&across.1)?,
+                bottom_left: border.intersect(&across.0)?,
+            };
+            Some(detection_result::edge_support(&quad, edges))
+        };
+        let border = borders
+            .iter()
+            .map(|b| b.aligned_to(&self.direction))
+            .filter(|b| (b.offset - visible.offset).abs() >= min_distance)
+            .filter_map(|b| support(&b).map(|s| (b, s)))
+            .max_by(|a, b| a.1.total_cmp(&b.1))?
+            .0;
+
+        let inferred = Line {
+            inferred: true,
+            ..border
+        };
+        if inferred.offset < visible.offset {
+            Some((inferred, visible))
+        } else {
+            Some((visible, inferred))
+        }
+    }
+}
+
+/// Votes of the accumulator cell of `line`, counted like `imageproc::hough::detect_lines`,
+/// which does not return them
+fn votes(edge_pixels: &[(f32, f32)], line: &PolarLine) -> u32 {
+    let (sin, cos) = (line.angle_in_degrees as f32).to_radians().sin_cos();
+    let bin = line.r as i32;
+    edge_pixels
+        .iter()
+        .filter(|(x, y)| (x * cos + y * sin) as i32 == bin)
+        .count() as u32
+}
+
+/// Mean share of the visible sides covered by the votes of their Hough lines, a straight
+/// edge along the whole side votes about once per pixel
+fn vote_support(sides: &[(Line, Point2<f32>, Point2<f32>)]) -> f32 {
+    let supports: Vec<f32> = sides
+        .iter()
+        .filter(|(line, _, _)| !line.inferred)
+        .map(|(line, start, end)| (line.votes as f32 / na::distance(start, end).max(1.0)).min(1.0))
+        .collect();
+    if supports.is_empty() {
+        0.0
+    } else {
+        supports.iter().sum::<f32>() / supports.len() as f32
+    }
+}
+
+/// The two most common line orientations which are at least `MIN_FAMILY_SEPARATION_DEGREES` apart
+fn dominant_orientations(lines: &[PolarLine]) -> Option<(u32, u32)> {
+    let support = |orientation: u32| {
+        lines
+            .iter()
+            .filter(|l| angle_distance(l.angle_in_degrees, orientation) <= FAMILY_TOLERANCE_DEGREES)
+            .count()
+    };
+    let supports: Vec<usize> = (0..180).map(support).collect();
+
+    let first = (0..180u32).max_by_key(|&o| supports[o as usize])?;
+    let second = (0..180u32)
+        .filter(|&o| angle_distance(o, first) >= MIN_FAMILY_SEPARATION_DEGREES)
+        .max_by_key(|&o| supports[o as usize])?;
+    if supports[second as usize] == 0 {
+        return None;
+    }
+    Some((first, second))
+}
+
+/// Finds the plate corners from Hough lines of any orientation.
+/// The lines are clustered into the two orientations of the plate sides and the
+/// outermost edges of both are intersected.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HoughLineDetector;
+
+impl PlateDetector for HoughLineDetector {
+    fn detect_scaled(&self, image: &GrayImage, expected_aspect_ratio: f32) -> DetectionResult {
+        let (width, height) = image.dimensions();
+        let min_dim = width.min(height);
+
+        let canny_edge = auto_canny(image);
+
+        // Detect all lines in the processed image
+        let lines_detected = imageproc::hough::detect_lines(
+            &canny_edge,
+            imageproc::hough::LineDetectionOptions {
+                vote_threshold: 40,
+                suppression_radius: 8,
+            },
+        );
+        if lines_detected.is_empty() {
+            return DetectionResult::failed(DetectionFailure::NoLines);
+        }
+
+        let (first, second) = match dominant_orientations(&lines_detected) {
+            Some(orientations) => orientations,
+            None => return DetectionResult::failed(DetectionFailure::NoIntersections),
+        };
+        let edge_pixels: Vec<(f32, f32)> = canny_edge
+            .enumerate_pixels()
+            .filter(|(_, _, p)| p[0] > 0)
+            .map(|(x, y, _)| (x as f32, y as f32))
+            .collect();
+        let voted: Vec<(PolarLine, u32)> = lines_detected
+            .iter()
+            .map(|line| (*line, votes(&edge_pixels, line)))
+            .collect();
+        let merge_distance = min_dim as f32 * MERGE_DISTANCE_FRACTION;
+        let families = [
+            LineFamily::new(&voted, first, merge_distance, (width, height)),
+            LineFamily::new(&voted, second, merge_distance, (width, height)),
+        ];
+        info!(
+            "Total lines: {}. Orientations {} and {} with {} and {} edges",
+            lines_detected.len(),
+            first,
+            second,
+            families[0].edges.len(),
+            families[1].edges.len()
+        );
+
+        let min_distance = min_dim as f32 * MIN_EDGE_DISTANCE_FRACTION;
+        let sides = (
+            families[0].extreme_edges(min_distance),
+            families[1].extreme_edges(min_distance),
+        );
+        let maybe_sides = match sides {
+            (Some(a), Some(b)) => Some((a, b)),
+            (Some(a), None) => families[1]
+                .inferred_edges(a, &canny_edge, min_distance)
+                .map(|b| (a, b)),
+            (None, Some(b)) => families[0]
+                .inferred_edges(b, &canny_edge, min_distance)
+                .map(|a| (a, b)),
+            // With an invisible side in both families only one corner is left
+            (None, None) => None,
+        };
+        let (a, b) = match maybe_sides {
+            Some(sides) => sides,
+            None => return DetectionResult::failed(DetectionFailure::MissingCorners),
+        };
+
+        // Going around the plate the corners alternate between both families
+        let pairs = [(a.0, b.0), (a.0, b.1), (a.1, b.1), (a.1, b.0)];
+        let mut points = [Point2::origin(); 4];
+        let mut inferred = [false; 4];
+        for (i, (l1, l2)) in pairs.iter().enumerate() {
+            match l1.intersect(l2) {
+                Some(point) => points[i] = point,
+                None => return DetectionResult::failed(DetectionFailure::Degenerate),
+            }
+            inferred[i] = l1.inferred || l2.inferred;
+        }
+
+        let (margin_x, margin_y) = (
+            width as f32 * OUTSIDE_MARGIN_FRACTION,
+            height as f32 * OUTSIDE_MARGIN_FRACTION,
+        );
+        let outside = |p: &Point2<f32>| {
+            p.x < -margin_x
+                || p.y < -margin_y
+                || p.x > width as f32 + margin_x
+                || p.y > height as f32 + margin_y
+        };
+        if points.iter().any(outside) {
+            return DetectionResult::failed(DetectionFailure::Degenerate);
+        }
+
+        let order = detection_result::corner_order(&points);
+        let detected = Quad {
+            top_left: points[order[0]],
+            top_right: points[order[1]],
+            bottom_right: points[order[2]],
+            bottom_left: points[order[3]],
+        };
+        let min_area = (width * height) as f32 * 0.01;
+        if !detection_result::is_valid_quad(&detected, min_area) {
+            return DetectionResult::failed(DetectionFailure::Degenerate);
+        }
+
+        let inferred_corners: Vec<Corner> = order
+            .iter()
+            .enumerate()
+            .filter(|(_, &i)| inferred[i])
+            .filter_map(|(corner, _)| Corner::from_index(corner as u8))
+            .collect();
+
+        // Each side runs between the corners of its line, see `pairs`
+        let sides = [
+            (a.0, points[0], points[1]),
+            (a.1, points[3], points[2]),
+            (b.0, points[0], points[3]),
+            (b.1, points[1], points[2]),
+        ];
+        let confidence = detection_result::confidence(
+            &detected,
+            vote_support(&sides),
+            expected_aspect_ratio,
+            inferred_corners.len(),
+        );
+
+        DetectionResult {
+            quad: Some(detected),
+            confidence,
+            inferred_corners,
+            failure: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::hough_detector::{angle_distance, dominant_orientations, votes, Line, LineFamily};
+    use crate::test_util::{draw_plate, rotated_plate};
+    use crate::{Corner, HoughLineDetector, PlateDetector};
+    use image::{GrayImage, Luma};
+    use imageproc::hough::PolarLine;
+    use na::{distance, Vector2};
+
+    fn polar(r: f32, angle_in_degrees: u32) -> PolarLine {
+        PolarLine {
+            r,
+            angle_in_degrees,
+        }
+    }
+
+    #[test]
+    fn test_angle_distance_wraps() {
+        assert_eq!(angle_distance(10, 20), 10);
+        assert_eq!(angle_distance(179, 1), 2);
+        assert_eq!(angle_distance(0, 90), 90);
+    }
+
+    #[test]
+    fn test_average_across_wrap() {
+        // Both describe vertical lines close to x = 100
+        let given = [polar(-100.0, 179), polar(100.0, 1)];
+        let direction = Vector2::new(1.0, 0.0);
+
+        let when = Line::average(
+            &given
+                .iter()
+                .map(|l| Line::from_polar(l).aligned_to(&direction))
+                .collect::<Vec<Line>>(),
+        );
+
+        assert!((when.offset - 100.0).abs() < 1e-3);
+        assert!((when.normal - direction).norm() < 1e-3);
+    }
+
+    #[test]
+    fn test_family_merges_close_lines_and_skips_borders() {
+        let given = [
+            (polar(10.0, 0), 60),
+            (polar(12.0, 1), 80),
+            (polar(90.0, 0), 70),
+            (polar(50.0, 90), 90),
+            (polar(254.0, 0), 200),
+        ];
+
+        let when = LineFamily::new(&given, 0, 5.0, (256, 256));
+
+        assert_eq!(when.edges.len(), 2);
+        assert!((when.edges[0].offset - 11.0).abs() < 1e-3);
+        assert_eq!(when.edges[0].votes, 80);
+        assert!((when.edges[1].offset - 90.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_votes_of_detected_lines() {
+        let mut given = GrayImage::new(128, 128);
+        imageproc::drawing::draw_line_segment_mut(
+            &mut given,
+            (40.0, 20.0),
+            (40.0, 99.0),
+            Luma([255u8]),
+        );
+        let edge_pixels: Vec<(f32, f32)> = given
+            .enumerate_pixels()
+            .filter(|(_, _, p)| p[0] > 0)
+            .map(|(x, y, _)| (x as f32, y as f32))
+            .collect();
+
+        let when = votes(&edge_pixels, &polar(40.0, 0));
+
+        assert_eq!(when, 80);
+        assert_eq!(votes(&edge_pixels, &polar(60.0, 0)), 0);
+    }
+
+    #[test]
+    fn test_confidence_follows_votes() {
+        let corners = rotated_plate((128.0, 128.0), (70.0, 140.0), 0.0);
+        let solid = draw_plate(256, 256, &corners);
+        // The same plate with gaps in its edges
+        let mut broken = solid.clone();
+        for y in (0..256).step_by(8) {
+            for x in 0..256 {
+                for dy in 0..4 {
+                    broken.put_pixel(x, y + dy, Luma([40u8]));
+                }
+            }
+        }
+
+        let solid = HoughLineDetector.detect_scaled(&solid, 2.0);
+        let broken = HoughLineDetector.detect_scaled(&broken, 2.0);
+
+        assert!(solid.confidence > 0.95, "{:?}", solid);
+        assert!(broken.confidence < solid.confidence, "{:?}", broken);
+    }
+
+    #[test]
+    fn test_dominant_orientations() {
+        let given = [
+            polar(10.0, 30),
+            polar(80.0, 31),
+            polar(20.0, 120),
+            polar(70.0, 121),
+            polar(5.0, 60),
+        ];
+
+        let when = dominant_orientations(&given).unwrap();
+
+        assert!(angle_distance(when.0, 30) <= 10 || angle_distance(when.0, 120) <= 10);
+        assert!(angle_distance(when.0, when.1) >= 80);
+    }
+
+    #[test]
+    fn test_detects_any_rotation() {
+        for &degrees in [0.0, 10.0, 25.0, 45.0, 60.0, 80.0, 90.0, 135.0].iter() {
+            let expected = rotated_plate((128.0, 128.0), (70.0, 140.0), degrees);
+            let given = draw_plate(256, 256, &expected);
+
+            let when = HoughLineDetector.detect_scaled(&given, 2.0);
+
+            assert_eq!(when.failure, None, "rotated by {}", degrees);
+            let quad = when.quad.unwrap();
+            // Every expected corner has a close detected corner
+            for corner in &expected {
+                let closest = crate::detection_result::corners(&quad)
+                    .iter()
+                    .map(|p| distance(p, corner))
+                    .fold(f32::MAX, f32::min);
+                assert!(closest < 6.0, "rotated by {}: {}", degrees, closest);
+            }
+            assert!(when.inferred_corners.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_top_left_is_orientation_independent() {
+        let upright = draw_plate(256, 256, &rotated_plate((128.0, 128.0), (70.0, 140.0), 0.0));
+        let flipped = draw_plate(
+            256,
+            256,
+            &rotated_plate((128.0, 128.0), (70.0, 140.0), 180.0),
+        );
+
+        let upright = HoughLineDetector.detect_scaled(&upright, 2.0).quad.unwrap();
+        let flipped = HoughLineDetector.detect_scaled(&flipped, 2.0).quad.unwrap();
+
+        assert!(distance(&upright.top_left, &flipped.top_left) < 2.0);
+        assert!(upright.top_left.x < upright.bottom_right.x);
+        assert!(upright.top_left.y < upright.bottom_right.y);
+    }
+
+    #[test]
+    fn test_cut_off_plate_infers_corners_at_border() {
+        let given = draw_plate(256, 256, &rotated_plate((128.0, 220.0), (80.0, 160.0), 0.0));
+
+        let when = HoughLineDetector.detect_scaled(&given, 2.0);
+
+        assert!(when.is_detected());
+        assert_eq!(
+            when.inferred_corners,
+            vec![Corner::BottomRight, Corner::BottomLeft]
+        );
+        let quad = when.quad.unwrap();
+        assert!((quad.bottom_left.y - 256.0).abs() < 1e-3);
+        assert!((quad.bottom_right.y - 256.0).abs() < 1e-3);
+    }
+}
@@ -1,5 +1,30 @@
 extern crate nalgebra as na;
 
-pub use plate_detector::Detector;
+pub use camera_calibration::{
+    calibrate_camera, calibrate_from_points, CameraCalibration, Checkerboard,
+};
+pub use contour_detector::ContourDetector;
+pub use corner_refinement::refine_corners;
+pub use detection_result::{Corner, DetectionFailure, DetectionResult};
+pub use fiducial_markers::{
+    detect_circular_targets, detect_square_markers, register_markers, render_square_marker,
+    DetectedMarker, MarkerKind, MarkerLayout, MarkerRegistration,
+};
+pub use hough_detector::HoughLineDetector;
+pub use plate_detector::{
+    detection_downscale_factor, CombinedDetector, Detector, PlateDetector, DETECTION_SIZE,
+    EXPECTED_ASPECT_RATIO,
+};
+pub use plate_tracker::{PlateTracker, TrackingOptions, TrackingState};
 
+mod camera_calibration;
+mod contour_detector;
+mod corner_refinement;
+mod detection_result;
+mod fiducial_markers;
+mod homography;
+mod hough_detector;
 mod plate_detector;
+mod plate_tracker;
+#[cfg(test)]
+mod test_util;
@@ -0,0 +1,399 @@
+use crate::detection_result::corners;
+use crate::hough_detector::HoughLineDetector;
+use crate::plate_detector::{detection_downscale_factor, PlateDetector, EXPECTED_ASPECT_RATIO};
+use image::{DynamicImage, GenericImageView, GrayImage};
+use na::{distance, Point2};
+use tlc_common::Quad;
+
+pub struct TrackingOptions {
+    /// Weight of the newest detection in the exponential smoothing. In (0, 1]
+    pub smoothing: f32,
+    /// Frames without a detection before the track is dropped
+    pub max_missed_frames: u32,
+    /// Mean corner motion relative to the frame diagonal which still counts as movement.
+    /// Motion at or above this value results in a stability of 0
+    pub motion_tolerance: f32,
+    /// Mean corner jump relative to the frame diagonal which restarts the track
+    pub jump_threshold: f32,
+    /// Consecutive detections required until the track can be fully stable
+    pub warmup_frames: u32,
+    /// Margin relative to the frame size which all corners need to keep from the border
+    pub border_margin: f32,
+}
+
+impl Default for TrackingOptions {
+    fn default() -> Self {
+        TrackingOptions {
+            smoothing: 0.3,
+            max_missed_frames: 5,
+            motion_tolerance: 0.01,
+            jump_threshold: 0.1,
+            warmup_frames: 10,
+            border_margin: 0.02,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TrackingState {
+    /// The smoothed plate corners in frame coordinates
+    pub quad: Option<Quad>,
+    /// 0 for no or a moving plate up to 1 for a plate which is steady for several frames
+    pub stability: f32,
+    /// All corners are inside the frame and not estimated from the frame border
+    pub fully_visible: bool,
+}
+
+impl TrackingState {
+    pub fn is_steady(&self, min_stability: f32) -> bool {
+        self.quad.is_some() && self.fully_visible && self.stability >= min_stability
+    }
+}
+
+/// Detects the plate on consecutive low resolution frames, e.g. a camera preview,
+/// and smooths the corners over time
+pub struct PlateTracker {
+    options: TrackingOptions,
+    strategy: Box<dyn PlateDetector>,
+    buffer: GrayImage,
+    smoothed: Option<Quad>,
+    motion: f32,
+    tracked_frames: u32,
+    missed_frames: u32,
+}
+
+impl PlateTracker {
+    pub fn new(options: TrackingOptions) -> Self {
+        PlateTracker {
+            options,
+            strategy: Box::new(HoughLineDetector),
+            buffer: GrayImage::new(0, 0),
+            smoothed: None,
+            motion: 0.0,
+            tracked_frames: 0,
+            missed_frames: 0,
+        }
+    }
+
+    /// Replaces the default Hough line based detection
+    pub fn with_strategy(mut self, strategy: Box<dyn PlateDetector>) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    pub fn update(&mut self, frame: &DynamicImage) -> TrackingState {
+        let (width, height) = frame.dimensions();
+        match frame {
+            DynamicImage::ImageLuma8(gray) => {
+                self.update_luma(gray.as_raw(), width, height, width as usize)
+            }
+            _ => self.update_luma(&frame.to_luma8(), width, height, width as usize),
+        }
+        .expect("Image buffer matches its dimensions")
+    }
+
+    /// Processes a luma plane, e.g. the Y plane of a YUV camera frame
+    pub fn update_luma(
+        &mut self,
+        luma: &[u8],
+        width: u32,
+        height: u32,
+        row_stride: usize,
+    ) -> Result<TrackingState, String> {
+        if width == 0 || height == 0 {
+            return Err("Empty frame".to_string());
+        }
+        // The row stride comes from the camera, so the size may overflow
+        let required = (height as usize - 1)
+            .checked_mul(row_stride)
+            .and_then(|v| v.checked_add(width as usize));
+        if row_stride < width as usize || required.map_or(true, |required| luma.len() < required) {
+            return Err(format!(
+                "Luma plane of {} bytes does not fit {}x{} with row stride {}",
+                luma.len(),
+                width,
+                height,
+                row_stride
+            ));
+        }
+
+        let downscale_factor = detection_downscale_factor(width, height);
+        self.downsample_into_buffer(luma, width, height, row_stride, downscale_factor);
+
+        let detection = self
+            .strategy
+            .detect_scaled(&self.buffer, EXPECTED_ASPECT_RATIO)
+            .scaled(2u32.pow(downscale_factor) as f32)
+            .quad;
+
+        Ok(self.track(detection, width, height))
+    }
+
+    pub fn reset(&mut self) {
+        self.smoothed = None;
+        self.motion = 0.0;
+        self.tracked_frames = 0;
+        self.missed_frames = 0;
+    }
+
+    fn downsample_into_buffer(
+        &mut self,
+        luma: &[u8],
+        width: u32,
+        height: u32,
+        row_stride: usize,
+        downscale_factor: u32,
+    ) {
+        let factor = 2u32.pow(downscale_factor);
+        let (nw, nh) = (width / factor, height / factor);
+        if self.buffer.dimensions() != (nw, nh) {
+            self.buffer = GrayImage::new(nw, nh);
+        }
+
+        // Average each block to avoid aliasing of the plate edges
+        let block_size = factor as usize;
+        let block_area = (block_size * block_size) as u32;
+        for (x, y, p) in self.buffer.enumerate_pixels_mut() {
+            let (left, top) = (x as usize * block_size, y as usize * block_size);
+            let sum: u32 = (top..top + block_size)
+                .map(|row| {
+                    let start = row * row_stride + left;
+                    luma[start..start + block_size]
+                        .iter()
+                        .map(|&v| v as u32)
+                        .sum::<u32>()
+                })
+                .sum();
+            p[0] = (sum / block_area) as u8;
+        }
+    }
+
+    fn track(&mut self, detection: Option<Quad>, width: u32, height: u32) -> TrackingState {
+        let diagonal = (width as f32).hypot(height as f32);
+
+        match (detection, self.smoothed) {
+            (Some(quad), Some(previous)) => {
+                self.missed_frames = 0;
+                let motion = mean_corner_distance(&previous, &quad) / diagonal;
+                if motion > self.options.jump_threshold {
+                    // Most likely a different plate or a large camera movement
+                    self.restart(quad);
+                } else {
+                    let alpha = self.options.smoothing;
+                    self.smoothed = Some(blend(&previous, &quad, alpha));
+                    self.motion = alpha * motion + (1.0 - alpha) * self.motion;
+                    self.tracked_frames += 1;
+                }
+            }
+            (Some(quad), None) => self.restart(quad),
+            (None, _) => {
+                self.missed_frames += 1;
+                if self.missed_frames > self.options.max_missed_frames {
+                    self.reset();
+                }
+            }
+        }
+
+        let fully_visible = self
+            .smoothed
+            .is_some_and(|quad| self.is_inside(&quad, width, height));
+        let stability = match self.smoothed {
+            Some(_) if self.missed_frames == 0 => {
+                let steadiness = (1.0 - self.motion / self.options.motion_tolerance).max(0.0);
+                let warmup = (self.tracked_frames as f32
+                    / self.options.warmup_frames.max(1) as f32)
+                    .min(1.0);
+                steadiness * warmup
+            }
+            _ => 0.0,
+        };
+
+        TrackingState {
+            quad: self.smoothed,
+            stability,
+            fully_visible,
+        }
+    }
+
+    fn restart(&mut self, quad: Quad) {
+        self.smoothed = Some(quad);
+        self.motion = 0.0;
+        self.tracked_frames = 1;
+        self.missed_frames = 0;
+    }
+
+    fn is_inside(&self, quad: &Quad, width: u32, height: u32) -> bool {
+        let margin_x = width as f32 * self.options.border_margin;
+        let margin_y = height as f32 * self.options.border_margin;
+
+        corners(quad).iter().all(|p| {
+            p.x >= margin_x
+                && p.x <= width as f32 - margin_x
+                && p.y >= margin_y
+                && p.y <= height as f32 - margin_y
+        })
+    }
+}
+
+impl Default for PlateTracker {
+    fn default() -> Self {
+        PlateTracker::new(TrackingOptions::default())
+    }
+}
+
+fn mean_corner_distance(a: &Quad, b: &Quad) -> f32 {
+    corners(a)
+        .iter()
+        .zip(corners(b).iter())
+        .map(|(pa, pb)| distance(pa, pb))
+        .sum::<f32>()
+        / 4.0
+}
+
+fn blend(previous: &Quad, current: &Quad, alpha: f32) -> Quad {
+    let mix = |p: Point2<f32>, c: Point2<f32>| Point2::from(p.coords.lerp(&c.coords, alpha));
+    Quad {
+        top_left: mix(previous.top_left, current.top_left),
+        top_right: mix(previous.top_right, current.top_right),
+        bottom_right: mix(previous.bottom_right, current.bottom_right),
+        bottom_left: mix(previous.bottom_left, current.bottom_left),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::plate_tracker::{PlateTracker, TrackingOptions};
+    use crate::test_util::rect_quad;
+    use image::{DynamicImage, GrayImage, Luma};
+    use imageproc::rect::Rect;
+
+    #[test]
+    fn test_steady_plate_becomes_stable() {
+        let mut tracker = PlateTracker::default();
+        let given = rect_quad(100.0, 50.0, 400.0, 550.0);
+
+        let first = tracker.track(Some(given), 640, 640);
+        assert!(first.stability < 0.5);
+        assert!(!first.is_steady(0.9));
+
+        let mut when = first;
+        for _ in 0..20 {
+            when = tracker.track(Some(given), 640, 640);
+        }
+
+        assert!(when.fully_visible);
+        assert!(when.stability > 0.99);
+        assert!(when.is_steady(0.9));
+        assert_eq!(when.quad, Some(given));
+    }
+
+    #[test]
+    fn test_moving_plate_is_unstable() {
+        let mut tracker = PlateTracker::default();
+
+        let mut when = None;
+        for i in 0..20 {
+            let offset = (i % 2) as f32 * 20.0;
+            when = Some(tracker.track(
+                Some(rect_quad(100.0 + offset, 50.0, 400.0 + offset, 550.0)),
+                640,
+                640,
+            ));
+        }
+
+        let when = when.unwrap();
+        assert!(when.stability < 0.1);
+        assert!(!when.is_steady(0.5));
+    }
+
+    #[test]
+    fn test_smoothing_dampens_jitter() {
+        let mut tracker = PlateTracker::default();
+        tracker.track(Some(rect_quad(100.0, 50.0, 400.0, 550.0)), 640, 640);
+
+        let when = tracker.track(Some(rect_quad(110.0, 50.0, 410.0, 550.0)), 640, 640);
+
+        let smoothed = when.quad.unwrap();
+        assert!(smoothed.top_left.x > 100.0 && smoothed.top_left.x < 110.0);
+    }
+
+    #[test]
+    fn test_jump_restarts_track() {
+        let mut tracker = PlateTracker::default();
+        for _ in 0..20 {
+            tracker.track(Some(rect_quad(50.0, 50.0, 200.0, 300.0)), 640, 640);
+        }
+
+        let given = rect_quad(300.0, 200.0, 600.0, 600.0);
+        let when = tracker.track(Some(given), 640, 640);
+
+        assert_eq!(when.quad, Some(given));
+        assert!(when.stability < 0.5);
+    }
+
+    #[test]
+    fn test_missed_frames_drop_track() {
+        let options = TrackingOptions {
+            max_missed_frames: 2,
+            ..TrackingOptions::default()
+        };
+        let mut tracker = PlateTracker::new(options);
+        tracker.track(Some(rect_quad(100.0, 50.0, 400.0, 550.0)), 640, 640);
+
+        let missed = tracker.track(None, 640, 640);
+        assert!(missed.quad.is_some());
+        assert_eq!(missed.stability, 0.0);
+
+        tracker.track(None, 640, 640);
+        let when = tracker.track(None, 640, 640);
+        assert!(when.quad.is_none());
+    }
+
+    #[test]
+    fn test_plate_at_border_is_not_fully_visible() {
+        let mut tracker = PlateTracker::default();
+
+        let when = tracker.track(Some(rect_quad(0.0, 50.0, 400.0, 550.0)), 640, 640);
+
+        assert!(!when.fully_visible);
+        assert!(!when.is_steady(0.0));
+    }
+
+    #[test]
+    fn test_detects_synthetic_plate() {
+        let mut frame = GrayImage::from_pixel(640, 480, Luma([40u8]));
+        imageproc::drawing::draw_filled_rect_mut(
+            &mut frame,
+            Rect::at(160, 80).of_size(320, 320),
+            Luma([220u8]),
+        );
+        let frame = DynamicImage::ImageLuma8(frame);
+
+        let mut tracker = PlateTracker::default();
+        let mut when = tracker.update(&frame);
+        for _ in 0..10 {
+            when = tracker.update(&frame);
+        }
+
+        let quad = when.quad.expect("Plate should be detected");
+        let then = rect_quad(160.0, 80.0, 480.0, 400.0);
+        for (w, t) in [
+            (quad.top_left, then.top_left),
+            (quad.top_right, then.top_right),
+            (quad.bottom_right, then.bottom_right),
+            (quad.bottom_left, then.bottom_left),
+        ] {
+            assert!(na::distance(&w, &t) < 12.0, "{:?} vs {:?}", w, t);
+        }
+        assert!(when.is_steady(0.9));
+    }
+
+    #[test]
+    fn test_rejects_too_small_luma_plane() {
+        let mut tracker = PlateTracker::default();
+        assert!(tracker.update_luma(&[0u8; 100], 20, 20, 20).is_err());
+        assert!(tracker
+            .update_luma(&[0u8; 100], 20, 20, usize::MAX)
+            .is_err());
+    }
+}
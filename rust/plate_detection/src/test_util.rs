@@ -0,0 +1,43 @@
+use image::{GrayImage, Luma};
+use imageproc::point::Point;
+use na::Point2;
+use tlc_common::Quad;
+
+/// Corners of a `size` plate turned by `degrees` around `center`, clockwise from the top left
+pub(crate) fn rotated_plate(
+    center: (f32, f32),
+    size: (f32, f32),
+    degrees: f32,
+) -> Vec<Point2<f32>> {
+    let (sin, cos) = degrees.to_radians().sin_cos();
+    [(-1.0, -1.0), (1.0, -1.0), (1.0, 1.0), (-1.0, 1.0)]
+        .iter()
+        .map(|(sx, sy)| {
+            let (dx, dy) = (sx * size.0 / 2.0, sy * size.1 / 2.0);
+            Point2::new(
+                center.0 + dx * cos - dy * sin,
+                center.1 + dx * sin + dy * cos,
+            )
+        })
+        .collect()
+}
+
+/// A bright plate with `corners` on a dark background
+pub(crate) fn draw_plate(width: u32, height: u32, corners: &[Point2<f32>]) -> GrayImage {
+    let mut image = GrayImage::from_pixel(width, height, Luma([40u8]));
+    let polygon: Vec<Point<i32>> = corners
+        .iter()
+        .map(|p| Point::new(p.x.round() as i32, p.y.round() as i32))
+        .collect();
+    imageproc::drawing::draw_polygon_mut(&mut image, &polygon, Luma([220u8]));
+    image
+}
+
+pub(crate) fn rect_quad(left: f32, top: f32, right: f32, bottom: f32) -> Quad {
+    Quad {
+        top_left: Point2::new(left, top),
+        top_right: Point2::new(right, top),
+        bottom_right: Point2::new(right, bottom),
+        bottom_left: Point2::new(left, bottom),
+    }
+}
@@ -1,42 +1,167 @@
-use image::{DynamicImage, GenericImage};
-use imageproc;
+use image::DynamicImage;
 use log::debug;
-use tlc_common::Quad;
+use nalgebra::Point2;
+use tlc_common::{CameraModel, PlateScale, PlateSize, Quad};
+
+pub use plate_transform::PlateTransform;
+pub use resampling::Interpolation;
+
+mod plate_transform;
+mod resampling;
 
 type QuadCropArray = [(f32, f32); 4];
 
-pub fn unwarp_crop(
-    image: &DynamicImage,
-    quad: &Quad,
-    save_path: String,
-) -> Result<DynamicImage, String> {
-    let from: QuadCropArray = [
+/// How the plate is resampled from the photo
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct WarpOptions {
+    pub interpolation: Interpolation,
+    /// Lens model of the photo, its distortion is removed in the same resampling pass
+    pub camera: Option<CameraModel>,
+}
+
+fn quad_to_array(quad: &Quad) -> QuadCropArray {
+    [
         (quad.top_left.x, quad.top_left.y),
         (quad.top_right.x, quad.top_right.y),
         (quad.bottom_right.x, quad.bottom_right.y),
         (quad.bottom_left.x, quad.bottom_left.y),
-    ];
+    ]
+}
+
+/// The quad without lens distortion, the warp maps it to the crop with a homography
+fn ideal_quad(quad: &Quad, camera: Option<&CameraModel>) -> Quad {
+    match camera {
+        Some(camera) => Quad {
+            top_left: camera.undistort(&quad.top_left),
+            top_right: camera.undistort(&quad.top_right),
+            bottom_right: camera.undistort(&quad.bottom_right),
+            bottom_left: camera.undistort(&quad.bottom_left),
+        },
+        None => *quad,
+    }
+}
+
+/// Warps the plate inside `quad` to an upright crop. The transform maps between
+/// photo and crop coordinates, e.g. to draw spots on the photo.
+pub fn unwarp_crop(
+    image: &DynamicImage,
+    quad: &Quad,
+    options: &WarpOptions,
+    save_path: String,
+) -> Result<(DynamicImage, PlateTransform), String> {
+    let ideal = ideal_quad(quad, options.camera.as_ref());
+    let (to, max_width, max_height) = propose_destination(&ideal);
+
+    warp_crop(
+        image,
+        quad_to_array(&ideal),
+        to,
+        ((max_width - 1.0) as u32, (max_height - 1.0) as u32),
+        options,
+        save_path,
+    )
+}
+
+/// Warps the plate inside `quad` to a crop of the given size, e.g. to line up another photo
+/// of the same plate with an earlier crop
+pub fn unwarp_crop_sized(
+    image: &DynamicImage,
+    quad: &Quad,
+    (width, height): (u32, u32),
+    options: &WarpOptions,
+    save_path: String,
+) -> Result<(DynamicImage, PlateTransform), String> {
+    let (right, bottom) = (width as f32, height as f32);
+    let to: QuadCropArray = [(0.0, 0.0), (right, 0.0), (right, bottom), (0.0, bottom)];
+    let from = quad_to_array(&ideal_quad(quad, options.camera.as_ref()));
+    warp_crop(image, from, to, (width, height), options, save_path)
+}
 
-    let (to, max_width, max_height) = propose_destination(quad);
+/// Pixel wise mean of crops of the same plate, e.g. replicate photos warped with
+/// `unwarp_crop_sized`, which lowers the sensor noise
+pub fn average_crops(crops: &[DynamicImage]) -> Result<DynamicImage, String> {
+    let first = crops
+        .first()
+        .ok_or_else(|| "There are no crops to average".to_string())?;
+    let (width, height) = (first.width(), first.height());
+    if crops
+        .iter()
+        .any(|crop| (crop.width(), crop.height()) != (width, height))
+    {
+        return Err("The crops differ in size".to_string());
+    }
+
+    let mut sums = vec![0u32; (width * height * 3) as usize];
+    for crop in crops {
+        sums.iter_mut()
+            .zip(crop.to_rgb8().as_raw().iter())
+            .for_each(|(sum, value)| *sum += *value as u32);
+    }
+    let count = crops.len() as f32;
+    let mean: Vec<u8> = sums
+        .iter()
+        .map(|sum| (*sum as f32 / count).round() as u8)
+        .collect();
+    image::RgbImage::from_raw(width, height, mean)
+        .map(DynamicImage::ImageRgb8)
+        .ok_or_else(|| "Averaging the crops failed".to_string())
+}
+
+/// Warps the plate to its physical size at a fixed resolution, so crops of different
+/// photos share the same scale. The top edge of the quad becomes the plate width.
+pub fn unwarp_crop_metric(
+    image: &DynamicImage,
+    quad: &Quad,
+    size: &PlateSize,
+    pixels_per_mm: f32,
+    options: &WarpOptions,
+    save_path: String,
+) -> Result<(DynamicImage, PlateScale, PlateTransform), String> {
+    if size.width_mm <= 0.0 || size.height_mm <= 0.0 || pixels_per_mm <= 0.0 {
+        return Err(format!(
+            "Invalid plate size {:?} at {} px/mm",
+            size, pixels_per_mm
+        ));
+    }
+
+    let scale = PlateScale::new(pixels_per_mm);
+    let (width, height) = size.pixels(&scale);
+    // The plate outline lies on the outer pixel edges, half a pixel off the pixel centres
+    let (right, bottom) = (
+        scale.to_pixels(size.width_mm) - 0.5,
+        scale.to_pixels(size.height_mm) - 0.5,
+    );
+    let to: QuadCropArray = [(-0.5, -0.5), (right, -0.5), (right, bottom), (-0.5, bottom)];
+
+    let from = quad_to_array(&ideal_quad(quad, options.camera.as_ref()));
+    let (crop, transform) = warp_crop(image, from, to, (width, height), options, save_path)?;
+    Ok((crop, scale, transform))
+}
 
-    let maybe_projection =
-        imageproc::geometric_transformations::Projection::from_control_points(from, to);
+fn warp_crop(
+    image: &DynamicImage,
+    from: QuadCropArray,
+    to: QuadCropArray,
+    (width, height): (u32, u32),
+    options: &WarpOptions,
+    save_path: String,
+) -> Result<(DynamicImage, PlateTransform), String> {
+    let maybe_transform = PlateTransform::from_control_points(from, to, options.camera);
 
     debug!("FROM {:#?} TO {:#?}", from, to);
-    match maybe_projection {
-        Some(projection) => {
-            debug!("{:#?}", projection);
-            let mut warped = imageproc::geometric_transformations::warp(
-                &image.to_rgb8(),
-                &projection,
-                imageproc::geometric_transformations::Interpolation::Bilinear,
-                image::Rgb([0, 0, 0]),
-            );
-            let crop = warped
-                .sub_image(0, 0, (max_width - 1.0) as u32, (max_height - 1.0) as u32)
-                .to_image();
+    match maybe_transform {
+        Some(transform) => {
+            debug!("{:#?}", transform);
+            // Only the crop is warped, it may be larger than the input.
+            // Crop pixel -> ideal pixel -> distorted photo pixel, sampled only once
+            let mut crop = image::RgbImage::new(width, height);
+            let mapping = |x, y| {
+                let source = transform.to_photo(&Point2::new(x, y));
+                (source.x, source.y)
+            };
+            resampling::warp(&image.to_rgb8(), mapping, options.interpolation, &mut crop);
             match crop.save(save_path) {
-                Ok(_) => Ok(DynamicImage::ImageRgb8(crop)),
+                Ok(_) => Ok((DynamicImage::ImageRgb8(crop), transform)),
                 Err(_) => Err("Saving the warped image failed!".to_string()),
             }
         }
@@ -47,6 +172,7 @@ pub fn unwarp_crop(
     }
 }
 
+#[allow(clippy::needless_return)]
 fn propose_destination(quad: &Quad) -> (QuadCropArray, f32, f32) {
     // max height of the rect in x direction
     let width_a = ((quad.bottom_right.x - quad.bottom_left.x).powf(2.0)
@@ -80,10 +206,15 @@ fn propose_destination(quad: &Quad) -> (QuadCropArray, f32, f32) {
 
 #[cfg(test)]
 mod test {
-    use crate::{propose_destination, QuadCropArray};
+    use crate::{
+        average_crops, propose_destination, unwarp_crop, unwarp_crop_metric, unwarp_crop_sized,
+        Interpolation, PlateTransform, QuadCropArray, WarpOptions,
+    };
     use assert_approx_eq::assert_approx_eq;
-    use imageproc;
-    use tlc_common::Quad;
+    use image::DynamicImage;
+    use imageproc::point::Point;
+    use nalgebra::{distance, Point2};
+    use tlc_common::{CameraModel, Distortion, PlateScale, PlateSize, Quad};
 
     #[test]
     fn test_destination_proposal() {
@@ -119,19 +250,8 @@ mod test {
             .all(|(a, b)| a.0 == b.0 && a.1 == b.1));
     }
 
-    enum TransformationClass {
-        Translation,
-        Affine,
-        Projection,
-    }
-
-    pub struct TestProjection {
-        pub transform: [f32; 9],
-        inverse: [f32; 9],
-        class: TransformationClass,
-    }
-
     #[test]
+    #[allow(clippy::excessive_precision)]
     fn test_projection_matrix() {
         let quad = Quad::from_simple_vec(vec![1720, 3694, 155, 3694, 122, 811, 1720, 811]);
 
@@ -143,14 +263,9 @@ mod test {
             (quad.bottom_left.x, quad.bottom_left.y),
         ];
 
-        // Horrible hack to expose transform
-        let projection = imageproc::geometric_transformations::Projection::from_control_points(
-            given_from, given_to,
-        )
-        .unwrap();
-
-        let projection_exp: TestProjection = unsafe { std::mem::transmute(projection) };
-        let when = projection_exp.transform;
+        let transform = PlateTransform::from_control_points(given_from, given_to, None).unwrap();
+        // Row major like the imageproc projection
+        let when = transform.matrix.transpose();
 
         let then = [
             9.93602223e-01f32,
@@ -164,8 +279,251 @@ mod test {
             1.00000000e+00f32,
         ];
 
-        for i in 0..then.len() {
-            assert_approx_eq!(when[i], then[i], 1e-4);
+        for (a, b) in when.as_slice().iter().zip(then.iter()) {
+            assert_approx_eq!(a, b, 1e-4);
+        }
+    }
+
+    #[test]
+    fn test_metric_crop_has_physical_scale() {
+        // A 40 x 60 mm plate photographed at 4 px/mm with a perspective distortion
+        let quad = Quad::from_simple_vec(vec![30, 20, 180, 30, 190, 270, 20, 260]);
+        let mut given = image::RgbImage::from_pixel(220, 300, image::Rgb([20, 20, 20]));
+        let outline: Vec<Point<i32>> = quad
+            .to_tuple_vec()
+            .iter()
+            .map(|&(x, y)| Point::new(x as i32, y as i32))
+            .collect();
+        imageproc::drawing::draw_polygon_mut(&mut given, &outline, image::Rgb([230, 230, 230]));
+        let save_path = std::env::temp_dir().join("metric_crop.png");
+
+        let (when, scale, transform) = unwarp_crop_metric(
+            &DynamicImage::ImageRgb8(given),
+            &quad,
+            &PlateSize::new(40.0, 60.0),
+            5.0,
+            &WarpOptions::default(),
+            save_path.into_os_string().into_string().unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(scale, PlateScale::new(5.0));
+        let corner = transform.to_photo(&Point2::new(199.5, 299.5));
+        assert!(distance(&corner, &Point2::new(190.0, 270.0)) < 1e-2);
+        assert_eq!((when.width(), when.height()), (200, 300));
+        // The whole crop shows the plate, apart from interpolated borders
+        let crop = when.to_luma8();
+        let dark = crop
+            .enumerate_pixels()
+            .filter(|(x, y, p)| *x > 2 && *y > 2 && *x < 197 && *y < 297 && p[0] < 200)
+            .count();
+        assert_eq!(dark, 0);
+    }
+
+    #[test]
+    fn test_sized_crop_matches_crop_of_other_photo() {
+        let quad = Quad::from_simple_vec(vec![30, 20, 180, 30, 190, 270, 20, 260]);
+        let given = DynamicImage::ImageRgb8(image::RgbImage::from_fn(220, 300, |x, y| {
+            image::Rgb([(x % 256) as u8, (y % 256) as u8, 90])
+        }));
+        let save_path = std::env::temp_dir().join("sized_crop.png");
+        let save_path = save_path.into_os_string().into_string().unwrap();
+        let (crop, _) =
+            unwarp_crop(&given, &quad, &WarpOptions::default(), save_path.clone()).unwrap();
+
+        let (when, _) = unwarp_crop_sized(
+            &given,
+            &quad,
+            (crop.width(), crop.height()),
+            &WarpOptions::default(),
+            save_path,
+        )
+        .unwrap();
+
+        assert_eq!(when.to_rgb8(), crop.to_rgb8());
+    }
+
+    #[test]
+    fn test_average_of_replicates_reduces_noise() {
+        // Replicates of a gray plate with independent noise
+        let replicates: Vec<DynamicImage> = (0..8u32)
+            .map(|i| {
+                DynamicImage::ImageRgb8(image::RgbImage::from_fn(40, 30, |x, y| {
+                    let noise = ((x * 31 + y * 17 + i * 57) % 21) as u8;
+                    image::Rgb([90 + noise, 90 + noise, 90 + noise])
+                }))
+            })
+            .collect();
+        let spread = |image: &DynamicImage| {
+            let luma = image.to_luma8();
+            let (min, max) = luma
+                .pixels()
+                .fold((255, 0), |(lo, hi), p| (p[0].min(lo), p[0].max(hi)));
+            max - min
+        };
+
+        let when = average_crops(&replicates).unwrap();
+
+        assert!(spread(&when) * 2 < spread(&replicates[0]));
+        let mismatched = [replicates[0].clone(), replicates[0].crop_imm(0, 0, 10, 10)];
+        assert!(average_crops(&mismatched).is_err());
+    }
+
+    #[test]
+    fn test_metric_crop_rejects_invalid_size() {
+        let given = DynamicImage::ImageRgb8(image::RgbImage::new(10, 10));
+        let quad = Quad::from_simple_vec(vec![0, 0, 9, 0, 9, 9, 0, 9]);
+
+        let when = unwarp_crop_metric(
+            &given,
+            &quad,
+            &PlateSize::new(0.0, 10.0),
+            5.0,
+            &WarpOptions::default(),
+            "x.png".into(),
+        );
+
+        assert!(when.is_err());
+    }
+
+    /// Photo of a 100 x 60 mm plate with a dark spot at (10, 10) mm through a barrel distorting lens
+    fn distorted_photo(camera: &CameraModel) -> (DynamicImage, Quad) {
+        let ideal = [(60.0, 50.0), (340.0, 40.0), (350.0, 260.0), (50.0, 250.0)];
+        let plate = [(0.0, 0.0), (100.0, 0.0), (100.0, 60.0), (0.0, 60.0)];
+        let to_plate = PlateTransform::from_control_points(ideal, plate, None).unwrap();
+
+        let photo = image::RgbImage::from_fn(camera.width, camera.height, |x, y| {
+            let p = camera.undistort(&Point2::new(x as f32, y as f32));
+            let plate = to_plate.to_crop(&p);
+            let (u, v) = (plate.x, plate.y);
+            let value = if !(0.0..100.0).contains(&u) || !(0.0..60.0).contains(&v) {
+                20
+            } else if (u - 10.0).powi(2) + (v - 10.0).powi(2) < 9.0 {
+                40
+            } else {
+                220
+            };
+            image::Rgb([value, value, value])
+        });
+        let corners: Vec<Point2<f32>> = ideal
+            .iter()
+            .map(|&(x, y)| camera.distort(&Point2::new(x, y)))
+            .collect();
+        let quad = Quad {
+            top_left: corners[0],
+            top_right: corners[1],
+            bottom_right: corners[2],
+            bottom_left: corners[3],
+        };
+        (DynamicImage::ImageRgb8(photo), quad)
+    }
+
+    fn spot_center(crop: &DynamicImage) -> Point2<f32> {
+        let dark: Vec<(u32, u32)> = crop
+            .to_luma8()
+            .enumerate_pixels()
+            .filter(|(x, y, p)| *x > 3 && *y > 3 && *x < 60 && *y < 60 && p[0] < 130)
+            .map(|(x, y, _)| (x, y))
+            .collect();
+        let count = dark.len() as f32;
+        Point2::new(
+            dark.iter().map(|p| p.0 as f32).sum::<f32>() / count,
+            dark.iter().map(|p| p.1 as f32).sum::<f32>() / count,
+        )
+    }
+
+    #[test]
+    fn test_crop_removes_lens_distortion() {
+        let camera = CameraModel::new(
+            (400, 300),
+            (300.0, 300.0),
+            (199.5, 149.5),
+            Distortion::radial(-0.25, 0.05, 0.0),
+        );
+        let (given, quad) = distorted_photo(&camera);
+        let save_path = std::env::temp_dir().join("undistorted_crop.png");
+        let crop = |camera| {
+            unwarp_crop_metric(
+                &given,
+                &quad,
+                &PlateSize::new(100.0, 60.0),
+                2.0,
+                &WarpOptions {
+                    camera,
+                    ..Default::default()
+                },
+                save_path.clone().into_os_string().into_string().unwrap(),
+            )
+            .unwrap()
+            .0
+        };
+
+        let when = spot_center(&crop(Some(camera)));
+        let distorted = spot_center(&crop(None));
+
+        let then = Point2::new(19.5, 19.5);
+        assert!(distance(&when, &then) < 0.5, "{}", when);
+        assert!(distance(&distorted, &then) > 1.0, "{}", distorted);
+    }
+
+    /// Integral of a dark gaussian spot on a bright background after minifying it 8 times
+    fn minified_spot_integral(interpolation: Interpolation, (x, y): (f32, f32)) -> f32 {
+        let given = image::RgbImage::from_fn(800, 800, |px, py| {
+            let r2 = (px as f32 - x).powi(2) + (py as f32 - y).powi(2);
+            let value = 200.0 - 150.0 * (-r2 / (2.0 * 2.5 * 2.5)).exp();
+            image::Rgb([value.round() as u8; 3])
+        });
+        let quad = Quad {
+            top_left: Point2::new(-0.5, -0.5),
+            top_right: Point2::new(799.5, -0.5),
+            bottom_right: Point2::new(799.5, 799.5),
+            bottom_left: Point2::new(-0.5, 799.5),
+        };
+        let save_path = std::env::temp_dir().join(format!("spot_{:?}.png", interpolation));
+
+        let (crop, _, _) = unwarp_crop_metric(
+            &DynamicImage::ImageRgb8(given),
+            &quad,
+            &PlateSize::new(100.0, 100.0),
+            1.0,
+            &WarpOptions {
+                interpolation,
+                camera: None,
+            },
+            save_path.into_os_string().into_string().unwrap(),
+        )
+        .unwrap();
+        crop.to_luma8().pixels().map(|p| 200.0 - p[0] as f32).sum()
+    }
+
+    #[test]
+    fn test_spot_integrals_across_interpolations() {
+        // 150 * 2 pi sigma^2 in photo pixels, 64 photo pixels per crop pixel
+        let then = 150.0 * 2.0 * std::f32::consts::PI * 6.25 / 64.0;
+        let offsets = [
+            (400.0, 400.0),
+            (403.3, 401.7),
+            (405.5, 406.1),
+            (397.9, 404.4),
+        ];
+        let max_error = |interpolation| {
+            offsets
+                .iter()
+                .map(|&offset| (minified_spot_integral(interpolation, offset) - then).abs() / then)
+                .fold(0.0, f32::max)
+        };
+
+        let area = max_error(Interpolation::Area);
+
+        assert!(area < 0.02, "{}", area);
+        for interpolation in [
+            Interpolation::Nearest,
+            Interpolation::Bilinear,
+            Interpolation::Bicubic,
+            Interpolation::Lanczos3,
+        ] {
+            let error = max_error(interpolation);
+            assert!(error > 2.0 * area, "{:?} {}", interpolation, error);
         }
     }
 }
@@ -0,0 +1,189 @@
+use nalgebra::{Matrix3, Point2, SMatrix, SVector, Vector3};
+use tlc_common::{CameraModel, Circle};
+
+/// Step in pixels for the local scale of circles
+const SCALE_STEP: f32 = 0.5;
+
+/// Mapping between the photo and the warped plate crop. `matrix` maps undistorted photo
+/// positions to the crop and `inverse` back. With a `camera` model photo positions are
+/// undistorted before the homography and distorted after the inverse.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PlateTransform {
+    pub matrix: Matrix3<f32>,
+    pub inverse: Matrix3<f32>,
+    pub camera: Option<CameraModel>,
+}
+
+impl PlateTransform {
+    /// The homography mapping the four `from` points onto the four `to` points
+    pub fn from_control_points(
+        from: [(f32, f32); 4],
+        to: [(f32, f32); 4],
+        camera: Option<CameraModel>,
+    ) -> Option<Self> {
+        // Eight equations for the matrix entries with the last one fixed to 1
+        let mut a = SMatrix::<f64, 8, 8>::zeros();
+        let mut b = SVector::<f64, 8>::zeros();
+        for (i, (&(x, y), &(u, v))) in from.iter().zip(to.iter()).enumerate() {
+            let (x, y, u, v) = (x as f64, y as f64, u as f64, v as f64);
+            let rows = [
+                ([x, y, 1.0, 0.0, 0.0, 0.0, -u * x, -u * y], u),
+                ([0.0, 0.0, 0.0, x, y, 1.0, -v * x, -v * y], v),
+            ];
+            for (j, (row, value)) in rows.iter().enumerate() {
+                for (k, entry) in row.iter().enumerate() {
+                    a[(2 * i + j, k)] = *entry;
+                }
+                b[2 * i + j] = *value;
+            }
+        }
+
+        let h = a.lu().solve(&b)?;
+        let matrix = Matrix3::new(h[0], h[1], h[2], h[3], h[4], h[5], h[6], h[7], 1.0);
+        let inverse = matrix.try_inverse()?;
+        Some(PlateTransform {
+            matrix: matrix.cast::<f32>(),
+            inverse: (inverse / inverse[(2, 2)]).cast::<f32>(),
+            camera,
+        })
+    }
+
+    pub fn to_crop(&self, photo: &Point2<f32>) -> Point2<f32> {
+        let ideal = match &self.camera {
+            Some(camera) => camera.undistort(photo),
+            None => *photo,
+        };
+        project(&self.matrix, &ideal)
+    }
+
+    pub fn to_photo(&self, crop: &Point2<f32>) -> Point2<f32> {
+        let ideal = project(&self.inverse, crop);
+        match &self.camera {
+            Some(camera) => camera.distort(&ideal),
+            None => ideal,
+        }
+    }
+
+    /// The circle in the crop, its radius scaled by the local magnification
+    pub fn circle_to_crop(&self, circle: &Circle) -> Circle {
+        map_circle(circle, |p| self.to_crop(p))
+    }
+
+    pub fn circle_to_photo(&self, circle: &Circle) -> Circle {
+        map_circle(circle, |p| self.to_photo(p))
+    }
+
+    /// Moves a circle of this crop into the crop of `other`, e.g. after correcting the corners
+    pub fn circle_to(&self, circle: &Circle, other: &PlateTransform) -> Circle {
+        other.circle_to_crop(&self.circle_to_photo(circle))
+    }
+}
+
+fn project(matrix: &Matrix3<f32>, point: &Point2<f32>) -> Point2<f32> {
+    let mapped = matrix * Vector3::new(point.x, point.y, 1.0);
+    Point2::new(mapped.x / mapped.z, mapped.y / mapped.z)
+}
+
+fn map_circle<F>(circle: &Circle, mapping: F) -> Circle
+where
+    F: Fn(&Point2<f32>) -> Point2<f32>,
+{
+    let center = mapping(&circle.center);
+    let step = |dx: f32, dy: f32| {
+        let p = Point2::new(circle.center.x + dx, circle.center.y + dy);
+        (mapping(&p) - center) / SCALE_STEP
+    };
+    // Square root of the Jacobian determinant, i.e. the mean magnification
+    let (along_x, along_y) = (step(SCALE_STEP, 0.0), step(0.0, SCALE_STEP));
+    let scale = along_x.perp(&along_y).abs().sqrt();
+    Circle {
+        center,
+        radius: circle.radius * scale,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::PlateTransform;
+    use nalgebra::{distance, Point2};
+    use tlc_common::{CameraModel, Circle, Distortion};
+
+    fn perspective() -> PlateTransform {
+        PlateTransform::from_control_points(
+            [(30.0, 20.0), (180.0, 30.0), (190.0, 270.0), (20.0, 260.0)],
+            [(0.0, 0.0), (200.0, 0.0), (200.0, 300.0), (0.0, 300.0)],
+            None,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_maps_control_points() {
+        let given = perspective();
+
+        let when = given.to_crop(&Point2::new(190.0, 270.0));
+
+        assert!(distance(&when, &Point2::new(200.0, 300.0)) < 1e-3);
+        assert!(distance(&given.to_photo(&when), &Point2::new(190.0, 270.0)) < 1e-3);
+    }
+
+    #[test]
+    fn test_round_trip_with_lens_distortion() {
+        let camera = CameraModel::new(
+            (220, 300),
+            (250.0, 250.0),
+            (110.0, 150.0),
+            Distortion::radial(-0.2, 0.0, 0.0),
+        );
+        let given = PlateTransform {
+            camera: Some(camera),
+            ..perspective()
+        };
+        let point = Point2::new(40.0, 250.0);
+
+        let when = given.to_photo(&given.to_crop(&point));
+
+        assert!(distance(&when, &point) < 1e-2);
+    }
+
+    #[test]
+    fn test_circle_radius_follows_magnification() {
+        let given = PlateTransform::from_control_points(
+            [(0.0, 0.0), (100.0, 0.0), (100.0, 100.0), (0.0, 100.0)],
+            [(0.0, 0.0), (200.0, 0.0), (200.0, 200.0), (0.0, 200.0)],
+            None,
+        )
+        .unwrap();
+
+        let when = given.circle_to_crop(&Circle::new(10.0, 20.0, 3.0));
+
+        assert!(distance(&when.center, &Point2::new(20.0, 40.0)) < 1e-3);
+        assert!((when.radius - 6.0).abs() < 1e-3);
+        let back = given.circle_to_photo(&when);
+        assert!((back.radius - 3.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_remaps_circles_between_crops() {
+        let given = perspective();
+        let corrected = PlateTransform::from_control_points(
+            [(32.0, 21.0), (180.0, 30.0), (190.0, 270.0), (20.0, 262.0)],
+            [(0.0, 0.0), (200.0, 0.0), (200.0, 300.0), (0.0, 300.0)],
+            None,
+        )
+        .unwrap();
+        let spot = Circle::new(60.0, 120.0, 5.0);
+
+        let when = given.circle_to(&spot, &corrected);
+
+        let photo = given.to_photo(&spot.center);
+        assert!(distance(&corrected.to_photo(&when.center), &photo) < 1e-2);
+    }
+
+    #[test]
+    fn test_degenerate_points_fail() {
+        let given = [(0.0, 0.0), (1.0, 1.0), (2.0, 2.0), (3.0, 3.0)];
+
+        assert!(PlateTransform::from_control_points(given, given, None).is_none());
+    }
+}
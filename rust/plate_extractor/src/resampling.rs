@@ -0,0 +1,196 @@
+use image::{Rgb, RgbImage};
+use imageproc::geometric_transformations::warp_into_with;
+use std::str::FromStr;
+
+/// Upper bound of samples per axis for one output pixel in `Interpolation::Area`
+const MAX_AREA_SAMPLES: usize = 16;
+
+/// How the photo is sampled while warping the plate
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Interpolation {
+    Nearest,
+    #[default]
+    Bilinear,
+    Bicubic,
+    Lanczos3,
+    /// Averages all photo pixels covered by an output pixel, for strongly minified plates
+    Area,
+}
+
+impl FromStr for Interpolation {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.to_ascii_lowercase().as_str() {
+            "nearest" => Ok(Interpolation::Nearest),
+            "bilinear" => Ok(Interpolation::Bilinear),
+            "bicubic" => Ok(Interpolation::Bicubic),
+            "lanczos3" | "lanczos" => Ok(Interpolation::Lanczos3),
+            "area" => Ok(Interpolation::Area),
+            _ => Err(format!("Unknown interpolation {}", name)),
+        }
+    }
+}
+
+/// Fills `out` with `image` sampled at `mapping(x, y)` of each output pixel.
+/// Positions outside of the image become black.
+pub(crate) fn warp<F>(
+    image: &RgbImage,
+    mapping: F,
+    interpolation: Interpolation,
+    out: &mut RgbImage,
+) where
+    F: Fn(f32, f32) -> (f32, f32) + Send + Sync,
+{
+    use imageproc::geometric_transformations::Interpolation as Basic;
+    let black = Rgb([0, 0, 0]);
+    let basic = match interpolation {
+        Interpolation::Nearest => Basic::Nearest,
+        Interpolation::Bilinear => Basic::Bilinear,
+        Interpolation::Bicubic => Basic::Bicubic,
+        Interpolation::Lanczos3 => {
+            fill(out, |x, y| {
+                let (sx, sy) = mapping(x, y);
+                sample(image, sx, sy, 3.0, lanczos3)
+            });
+            return;
+        }
+        Interpolation::Area => {
+            fill(out, |x, y| area_average(image, &mapping, x, y));
+            return;
+        }
+    };
+    warp_into_with(image, mapping, basic, black, out);
+}
+
+fn fill<F>(out: &mut RgbImage, pixel: F)
+where
+    F: Fn(f32, f32) -> Option<[f32; 3]>,
+{
+    for (x, y, p) in out.enumerate_pixels_mut() {
+        let value = pixel(x as f32, y as f32).unwrap_or([0.0; 3]);
+        *p = Rgb([
+            value[0].round().clamp(0.0, 255.0) as u8,
+            value[1].round().clamp(0.0, 255.0) as u8,
+            value[2].round().clamp(0.0, 255.0) as u8,
+        ]);
+    }
+}
+
+/// Mean of bilinear samples spread over the parallelogram, which the output pixel covers in
+/// the photo. Magnified output pixels fall back to a single bilinear sample.
+fn area_average<F>(image: &RgbImage, mapping: &F, x: f32, y: f32) -> Option<[f32; 3]>
+where
+    F: Fn(f32, f32) -> (f32, f32),
+{
+    let center = mapping(x, y);
+    let span = |(ax, ay): (f32, f32), (bx, by): (f32, f32)| (bx - ax, by - ay);
+    let along_x = span(mapping(x - 0.5, y), mapping(x + 0.5, y));
+    let along_y = span(mapping(x, y - 0.5), mapping(x, y + 0.5));
+    let count = |(dx, dy): (f32, f32)| {
+        ((dx * dx + dy * dy).sqrt().ceil() as usize).clamp(1, MAX_AREA_SAMPLES)
+    };
+    let (nx, ny) = (count(along_x), count(along_y));
+
+    let mut sum = [0f32; 3];
+    let mut samples = 0;
+    for j in 0..ny {
+        let v = (j as f32 + 0.5) / ny as f32 - 0.5;
+        for i in 0..nx {
+            let u = (i as f32 + 0.5) / nx as f32 - 0.5;
+            let sx = center.0 + u * along_x.0 + v * along_y.0;
+            let sy = center.1 + u * along_x.1 + v * along_y.1;
+            if let Some(value) = sample(image, sx, sy, 1.0, triangle) {
+                (0..3).for_each(|c| sum[c] += value[c]);
+                samples += 1;
+            }
+        }
+    }
+    if samples == 0 {
+        return None;
+    }
+    Some(sum.map(|s| s / samples as f32))
+}
+
+fn triangle(t: f32) -> f32 {
+    (1.0 - t.abs()).max(0.0)
+}
+
+fn lanczos3(t: f32) -> f32 {
+    let sinc = |x: f32| {
+        if x.abs() < 1e-6 {
+            1.0
+        } else {
+            let px = std::f32::consts::PI * x;
+            px.sin() / px
+        }
+    };
+    if t.abs() < 3.0 {
+        sinc(t) * sinc(t / 3.0)
+    } else {
+        0.0
+    }
+}
+
+/// Separable kernel interpolation with pixel centres at integer positions.
+/// Taps beyond the border repeat the edge pixels.
+fn sample<K>(image: &RgbImage, x: f32, y: f32, radius: f32, kernel: K) -> Option<[f32; 3]>
+where
+    K: Fn(f32) -> f32,
+{
+    let (width, height) = image.dimensions();
+    if x < 0.0 || y < 0.0 || x > (width - 1) as f32 || y > (height - 1) as f32 {
+        return None;
+    }
+
+    let taps = |center: f32, size: u32| -> Vec<(u32, f32)> {
+        let first = (center - radius).floor() as i64 + 1;
+        let last = (center + radius).floor() as i64;
+        let weights: Vec<(u32, f32)> = (first..=last)
+            .map(|i| {
+                let index = i.clamp(0, size as i64 - 1) as u32;
+                (index, kernel(center - i as f32))
+            })
+            .collect();
+        let total: f32 = weights.iter().map(|(_, w)| w).sum();
+        weights.into_iter().map(|(i, w)| (i, w / total)).collect()
+    };
+
+    let (columns, rows) = (taps(x, width), taps(y, height));
+    let mut value = [0f32; 3];
+    for &(row, wy) in rows.iter() {
+        for &(column, wx) in columns.iter() {
+            let p = image.get_pixel(column, row);
+            (0..3).for_each(|c| value[c] += wx * wy * p[c] as f32);
+        }
+    }
+    Some(value)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::resampling::{lanczos3, sample, triangle};
+    use crate::Interpolation;
+    use image::{Rgb, RgbImage};
+
+    #[test]
+    fn test_kernels_interpolate_pixel_centres() {
+        let given = RgbImage::from_fn(8, 8, |x, y| Rgb([(x * 20) as u8, (y * 20) as u8, 7]));
+
+        for kernel in [triangle, lanczos3].iter() {
+            let when = sample(&given, 3.0, 5.0, 3.0, kernel).unwrap();
+
+            assert!((when[0] - 60.0).abs() < 1e-3);
+            assert!((when[1] - 100.0).abs() < 1e-3);
+            assert!((when[2] - 7.0).abs() < 1e-3);
+        }
+        assert_eq!(sample(&given, 7.5, 0.0, 1.0, triangle), None);
+    }
+
+    #[test]
+    fn test_parse_interpolation() {
+        assert_eq!("Lanczos3".parse(), Ok(Interpolation::Lanczos3));
+        assert_eq!("area".parse(), Ok(Interpolation::Area));
+        assert!("cubic spline".parse::<Interpolation>().is_err());
+    }
+}
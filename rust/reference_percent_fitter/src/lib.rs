@@ -55,6 +55,7 @@ impl ReferencePercentFitter {
         }
     }
 
+    #[allow(clippy::unnecessary_cast)]
     pub fn evaluate(&self, integrated_blobs: &HashMap<u32, u64>) -> HashMap<u32, f32> {
         integrated_blobs
             .iter()
@@ -74,6 +75,7 @@ mod test {
     use assert_approx_eq::assert_approx_eq;
     use std::collections::HashMap;
 
+    #[allow(clippy::type_complexity)]
     fn setup_example() -> (
         HashMap<u32, u64>,
         HashMap<u32, f32>,